@@ -6,7 +6,7 @@ use rand::{thread_rng, Rng};
 
 use criterion::BenchmarkId;
 use criterion::Criterion;
-use kvs::engine::{KvStore, KvsEngine, SledStore};
+use kvs::engine::{BTreeStore, KvStore, KvStoreOptions, KvsEngine, SledStore};
 use tempfile::TempDir;
 
 fn write_bench(c: &mut Criterion) {
@@ -28,10 +28,23 @@ fn write_bench(c: &mut Criterion) {
         let store = KvStore::open(temp_dir.path()).unwrap();
         let mut i = 0;
         b.iter(|| {
-            let kv = *&input.get(i).unwrap();
-            &store.set(kv.0.clone(), kv.1.clone());
+            let kv = &input[i];
+            let _ = store.set(kv.0.clone(), kv.1.clone());
             i += 1;
-            if i == *&input.len() {
+            if i == input.len() {
+                i = 0;
+            }
+        })
+    });
+    group.bench_function(BenchmarkId::new("btree_write", 1), |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = BTreeStore::open(temp_dir.path()).unwrap();
+        let mut i = 0;
+        b.iter(|| {
+            let kv = &input[i];
+            let _ = store.set(kv.0.clone(), kv.1.clone());
+            i += 1;
+            if i == input.len() {
                 i = 0;
             }
         })
@@ -41,10 +54,10 @@ fn write_bench(c: &mut Criterion) {
         let store = SledStore::open(temp_dir.path()).unwrap();
         let mut i = 0;
         b.iter(|| {
-            let kv = *&input.get(i).unwrap();
-            &store.set(kv.0.clone(), kv.1.clone());
+            let kv = &input[i];
+            let _ = store.set(kv.0.clone(), kv.1.clone());
             i += 1;
-            if i == *&input.len() {
+            if i == input.len() {
                 i = 0;
             }
         })
@@ -81,15 +94,60 @@ fn read_bench(c: &mut Criterion) {
         let mut i = 0;
 
         for (k, v) in &input {
-            &store.set(k.clone(), v.clone()).unwrap();
+            store.set(k.clone(), v.clone()).unwrap();
         }
 
         b.iter(|| {
             for _ in 0..100 {
-                let k = *&read_iter.get(i).unwrap();
-                &store.get(k.clone());
+                let k = &read_iter[i];
+                let _ = store.get(k.clone());
                 i += 1;
-                if i == *&read_iter.len() {
+                if i == read_iter.len() {
+                    i = 0;
+                }
+            }
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("kvs_read_mmap", 100), |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = KvStoreOptions::new()
+            .mmap(true)
+            .open(temp_dir.path())
+            .unwrap();
+        let mut i = 0;
+
+        for (k, v) in &input {
+            store.set(k.clone(), v.clone()).unwrap();
+        }
+
+        b.iter(|| {
+            for _ in 0..100 {
+                let k = &read_iter[i];
+                let _ = store.get(k.clone());
+                i += 1;
+                if i == read_iter.len() {
+                    i = 0;
+                }
+            }
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("btree_read", 100), |b| {
+        let temp_dir = TempDir::new().unwrap();
+        let store = BTreeStore::open(temp_dir.path()).unwrap();
+        let mut i = 0;
+
+        for (k, v) in &input {
+            store.set(k.clone(), v.clone()).unwrap();
+        }
+
+        b.iter(|| {
+            for _ in 0..100 {
+                let k = &read_iter[i];
+                let _ = store.get(k.clone());
+                i += 1;
+                if i == read_iter.len() {
                     i = 0;
                 }
             }
@@ -102,15 +160,15 @@ fn read_bench(c: &mut Criterion) {
         let mut i = 0;
 
         for (k, v) in &input {
-            &store.set(k.clone(), v.clone()).unwrap();
+            store.set(k.clone(), v.clone()).unwrap();
         }
 
         b.iter(|| {
             for _ in 0..100 {
-                let k = *&read_iter.get(i).unwrap();
-                &store.get(k.clone());
+                let k = &read_iter[i];
+                let _ = store.get(k.clone());
                 i += 1;
-                if i == *&read_iter.len() {
+                if i == read_iter.len() {
                     i = 0;
                 }
             }
@@ -119,5 +177,52 @@ fn read_bench(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, write_bench, read_bench);
+fn scan_bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan");
+
+    // 10000 keys with 100-byte values, written once per engine; each scan returns 100 of them
+    let size: usize = 10000;
+    let fill = |store: &dyn Fn(String, String)| {
+        for i in 0..size {
+            let value: String = thread_rng().sample_iter(&Alphanumeric).take(100).collect();
+            store(format!("key{:05}", i), value);
+        }
+    };
+    let prefixes: Vec<String> = (0..100).map(|i| format!("key{:03}", i)).collect();
+
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    fill(&|k, v| store.set(k, v).unwrap());
+    group.bench_function(BenchmarkId::new("kvs_scan", 100), |b| {
+        let mut i = 0;
+        b.iter(|| {
+            let _ = store.scan(prefixes[i].clone());
+            i = (i + 1) % prefixes.len();
+        })
+    });
+    let temp_dir = TempDir::new().unwrap();
+    let store = BTreeStore::open(temp_dir.path()).unwrap();
+    fill(&|k, v| store.set(k, v).unwrap());
+    group.bench_function(BenchmarkId::new("btree_scan", 100), |b| {
+        let mut i = 0;
+        b.iter(|| {
+            let _ = store.scan(prefixes[i].clone());
+            i = (i + 1) % prefixes.len();
+        })
+    });
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path()).unwrap();
+    fill(&|k, v| store.set(k, v).unwrap());
+    group.bench_function(BenchmarkId::new("sled_scan", 100), |b| {
+        let mut i = 0;
+        b.iter(|| {
+            let _ = store.scan(prefixes[i].clone());
+            i = (i + 1) % prefixes.len();
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, write_bench, read_bench, scan_bench);
 criterion_main!(benches);
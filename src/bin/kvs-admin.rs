@@ -0,0 +1,288 @@
+use byteorder::ReadBytesExt;
+use kvs::engine::{
+    EncryptionKey, KvStore, KvStoreOptions, KvsEngine, SegmentFormat, ShardedKvStore, SledStore,
+};
+use kvs::err::{KvsError, Result};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Engine {
+    Kvs,
+    Sled,
+}
+
+impl FromStr for Engine {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kvs" => Ok(Engine::Kvs),
+            "sled" => Ok(Engine::Sled),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+struct Target {
+    #[structopt(
+        name = "DIR",
+        long = "dir",
+        help = "the data directory",
+        default_value = ".",
+        parse(from_os_str)
+    )]
+    dir: PathBuf,
+
+    #[structopt(
+        name = "ENGINE-NAME",
+        long = "engine",
+        help = "the engine of the data: kvs or sled, detected if not given"
+    )]
+    engine: Option<Engine>,
+
+    #[structopt(
+        name = "KEY-FILE",
+        long = "encryption-key-file",
+        help = "file holding the hex key of encrypted records, read from KVS_ENCRYPTION_KEY if not given",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+
+    #[structopt(
+        name = "OLD-KEY-FILE",
+        long = "decryption-key-file",
+        help = "file holding an older key records may be encrypted with; compact re-encrypts them",
+        parse(from_os_str)
+    )]
+    old_key_files: Vec<PathBuf>,
+}
+
+impl Target {
+    fn kvs_options(&self) -> Result<KvStoreOptions> {
+        let key = match &self.key_file {
+            Some(path) => Some(EncryptionKey::from_file(path)?),
+            None => EncryptionKey::from_env(EncryptionKey::ENV)?,
+        };
+        let mut options = KvStoreOptions::new();
+        if let Some(key) = key {
+            options = options.encryption_key(key);
+        }
+        for path in &self.old_key_files {
+            options = options.decryption_key(EncryptionKey::from_file(path)?);
+        }
+        Ok(options)
+    }
+
+    /// The engine given, else the one recorded by `kvs-server`, else guessed from the files.
+    ///
+    /// Only kvs and sled data is handled: the directories of other engines and sharded stores
+    /// fail with `EngineMismatch` rather than being taken for kvs ones.
+    fn engine(&self) -> Result<Engine> {
+        if ShardedKvStore::shard_count(&self.dir)?.is_some() {
+            return Err(KvsError::EngineMismatch);
+        }
+        let tag = File::open(self.dir.join(".engine")).and_then(|mut f| f.read_u8());
+        let found = match tag {
+            Ok(1) => Some(Engine::Kvs),
+            Ok(2) => Some(Engine::Sled),
+            Ok(tag) if tag != 0 => return Err(KvsError::EngineMismatch),
+            // an lsm store opened without `kvs-server`
+            _ if self.holds(|name| name.ends_with(".wal") || name.ends_with(".sst")) => {
+                return Err(KvsError::EngineMismatch)
+            }
+            // a btree store opened without `kvs-server`
+            _ if self.holds(|name| name == "data.btree") => return Err(KvsError::EngineMismatch),
+            _ if self.dir.join("conf").exists() => Some(Engine::Sled),
+            _ => None,
+        };
+        match (self.engine, found) {
+            (Some(given), Some(found)) if given != found => Err(KvsError::EngineMismatch),
+            (given, found) => Ok(given.or(found).unwrap_or(Engine::Kvs)),
+        }
+    }
+
+    /// Whether the directory holds a file whose name matches.
+    fn holds(&self, matches: impl Fn(&str) -> bool) -> bool {
+        fs::read_dir(&self.dir).is_ok_and(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .any(|entry| entry.file_name().to_str().is_some_and(&matches))
+        })
+    }
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "kvs-admin", version = env!("CARGO_PKG_VERSION"), about = "Offline maintenance of kvs data directories", author = env!("CARGO_PKG_AUTHORS"))]
+enum Command {
+    #[structopt(about = "Check the checksums of all records and the consistency of the index.")]
+    Verify {
+        #[structopt(flatten)]
+        target: Target,
+    },
+
+    #[structopt(about = "Print every record of the log with its position.")]
+    Dump {
+        #[structopt(flatten)]
+        target: Target,
+    },
+
+    #[structopt(about = "Compact the log.")]
+    Compact {
+        #[structopt(flatten)]
+        target: Target,
+    },
+
+    #[structopt(about = "Show statistics of the data.")]
+    Stats {
+        #[structopt(flatten)]
+        target: Target,
+    },
+
+    #[structopt(about = "Truncate torn or corrupt records at the end of the log.")]
+    Repair {
+        #[structopt(flatten)]
+        target: Target,
+    },
+}
+
+fn main() -> Result<()> {
+    let ok = match Command::from_args() {
+        Command::Verify { target } => match target.engine()? {
+            Engine::Kvs => verify_kvs(&target.dir, &target.kvs_options()?)?,
+            Engine::Sled => verify_sled(&target.dir)?,
+        },
+        Command::Dump { target } => match target.engine()? {
+            Engine::Kvs => dump_kvs(&target.dir, &target.kvs_options()?)?,
+            Engine::Sled => dump_sled(&target.dir)?,
+        },
+        Command::Compact { target } => match target.engine()? {
+            Engine::Kvs => {
+                let store = target.kvs_options()?.open(&target.dir)?;
+                let before = store.stats()?.file_size;
+                store.compact()?;
+                println!("{} -> {} bytes", before, store.stats()?.file_size);
+                true
+            }
+            Engine::Sled => {
+                println!("sled compacts its files on its own");
+                true
+            }
+        },
+        Command::Stats { target } => {
+            let stats = match target.engine()? {
+                Engine::Kvs => target
+                    .kvs_options()?
+                    .read_only(true)
+                    .open(&target.dir)?
+                    .stats()?,
+                Engine::Sled => SledStore::open(&target.dir)?.stats()?,
+            };
+            print!("{}", stats);
+            true
+        }
+        Command::Repair { target } => match target.engine()? {
+            Engine::Kvs => repair_kvs(&target.dir, &target.kvs_options()?)?,
+            Engine::Sled => {
+                // sled drops torn writes when opening
+                let store = SledStore::open(&target.dir)?;
+                println!("{} keys", store.stats()?.live_keys);
+                true
+            }
+        },
+    };
+
+    if !ok {
+        exit(1);
+    }
+    Ok(())
+}
+
+fn verify_kvs(dir: &Path, options: &KvStoreOptions) -> Result<bool> {
+    let mut ok = true;
+    for report in KvStore::inspect_with(dir, options, |_| ())? {
+        let format = match report.format {
+            Some(SegmentFormat::Encrypted) => "checksummed, encrypted",
+            Some(SegmentFormat::Checksummed) => "checksummed",
+            Some(SegmentFormat::Legacy) => "no checksums",
+            None => "empty",
+        };
+        match &report.error {
+            None => println!(
+                "{}: {} records, {}",
+                report.path.display(),
+                report.records,
+                format
+            ),
+            Some(error) => {
+                ok = false;
+                println!("{}: {}", report.path.display(), error);
+            }
+        }
+    }
+
+    let store = options.clone().read_only(true).open(dir)?;
+    let problems = store.check_index()?;
+    for problem in &problems {
+        println!("index: {}", problem);
+    }
+    if problems.is_empty() {
+        println!("index: {} keys", store.stats()?.live_keys);
+    }
+    Ok(ok && problems.is_empty())
+}
+
+fn verify_sled(dir: &Path) -> Result<bool> {
+    let store = SledStore::open(dir)?;
+    // decodes every key and value
+    let pairs = store.scan(String::new())?;
+    println!("{} keys, checksum {:08x}", pairs.len(), store.checksum()?);
+    Ok(true)
+}
+
+fn dump_kvs(dir: &Path, options: &KvStoreOptions) -> Result<bool> {
+    let reports = KvStore::inspect_with(dir, options, |record| match record.value {
+        Some(value) => println!(
+            "{}:{} {} set {} {}",
+            record.gen, record.offset, record.len, record.key, value
+        ),
+        None => println!(
+            "{}:{} {} rm {}",
+            record.gen, record.offset, record.len, record.key
+        ),
+    })?;
+
+    let mut ok = true;
+    for report in reports {
+        if let Some(error) = report.error {
+            ok = false;
+            eprintln!("{}: {}", report.path.display(), error);
+        }
+    }
+    Ok(ok)
+}
+
+fn dump_sled(dir: &Path) -> Result<bool> {
+    for (key, value) in SledStore::open(dir)?.scan(String::new())? {
+        println!("set {} {}", key, value);
+    }
+    Ok(true)
+}
+
+fn repair_kvs(dir: &Path, options: &KvStoreOptions) -> Result<bool> {
+    for report in KvStore::repair_with(dir, options)? {
+        if let Some(error) = report.error {
+            println!(
+                "{}: {}, dropped {} bytes",
+                report.path.display(),
+                error,
+                report.file_len - report.valid_len
+            );
+        }
+    }
+    Ok(true)
+}
@@ -48,39 +48,155 @@ enum Command {
         )]
         addr: SocketAddr,
     },
+
+    #[structopt(about = "Get the string values of many string keys, one per line.")]
+    Mget {
+        #[structopt(name = "KEY", help = "the String keys", required = true)]
+        keys: Vec<String>,
+        #[structopt(
+            name = "IP-PORT",
+            long = "addr",
+            help = "server IP address",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(about = "Set the values of many string keys.")]
+    Mset {
+        #[structopt(
+            name = "KEY VALUE",
+            help = "the String keys to set, each followed by its value",
+            required = true
+        )]
+        pairs: Vec<String>,
+        #[structopt(
+            name = "IP-PORT",
+            long = "addr",
+            help = "server IP address",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(about = "Remove many string keys.")]
+    Mdel {
+        #[structopt(name = "KEY", help = "the String keys to remove", required = true)]
+        keys: Vec<String>,
+        #[structopt(
+            name = "IP-PORT",
+            long = "addr",
+            help = "server IP address",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: SocketAddr,
+    },
+
+    #[structopt(about = "Show statistics of the storage engine.")]
+    Stats {
+        #[structopt(
+            name = "IP-PORT",
+            long = "addr",
+            help = "server IP address",
+            default_value = "127.0.0.1:4000"
+        )]
+        addr: SocketAddr,
+    },
 }
 
 fn main() -> Result<()> {
     let opt: Command = Command::from_args();
-    let mut client;
-    let req;
     match opt {
-        Command::Get { key, addr } => {
-            client = KvsClient::connect(addr)?;
-            req = Request::Get { key };
-            match client.do_request(&req)? {
-                Response::NotFound => {
-                    println!("Key not found");
-                }
-                Response::Value(value) => {
-                    println!("{}", value);
-                }
-                _ => unreachable!(),
+        Command::Get { key, addr } => match request(addr, &Request::Get { key })? {
+            Response::NotFound => {
+                println!("Key not found");
             }
-        }
+            Response::Value(value) => {
+                println!("{}", value);
+            }
+            _ => unreachable!(),
+        },
         Command::Set { key, value, addr } => {
-            client = KvsClient::connect(addr)?;
-            req = Request::Set { key, value };
-            client.do_request(&req)?;
+            request(addr, &Request::Set { key, value })?;
         }
         Command::Rm { key, addr } => {
-            client = KvsClient::connect(addr)?;
-            req = Request::Remove { key };
-            if let Response::NotFound = client.do_request(&req)? {
+            if let Response::NotFound = request(addr, &Request::Remove { key })? {
                 eprintln!("Key not found");
                 exit(1);
             }
         }
+        Command::Mget { keys, addr } => {
+            let mut failed = false;
+            for resp in KvsClient::connect(addr)?.mget(keys)? {
+                match resp {
+                    Response::Value(value) => println!("{}", value),
+                    Response::NotFound => println!("Key not found"),
+                    resp => failed |= report(resp),
+                }
+            }
+            if failed {
+                exit(1);
+            }
+        }
+        Command::Mset { pairs, addr } => {
+            if pairs.len() % 2 != 0 {
+                eprintln!("Every key needs a value");
+                exit(1);
+            }
+            let pairs = pairs
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            let mut failed = false;
+            for resp in KvsClient::connect(addr)?.mset(pairs)? {
+                failed |= report(resp);
+            }
+            if failed {
+                exit(1);
+            }
+        }
+        Command::Mdel { keys, addr } => {
+            let mut failed = false;
+            let resps = KvsClient::connect(addr)?.mdel(keys.clone())?;
+            for (key, resp) in keys.iter().zip(resps) {
+                if let Response::NotFound = resp {
+                    eprintln!("Key not found: {}", key);
+                    failed = true;
+                } else {
+                    failed |= report(resp);
+                }
+            }
+            if failed {
+                exit(1);
+            }
+        }
+        Command::Stats { addr } => match request(addr, &Request::Stats)? {
+            Response::Stats(stats) => print!("{}", stats),
+            _ => unreachable!(),
+        },
     };
     Ok(())
 }
+
+/// Print the answer for one key of a request on many if it is an error. Return whether it is.
+fn report(resp: Response) -> bool {
+    match resp {
+        Response::Error { code, message } => {
+            eprintln!("Error ({}): {}", code, message);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Send a request to the server. If it fails, print the error and exit.
+fn request(addr: SocketAddr, req: &Request) -> Result<Response> {
+    let mut client = KvsClient::connect(addr)?;
+    match client.do_request(req)? {
+        Response::Error { code, message } => {
+            eprintln!("Error ({}): {}", code, message);
+            exit(1);
+        }
+        resp => Ok(resp),
+    }
+}
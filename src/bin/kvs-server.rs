@@ -1,33 +1,84 @@
 use byteorder::{ReadBytesExt, WriteBytesExt};
 use env_logger::{Builder, Target};
-use kvs::engine::{KvStore, SledStore};
-use kvs::err::{ParseError, Result, ServerNotMatch};
+use kvs::engine::{
+    BTreeStore, BTreeStoreOptions, EncryptionKey, FaultLayer, FaultRule, KvStore, KvStoreOptions,
+    KvsEngine, LimitsLayer, LsmStore, LsmStoreOptions, MemStore, MetricsLayer, ShardedKvStore,
+    SledStore, SlowLogLayer, SyncPolicy,
+};
+use kvs::err::{KvsError, Result};
+use kvs::network::http::HttpServer;
+use kvs::network::memcache::{ItemLayer, MemcacheServer};
+use kvs::network::reactor::ReactorServer;
+use kvs::network::resp::RespServer;
 use kvs::network::server::KvsServer;
-use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::thread_pool::{NaiveThreadPool, SharedQueueThreadPool, ThreadPool};
 use log::LevelFilter;
-use log::{error, info};
+use log::{error, info, warn};
 use std::env::current_dir;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom};
 use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
 use std::process::exit;
 use std::str::FromStr;
-use structopt::StructOpt;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use structopt::{clap, StructOpt};
 
-#[derive(Debug)]
+/// Seconds between writes of the snapshot of the memory engine when not given.
+const DEFAULT_SNAPSHOT_SECS: u64 = 10;
+
+#[derive(Debug, PartialEq, Eq)]
 enum Engine {
     Kvs,
+    Lsm,
+    BTree,
     Sled,
+    Memory,
+}
+
+impl Engine {
+    fn name(&self) -> &'static str {
+        match self {
+            Engine::Kvs => "kvs",
+            Engine::Lsm => "lsm",
+            Engine::BTree => "btree",
+            Engine::Sled => "sled",
+            Engine::Memory => "memory",
+        }
+    }
 }
 
 impl FromStr for Engine {
-    type Err = ParseError;
+    type Err = KvsError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             "kvs" => Ok(Engine::Kvs),
+            "lsm" => Ok(Engine::Lsm),
+            "btree" => Ok(Engine::BTree),
             "sled" => Ok(Engine::Sled),
-            _ => Err(ParseError {}),
+            "memory" => Ok(Engine::Memory),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Server {
+    Threads,
+    Reactor,
+}
+
+impl FromStr for Server {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "threads" => Ok(Server::Threads),
+            "reactor" => Ok(Server::Reactor),
+            _ => Err(KvsError::Parse),
         }
     }
 }
@@ -43,47 +94,367 @@ struct Command {
     )]
     addr: SocketAddr,
 
+    #[structopt(
+        name = "SERVER",
+        long = "server",
+        help = "how connections are served: threads, one thread blocking on each, or reactor, all multiplexed on one thread running the engine calls on a pool",
+        default_value = "threads"
+    )]
+    server: Server,
+
+    #[structopt(
+        name = "RESP-IP-PORT",
+        long = "resp-addr",
+        help = "also serve Redis clients, speaking RESP2, on this address"
+    )]
+    resp_addr: Option<SocketAddr>,
+
+    #[structopt(
+        name = "HTTP-IP-PORT",
+        long = "http-addr",
+        help = "also serve the HTTP/JSON gateway on this address"
+    )]
+    http_addr: Option<SocketAddr>,
+
+    #[structopt(
+        name = "MEMCACHE-IP-PORT",
+        long = "memcache-addr",
+        help = "also serve memcached clients, speaking its text protocol, on this address"
+    )]
+    memcache_addr: Option<SocketAddr>,
+
     #[structopt(
         name = "ENGINE-NAME",
         long = "engine",
-        help = "the key/value engine to use",
+        help = "the key/value engine to use: kvs, lsm, btree, sled or memory",
         default_value = "kvs"
     )]
     engine: Engine,
+
+    #[structopt(
+        name = "BYTES",
+        long = "compaction-threshold",
+        help = "stale bytes required before compaction (kvs engine only)"
+    )]
+    compaction_threshold: Option<u64>,
+
+    #[structopt(
+        name = "RATIO",
+        long = "compaction-ratio",
+        help = "fraction of the log that must be stale before compaction (kvs engine only)"
+    )]
+    compaction_ratio: Option<f64>,
+
+    #[structopt(
+        name = "SEGMENT-BYTES",
+        long = "segment-size",
+        help = "size at which a new log segment is started (kvs engine only)"
+    )]
+    segment_size: Option<u64>,
+
+    #[structopt(
+        name = "POLICY",
+        long = "sync",
+        help = "when to sync writes: never, always or an interval like 100ms (kvs, lsm and btree engines only)"
+    )]
+    sync: Option<SyncPolicy>,
+
+    #[structopt(
+        name = "CACHE-BYTES",
+        long = "cache-size",
+        help = "bytes of values, or pages for btree, kept in the read cache (kvs and btree engines only)"
+    )]
+    cache_size: Option<u64>,
+
+    #[structopt(
+        name = "SHARDS",
+        long = "shards",
+        help = "partition keys across this many logs written in parallel; fixed once the data is created (kvs engine only)"
+    )]
+    shards: Option<usize>,
+
+    #[structopt(
+        name = "KEY-FILE",
+        long = "encryption-key-file",
+        help = "file holding the hex key to encrypt records with, read from KVS_ENCRYPTION_KEY if not given (kvs engine only)",
+        parse(from_os_str)
+    )]
+    key_file: Option<PathBuf>,
+
+    #[structopt(
+        name = "OLD-KEY-FILE",
+        long = "decryption-key-file",
+        help = "file holding an older key records may be encrypted with, until compaction re-encrypts them (kvs engine only)",
+        parse(from_os_str)
+    )]
+    old_key_files: Vec<PathBuf>,
+
+    #[structopt(
+        name = "SNAPSHOT-FILE",
+        long = "snapshot",
+        help = "file the data is loaded from and periodically written back to (memory engine only)",
+        parse(from_os_str)
+    )]
+    snapshot: Option<PathBuf>,
+
+    #[structopt(
+        name = "SNAPSHOT-SECS",
+        long = "snapshot-interval",
+        help = "seconds between writes of the snapshot file, or never if 0; 10 if not given",
+        requires = "SNAPSHOT-FILE"
+    )]
+    snapshot_interval: Option<u64>,
+
+    #[structopt(
+        long = "read-only",
+        help = "reject writes and never modify the data files (kvs engine only)"
+    )]
+    read_only: bool,
+
+    #[structopt(
+        name = "KEY-BYTES",
+        long = "max-key-size",
+        help = "reject keys larger than this"
+    )]
+    max_key_size: Option<usize>,
+
+    #[structopt(
+        name = "VALUE-BYTES",
+        long = "max-value-size",
+        help = "reject values larger than this"
+    )]
+    max_value_size: Option<usize>,
+
+    #[structopt(
+        name = "MILLIS",
+        long = "slow-log-ms",
+        help = "log operations taking longer than this as warnings",
+        default_value = "100"
+    )]
+    slow_log_ms: u64,
+
+    #[structopt(
+        name = "SECS",
+        long = "idle-timeout",
+        help = "close connections idle for longer than this, or never if 0",
+        default_value = "60"
+    )]
+    idle_timeout: u64,
+
+    #[structopt(
+        name = "FAULT-RULE",
+        long = "inject-fault",
+        help = "debug: inject faults into the engine, e.g. ops=set|get,keys=user*,error=0.1,partial=0.05,latency=20ms"
+    )]
+    faults: Vec<FaultRule>,
+
+    #[structopt(
+        name = "SEED",
+        long = "fault-seed",
+        help = "debug: seed of the random generator drawing injected faults",
+        default_value = "0"
+    )]
+    fault_seed: u64,
+}
+
+impl Command {
+    /// Fail on an option the chosen engine does not support, rather than ignore it.
+    fn check_engine_options(&self) -> std::result::Result<(), String> {
+        let only = |given: bool, option: &str, engines: &[Engine]| {
+            if given && !engines.contains(&self.engine) {
+                Err(format!(
+                    "{} is not supported by the {} engine",
+                    option,
+                    self.engine.name()
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        only(self.read_only, "--read-only", &[Engine::Kvs])?;
+        let kvs = &[Engine::Kvs];
+        only(
+            self.compaction_threshold.is_some(),
+            "--compaction-threshold",
+            kvs,
+        )?;
+        only(self.compaction_ratio.is_some(), "--compaction-ratio", kvs)?;
+        only(self.segment_size.is_some(), "--segment-size", kvs)?;
+        only(self.shards.is_some(), "--shards", kvs)?;
+        only(self.key_file.is_some(), "--encryption-key-file", kvs)?;
+        only(!self.old_key_files.is_empty(), "--decryption-key-file", kvs)?;
+        // a key in the environment would otherwise leave the data unencrypted unnoticed
+        let key_in_env = std::env::var_os(EncryptionKey::ENV).is_some();
+        only(key_in_env, EncryptionKey::ENV, kvs)?;
+        let sync = &[Engine::Kvs, Engine::Lsm, Engine::BTree];
+        only(self.sync.is_some(), "--sync", sync)?;
+        only(
+            self.cache_size.is_some(),
+            "--cache-size",
+            &[Engine::Kvs, Engine::BTree],
+        )?;
+        only(self.snapshot.is_some(), "--snapshot", &[Engine::Memory])
+    }
+
+    fn kvs_options(&self) -> Result<KvStoreOptions> {
+        let mut options = KvStoreOptions::new().read_only(self.read_only);
+        if let Some(bytes) = self.compaction_threshold {
+            options = options.compaction_threshold(bytes);
+        }
+        if let Some(ratio) = self.compaction_ratio {
+            options = options.compaction_ratio(ratio);
+        }
+        if let Some(bytes) = self.segment_size {
+            options = options.segment_size(bytes);
+        }
+        if let Some(policy) = self.sync {
+            options = options.sync(policy);
+        }
+        if let Some(bytes) = self.cache_size {
+            options = options.cache_size(bytes);
+        }
+        let key = match &self.key_file {
+            Some(path) => Some(EncryptionKey::from_file(path)?),
+            None => EncryptionKey::from_env(EncryptionKey::ENV)?,
+        };
+        if let Some(key) = key {
+            options = options.encryption_key(key);
+        }
+        for path in &self.old_key_files {
+            options = options.decryption_key(EncryptionKey::from_file(path)?);
+        }
+        Ok(options)
+    }
+
+    fn lsm_options(&self) -> LsmStoreOptions {
+        let mut options = LsmStoreOptions::new();
+        if let Some(policy) = self.sync {
+            options = options.sync(policy);
+        }
+        options
+    }
+
+    fn btree_options(&self) -> BTreeStoreOptions {
+        let mut options = BTreeStoreOptions::new();
+        if let Some(policy) = self.sync {
+            options = options.sync(policy);
+        }
+        if let Some(bytes) = self.cache_size {
+            options = options.cache_size(bytes);
+        }
+        options
+    }
+
+    fn limits(&self) -> LimitsLayer {
+        let mut limits = LimitsLayer::new().read_only(self.read_only);
+        if let Some(bytes) = self.max_key_size {
+            limits = limits.max_key_size(bytes);
+        }
+        if let Some(bytes) = self.max_value_size {
+            limits = limits.max_value_size(bytes);
+        }
+        limits
+    }
 }
 
 enum EngineImpl {
     Kvs(KvStore),
+    Sharded(ShardedKvStore),
+    Lsm(LsmStore),
+    BTree(BTreeStore),
     Sled(SledStore),
+    Memory(MemStore),
 }
 
 fn get_engine(command: &Command) -> Result<EngineImpl> {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .read(true)
-        .write(true)
-        .open(".engine")?;
+    // nothing is stored on disk, so it can not clash with the data of another engine
+    if let Engine::Memory = command.engine {
+        let store = match &command.snapshot {
+            Some(path) => MemStore::with_snapshot(path)?,
+            None => return Ok(EngineImpl::Memory(MemStore::new())),
+        };
+        // the server only stops when killed, so the snapshot is never written on drop
+        let interval = command.snapshot_interval.unwrap_or(DEFAULT_SNAPSHOT_SECS);
+        if interval > 0 {
+            spawn_snapshots(store.clone(), Duration::from_secs(interval));
+        }
+        return Ok(EngineImpl::Memory(store));
+    }
 
     // engine tag:
-    //   0 for unknown, 1 for kvs, 2 for sled
-    let opt_tag = file.read_u8().unwrap_or(0u8);
-    file.seek(SeekFrom::Start(0u64))?;
+    //   0 for unknown, 1 for kvs, 2 for sled, 3 for lsm, 4 for btree
+    let tag = match command.engine {
+        Engine::Kvs => 1u8,
+        Engine::Sled => 2u8,
+        Engine::Lsm => 3u8,
+        Engine::BTree => 4u8,
+        Engine::Memory => unreachable!(),
+    };
+    if command.read_only {
+        // nothing is written, so the directory must already be tagged
+        let opt_tag = File::open(".engine")?.read_u8()?;
+        if opt_tag != tag {
+            return Err(KvsError::EngineMismatch);
+        }
+    } else {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(".engine")?;
+        let opt_tag = file.read_u8().unwrap_or(0u8);
+        if ![0u8, tag].contains(&opt_tag) {
+            return Err(KvsError::EngineMismatch);
+        }
+        file.seek(SeekFrom::Start(0u64))?;
+        file.write_u8(tag)?;
+    }
 
     match &command.engine {
-        Engine::Kvs if [0u8, 1u8].contains(&opt_tag) => {
-            file.write_u8(1u8)?;
-            Ok(EngineImpl::Kvs(KvStore::open(current_dir()?)?))
-        }
-        Engine::Sled if [0u8, 2u8].contains(&opt_tag) => {
-            file.write_u8(2u8)?;
-            Ok(EngineImpl::Sled(SledStore::open(current_dir()?)?))
+        Engine::Kvs => {
+            let dir = current_dir()?;
+            // a sharded store is opened as such even without `--shards`
+            match command.shards.or(ShardedKvStore::shard_count(&dir)?) {
+                Some(shards) => Ok(EngineImpl::Sharded(ShardedKvStore::open_with(
+                    dir,
+                    shards,
+                    command.kvs_options()?,
+                )?)),
+                None => Ok(EngineImpl::Kvs(KvStore::open_with(
+                    dir,
+                    command.kvs_options()?,
+                )?)),
+            }
         }
-        _ => Err(Box::new(ServerNotMatch)),
+        Engine::Sled => Ok(EngineImpl::Sled(SledStore::open(current_dir()?)?)),
+        Engine::Lsm => Ok(EngineImpl::Lsm(LsmStore::open_with(
+            current_dir()?,
+            command.lsm_options(),
+        )?)),
+        Engine::BTree => Ok(EngineImpl::BTree(BTreeStore::open_with(
+            current_dir()?,
+            command.btree_options(),
+        )?)),
+        Engine::Memory => unreachable!(),
     }
 }
 
+/// Write the snapshot file of the store every `interval`.
+fn spawn_snapshots(store: MemStore, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = store.flush() {
+            error!("failed to write snapshot: {}", e);
+        }
+    });
+}
+
 fn main() -> Result<()> {
     let opt: Command = Command::from_args();
+    if let Err(msg) = opt.check_engine_options() {
+        clap::Error::with_description(&msg, clap::ErrorKind::ArgumentConflict).exit();
+    }
 
     // init logger
     Builder::from_default_env()
@@ -94,21 +465,89 @@ fn main() -> Result<()> {
     let engine = match get_engine(&opt) {
         Ok(e) => e,
         Err(err) => {
-            error!("{}", err.to_string());
+            error!("{}", err);
             exit(1);
         }
     };
 
-    let listener = TcpListener::bind(&opt.addr)?;
+    let listener = TcpListener::bind(opt.addr)?;
     info!(
         "kvs-server init successfully, version: {}",
         env!("CARGO_PKG_VERSION")
     );
     info!("server is listening to {}", &opt.addr);
 
-    let thread_pool = NaiveThreadPool::new(0)?;
     match engine {
-        EngineImpl::Kvs(k) => KvsServer::new(k, listener, thread_pool).do_loop(),
-        EngineImpl::Sled(s) => KvsServer::new(s, listener, thread_pool).do_loop(),
+        EngineImpl::Kvs(k) => serve(k, listener, &opt),
+        EngineImpl::Sharded(k) => serve(k, listener, &opt),
+        EngineImpl::Lsm(l) => serve(l, listener, &opt),
+        EngineImpl::BTree(b) => serve(b, listener, &opt),
+        EngineImpl::Sled(s) => serve(s, listener, &opt),
+        EngineImpl::Memory(m) => serve(m, listener, &opt),
     }
 }
+
+fn serve<E: KvsEngine>(engine: E, listener: TcpListener, opt: &Command) -> Result<()> {
+    if opt.faults.is_empty() {
+        return serve_layered(engine, listener, opt);
+    }
+
+    warn!("injecting faults into the engine: {:?}", opt.faults);
+    let faults = FaultLayer::new(opt.faults.clone(), opt.fault_seed);
+    serve_layered(engine.layer(faults), listener, opt)
+}
+
+fn serve_layered<E: KvsEngine>(engine: E, listener: TcpListener, opt: &Command) -> Result<()> {
+    let engine = engine
+        .layer(opt.limits())
+        .layer(SlowLogLayer::new(Duration::from_millis(opt.slow_log_ms)))
+        .layer(MetricsLayer::new());
+    // the other protocols see the data of the items memcached clients store
+    let items = engine.clone().layer(ItemLayer::new());
+
+    let idle_timeout = Some(Duration::from_secs(opt.idle_timeout)).filter(|t| !t.is_zero());
+    // the listeners blocking on their connections share a pool of their own, so that idle
+    // connections never hold the threads the reactor runs engine calls on
+    let thread_pool = Arc::new(NaiveThreadPool::new(0)?);
+    if let Some(addr) = opt.resp_addr {
+        let mut server =
+            RespServer::new(items.clone(), TcpListener::bind(addr)?, thread_pool.clone())
+                .idle_timeout(idle_timeout);
+        info!("serving RESP on {}", addr);
+        spawn_listener("RESP", move || server.do_loop());
+    }
+    if let Some(addr) = opt.http_addr {
+        let mut server =
+            HttpServer::new(items.clone(), TcpListener::bind(addr)?, thread_pool.clone())
+                .idle_timeout(idle_timeout);
+        info!("serving HTTP on {}", addr);
+        spawn_listener("HTTP", move || server.do_loop());
+    }
+    if let Some(addr) = opt.memcache_addr {
+        let mut server = MemcacheServer::new(engine, TcpListener::bind(addr)?, thread_pool.clone())
+            .idle_timeout(idle_timeout);
+        info!("serving memcache on {}", addr);
+        spawn_listener("memcache", move || server.do_loop());
+    }
+
+    match opt.server {
+        Server::Threads => KvsServer::new(items, listener, thread_pool)
+            .idle_timeout(idle_timeout)
+            .do_loop(),
+        Server::Reactor => {
+            let engine_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+            ReactorServer::new(items, listener, engine_pool)
+                .idle_timeout(idle_timeout)
+                .do_loop()
+        }
+    }
+}
+
+/// Run a listener beside the native one, logging why it stopped.
+fn spawn_listener(name: &'static str, do_loop: impl FnOnce() -> Result<()> + Send + 'static) {
+    thread::spawn(move || {
+        if let Err(e) = do_loop() {
+            error!("{} listener failed: {}", name, e);
+        }
+    });
+}
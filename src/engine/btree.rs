@@ -0,0 +1,570 @@
+use super::offload::{io_pool, offload};
+use super::{AsyncKvsEngine, KvsEngine, KvsFuture, Stats, SyncPolicy};
+use crate::err::{KvsError, Result};
+use std::fs::{self, OpenOptions};
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use self::node::{Key, Node, Value, CAPACITY, INLINE_MAX, PAGE_SIZE};
+pub use self::options::BTreeStoreOptions;
+use self::pager::{Meta, Pager, META_PAGES};
+
+mod node;
+mod options;
+mod pager;
+
+/// Name of the file holding the pages.
+const DATA_FILE: &str = "data.btree";
+
+/// The new page of a subtree an entry was inserted into, and the lowest key and page of the
+/// sibling split off it if it outgrew a page.
+struct Inserted {
+    page: u64,
+    split: Option<(Key, u64)>,
+}
+
+/// The new page of a subtree an entry was removed from, or `None` if it is left empty, and the
+/// bytes of the key and value removed.
+struct Removed {
+    page: Option<u64>,
+    len: u64,
+}
+
+/// The index of the child of a branch that may hold a key.
+fn child_index(children: &[(Key, u64)], key: &str) -> usize {
+    children[1..].partition_point(|(low, _)| low.text.as_str() <= key)
+}
+
+struct RawBTreeStore {
+    options: BTreeStoreOptions,
+    pager: Pager,
+    meta: Meta,
+    live_keys: u64,
+    live_bytes: u64,
+    last_sync: Instant,
+}
+
+impl RawBTreeStore {
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let mut page = self.meta.root;
+        loop {
+            let node = self.pager.read_node(page)?;
+            match &*node {
+                Node::Branch(children) => page = children[child_index(children, key)].1,
+                Node::Leaf(entries) => {
+                    return match entries.binary_search_by(|(k, _)| k.text.as_str().cmp(key)) {
+                        Ok(i) => Ok(Some(self.pager.read_value(&entries[i].1)?)),
+                        Err(_) => Ok(None),
+                    };
+                }
+            }
+        }
+    }
+
+    fn scan(&mut self, page: u64, prefix: &str, out: &mut Vec<(String, String)>) -> Result<()> {
+        let node = self.pager.read_node(page)?;
+        match &*node {
+            Node::Leaf(entries) => {
+                let start = entries.partition_point(|(k, _)| k.text.as_str() < prefix);
+                for (key, value) in &entries[start..] {
+                    if !key.text.starts_with(prefix) {
+                        break;
+                    }
+                    out.push((key.text.clone(), self.pager.read_value(value)?));
+                }
+            }
+            Node::Branch(children) => {
+                let start = child_index(children, prefix);
+                for (i, (low, child)) in children.iter().enumerate().skip(start) {
+                    // past the first child, keys are above the prefix; once they no longer
+                    // start with it, they are above every key that does
+                    if i > start && !low.text.starts_with(prefix) {
+                        break;
+                    }
+                    self.scan(*child, prefix, out)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn new_key(&mut self, text: &str) -> Result<Key> {
+        let chain = if text.len() > INLINE_MAX {
+            Some(self.pager.write_chain(text)?)
+        } else {
+            None
+        };
+        Ok(Key {
+            text: text.to_owned(),
+            chain,
+        })
+    }
+
+    fn free_key(&mut self, key: &Key) {
+        if let Some(chain) = &key.chain {
+            self.pager.free(chain.page, chain.pages());
+        }
+    }
+
+    /// Insert an entry, copying the pages on the path to its leaf.
+    ///
+    /// Return the new root and the length of the value replaced, if any.
+    fn insert_root(&mut self, key: &str, value: String) -> Result<(u64, Option<u64>)> {
+        let value = if value.len() > INLINE_MAX {
+            Value::Chain(self.pager.write_chain(&value)?)
+        } else {
+            Value::Inline(value)
+        };
+        let mut replaced = None;
+        let inserted = self.insert(self.meta.root, key, value, &mut replaced)?;
+        let root = match inserted.split {
+            None => inserted.page,
+            Some(split) => self
+                .pager
+                .write_node(Node::Branch(vec![(Key::default(), inserted.page), split]))?,
+        };
+        Ok((root, replaced))
+    }
+
+    fn insert(
+        &mut self,
+        page: u64,
+        key: &str,
+        value: Value,
+        replaced: &mut Option<u64>,
+    ) -> Result<Inserted> {
+        let node = self.pager.read_node(page)?;
+        self.pager.free(page, 1);
+        let node = match &*node {
+            Node::Leaf(entries) => {
+                let mut entries = entries.clone();
+                match entries.binary_search_by(|(k, _)| k.text.as_str().cmp(key)) {
+                    Ok(i) => {
+                        let old = mem::replace(&mut entries[i].1, value);
+                        *replaced = Some(old.len());
+                        self.pager.free_value(&old);
+                    }
+                    Err(i) => {
+                        let key = self.new_key(key)?;
+                        entries.insert(i, (key, value));
+                    }
+                }
+                Node::Leaf(entries)
+            }
+            Node::Branch(children) => {
+                let mut children = children.clone();
+                let i = child_index(&children, key);
+                let inserted = self.insert(children[i].1, key, value, replaced)?;
+                children[i].1 = inserted.page;
+                if let Some(split) = inserted.split {
+                    children.insert(i + 1, split);
+                }
+                Node::Branch(children)
+            }
+        };
+        self.write_split(node)
+    }
+
+    /// Write a node, split in two if it outgrew a page.
+    fn write_split(&mut self, node: Node) -> Result<Inserted> {
+        if node.size() <= CAPACITY {
+            return Ok(Inserted {
+                page: self.pager.write_node(node)?,
+                split: None,
+            });
+        }
+        let at = node.split_point();
+        let (left, right, key) = match node {
+            Node::Leaf(mut entries) => {
+                let right = entries.split_off(at);
+                let key = self.new_key(&right[0].0.text)?;
+                (Node::Leaf(entries), Node::Leaf(right), key)
+            }
+            Node::Branch(mut children) => {
+                let mut right = children.split_off(at);
+                let key = mem::take(&mut right[0].0);
+                (Node::Branch(children), Node::Branch(right), key)
+            }
+        };
+        Ok(Inserted {
+            page: self.pager.write_node(left)?,
+            split: Some((key, self.pager.write_node(right)?)),
+        })
+    }
+
+    /// Remove an entry, copying the pages on the path to its leaf.
+    ///
+    /// Return the new root and the bytes removed, or `None` if the key does not exist.
+    fn remove_root(&mut self, key: &str) -> Result<Option<(u64, u64)>> {
+        let removed = match self.remove(self.meta.root, key)? {
+            Some(removed) => removed,
+            None => return Ok(None),
+        };
+        let mut root = match removed.page {
+            Some(page) => page,
+            None => self.pager.write_node(Node::Leaf(Vec::new()))?,
+        };
+        // a root left with a single child gives way to it
+        loop {
+            let node = self.pager.read_node(root)?;
+            match &*node {
+                Node::Branch(children) if children.len() == 1 => {
+                    self.pager.free(root, 1);
+                    root = children[0].1;
+                }
+                _ => break,
+            }
+        }
+        Ok(Some((root, removed.len)))
+    }
+
+    fn remove(&mut self, page: u64, key: &str) -> Result<Option<Removed>> {
+        let node = self.pager.read_node(page)?;
+        match &*node {
+            Node::Leaf(entries) => {
+                let i = match entries.binary_search_by(|(k, _)| k.text.as_str().cmp(key)) {
+                    Ok(i) => i,
+                    Err(_) => return Ok(None),
+                };
+                let mut entries = entries.clone();
+                let (key, value) = entries.remove(i);
+                self.free_key(&key);
+                self.pager.free_value(&value);
+                self.pager.free(page, 1);
+
+                let page = if entries.is_empty() {
+                    None
+                } else {
+                    Some(self.pager.write_node(Node::Leaf(entries))?)
+                };
+                Ok(Some(Removed {
+                    page,
+                    len: key.text.len() as u64 + value.len(),
+                }))
+            }
+            Node::Branch(children) => {
+                let mut children = children.clone();
+                let i = child_index(&children, key);
+                let removed = match self.remove(children[i].1, key)? {
+                    Some(removed) => removed,
+                    None => return Ok(None),
+                };
+                self.pager.free(page, 1);
+
+                match removed.page {
+                    Some(child) => {
+                        children[i].1 = child;
+                        self.merge(&mut children, i)?;
+                    }
+                    None => {
+                        let (low, _) = children.remove(i);
+                        self.free_key(&low);
+                        // the first child holds everything below the second, whatever its key
+                        if let Some(first) = children.first_mut().filter(|_| i == 0) {
+                            let low = mem::take(&mut first.0);
+                            self.free_key(&low);
+                        }
+                    }
+                }
+
+                let page = if children.is_empty() {
+                    None
+                } else {
+                    Some(self.pager.write_node(Node::Branch(children))?)
+                };
+                Ok(Some(Removed {
+                    page,
+                    len: removed.len,
+                }))
+            }
+        }
+    }
+
+    /// Merge the child of a branch with a sibling if it shrank below a quarter of a page and
+    /// both fit in one.
+    fn merge(&mut self, children: &mut Vec<(Key, u64)>, i: usize) -> Result<()> {
+        if children.len() < 2 || self.pager.read_node(children[i].1)?.size() >= CAPACITY / 4 {
+            return Ok(());
+        }
+        let l = if i + 1 < children.len() { i } else { i - 1 };
+        let left = self.pager.read_node(children[l].1)?;
+        let right = self.pager.read_node(children[l + 1].1)?;
+        let merged = match (&*left, &*right) {
+            (Node::Leaf(a), Node::Leaf(b)) => Node::Leaf(a.iter().chain(b).cloned().collect()),
+            (Node::Branch(a), Node::Branch(b)) => {
+                let mut merged = a.clone();
+                merged.extend(b.iter().cloned());
+                // the first child of the right branch takes the key the right branch had
+                merged[a.len()].0 = children[l + 1].0.clone();
+                Node::Branch(merged)
+            }
+            _ => {
+                return Err(KvsError::Corruption {
+                    offset: children[l + 1].1 * PAGE_SIZE as u64,
+                })
+            }
+        };
+        if merged.size() > CAPACITY {
+            return Ok(());
+        }
+
+        self.pager.free(children[l].1, 1);
+        self.pager.free(children[l + 1].1, 1);
+        let (low, _) = children.remove(l + 1);
+        if let Node::Leaf(_) = merged {
+            self.free_key(&low);
+        }
+        children[l].1 = self.pager.write_node(merged)?;
+        Ok(())
+    }
+
+    /// Make a transaction with a new root durable, or forget it if it failed.
+    fn commit<T>(&mut self, result: Result<(u64, T)>) -> Result<T> {
+        let result = result.and_then(|(root, out)| {
+            self.write_meta(root)?;
+            Ok(out)
+        });
+        if result.is_err() {
+            self.pager.rollback();
+        }
+        result
+    }
+
+    /// Write the meta of a new transaction. The pages it uses are synced before whatever the
+    /// policy, so that the meta never points to pages still in flight; this also makes the
+    /// meta before durable, and the pages released by its transaction free to reuse. The new
+    /// meta itself is synced if the policy requires it.
+    fn write_meta(&mut self, root: u64) -> Result<()> {
+        self.pager.file().sync_data()?;
+        self.pager.synced();
+        let meta = Meta {
+            txid: self.meta.txid + 1,
+            root,
+            page_count: self.pager.page_count,
+        };
+        self.pager.write_meta(&meta)?;
+        self.meta = meta;
+        let due = match self.options.sync {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.pager.file().sync_data()?;
+            self.pager.synced();
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Mark the pages of a subtree as used, counting its keys and bytes.
+    fn walk(&mut self, page: u64, used: &mut [bool]) -> Result<()> {
+        mark(used, page, 1)?;
+        let node = self.pager.read_node(page)?;
+        match &*node {
+            Node::Leaf(entries) => {
+                for (key, value) in entries {
+                    if let Some(chain) = &key.chain {
+                        mark(used, chain.page, chain.pages())?;
+                    }
+                    if let Value::Chain(chain) = value {
+                        mark(used, chain.page, chain.pages())?;
+                    }
+                    self.live_keys += 1;
+                    self.live_bytes += key.text.len() as u64 + value.len();
+                }
+            }
+            Node::Branch(children) => {
+                for (key, child) in children {
+                    if let Some(chain) = &key.chain {
+                        mark(used, chain.page, chain.pages())?;
+                    }
+                    self.walk(*child, used)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Mark a run of pages as used, failing with a `Corruption` if it is past the end of the file
+/// or already used.
+fn mark(used: &mut [bool], page: u64, pages: u64) -> Result<()> {
+    for page in page..page + pages {
+        match used.get_mut(page as usize) {
+            Some(used) if !*used => *used = true,
+            _ => {
+                return Err(KvsError::Corruption {
+                    offset: page * PAGE_SIZE as u64,
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A store keeping its keys in a B+tree of fixed-size pages in a single file.
+///
+/// Pages are copied on write: a write builds new pages up to a new root, then switches to it
+/// by writing a meta page, alternating between two so that a torn meta leaves the previous
+/// one. Nothing needs replaying on open, and reads and range scans visit only the pages on
+/// their path, kept decoded in a page cache.
+///
+/// Keys and values too long for a page go to runs of pages of their own.
+#[derive(Clone)]
+pub struct BTreeStore(Arc<Mutex<RawBTreeStore>>);
+
+impl KvsEngine for BTreeStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut guard = self.0.lock().unwrap();
+        let value_len = value.len() as u64;
+        let result = guard.insert_root(&key, value);
+        match guard.commit(result)? {
+            Some(old_len) => guard.live_bytes = guard.live_bytes - old_len + value_len,
+            None => {
+                guard.live_keys += 1;
+                guard.live_bytes += key.len() as u64 + value_len;
+            }
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.lock().unwrap().get(&key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut guard = self.0.lock().unwrap();
+        let result = match guard.remove_root(&key) {
+            Ok(Some((root, len))) => Ok((root, len)),
+            Ok(None) => Err(KvsError::KeyNotFound),
+            Err(e) => Err(e),
+        };
+        let len = guard.commit(result)?;
+        guard.live_keys -= 1;
+        guard.live_bytes -= len;
+        Ok(())
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut guard = self.0.lock().unwrap();
+        let mut result = Vec::new();
+        let root = guard.meta.root;
+        guard.scan(root, &prefix, &mut result)?;
+        Ok(result)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let guard = self.0.lock().unwrap();
+        Ok(Stats {
+            engine: "btree".to_owned(),
+            live_keys: guard.live_keys,
+            live_bytes: guard.live_bytes,
+            dead_bytes: guard.pager.free_pages() * PAGE_SIZE as u64,
+            file_size: guard.pager.file().metadata()?.len(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Disk I/O is run on a shared pool.
+impl AsyncKvsEngine for BTreeStore {
+    fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::set(&store, key, value))
+    }
+
+    fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::get(&store, key))
+    }
+
+    fn remove(&self, key: String) -> KvsFuture<()> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::remove(&store, key))
+    }
+
+    fn scan(&self, prefix: String) -> KvsFuture<Vec<(String, String)>> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::scan(&store, prefix))
+    }
+
+    fn stats(&self) -> KvsFuture<Stats> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::stats(&store))
+    }
+}
+
+impl BTreeStore {
+    /// Open the BTreeStore at a given path with the default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<BTreeStore> {
+        Self::open_with(path, BTreeStoreOptions::default())
+    }
+
+    /// Open the BTreeStore at a given path with the given options.
+    ///
+    /// The tree is walked to find the free pages. If the tree of the newest meta page is damaged,
+    /// the tree of the other one is used. Fail with a `Corruption` if neither meta page is valid
+    /// or points to an undamaged tree.
+    pub fn open_with(path: impl Into<PathBuf>, options: BTreeStoreOptions) -> Result<BTreeStore> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path.join(DATA_FILE))?;
+        let len = file.metadata()?.len();
+        let cache_pages = (options.cache_size / PAGE_SIZE as u64) as usize;
+        let mut pager = Pager::new(file, META_PAGES, cache_pages);
+
+        let mut metas = pager.read_metas()?;
+        // a new file, or one whose creation was interrupted before its first meta
+        if metas.is_empty() && len <= (META_PAGES + 1) * PAGE_SIZE as u64 {
+            let root = pager.write_node(Node::Leaf(Vec::new()))?;
+            let meta = Meta {
+                txid: 0,
+                root,
+                page_count: pager.page_count,
+            };
+            pager.write_meta(&meta)?;
+            pager.file().sync_all()?;
+            pager.synced();
+            metas.push(meta);
+        }
+        let newest = *metas.first().ok_or(KvsError::Corruption { offset: 0 })?;
+
+        let mut raw = RawBTreeStore {
+            options,
+            pager,
+            meta: newest,
+            live_keys: 0,
+            live_bytes: 0,
+            last_sync: Instant::now(),
+        };
+        // the newest tree may be damaged; the one before it is then used instead, and its meta
+        // page overwritten by the next write
+        let mut result = Ok(());
+        for meta in metas {
+            raw.meta = meta;
+            raw.pager.page_count = meta.page_count;
+            raw.live_keys = 0;
+            raw.live_bytes = 0;
+            let mut used = vec![false; meta.page_count as usize];
+            let walked = raw.walk(meta.root, &mut used);
+            if walked.is_ok() {
+                raw.pager.set_used(&used);
+                result = Ok(());
+                break;
+            }
+            if meta.txid == newest.txid {
+                result = walked;
+            }
+        }
+        result?;
+        Ok(BTreeStore(Arc::new(Mutex::new(raw))))
+    }
+}
@@ -0,0 +1,281 @@
+use crate::err::{KvsError, Result};
+use std::convert::TryInto;
+
+/// Size of every page of the file.
+pub(super) const PAGE_SIZE: usize = 4096;
+/// Bytes of a node page available to its entries; the page ends with a CRC32 of the rest.
+pub(super) const CAPACITY: usize = PAGE_SIZE - 4;
+/// Keys and values longer than this are written to pages of their own, so that every node
+/// fits at least a few entries.
+pub(super) const INLINE_MAX: usize = 1000;
+
+const LEAF: u8 = 1;
+const BRANCH: u8 = 2;
+/// The kind and entry count of a node page.
+const HEADER_LEN: usize = 3;
+const CHAIN_LEN: usize = 20;
+
+/// A run of consecutive pages holding a key or value too long for a node.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Chain {
+    pub(super) page: u64,
+    pub(super) len: u64,
+    pub(super) crc: u32,
+}
+
+impl Chain {
+    pub(super) fn pages(&self) -> u64 {
+        self.len.div_ceil(PAGE_SIZE as u64).max(1)
+    }
+}
+
+/// A key of a node. Long keys are loaded from their chain when the node is read, since
+/// searches compare them.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Key {
+    pub(super) text: String,
+    pub(super) chain: Option<Chain>,
+}
+
+/// A value of a leaf. Long values are only read from their chain when asked for.
+#[derive(Debug, Clone)]
+pub(super) enum Value {
+    Inline(String),
+    Chain(Chain),
+}
+
+impl Value {
+    pub(super) fn len(&self) -> u64 {
+        match self {
+            Value::Inline(s) => s.len() as u64,
+            Value::Chain(chain) => chain.len,
+        }
+    }
+}
+
+/// A page of the tree.
+///
+/// The entries of a branch are its children, each with the lowest key it may hold. The key
+/// of the first child is empty: it holds everything below the key of the second.
+#[derive(Debug, Clone)]
+pub(super) enum Node {
+    Leaf(Vec<(Key, Value)>),
+    Branch(Vec<(Key, u64)>),
+}
+
+impl Node {
+    /// Bytes of the encoded node, without the checksum.
+    pub(super) fn size(&self) -> usize {
+        HEADER_LEN
+            + match self {
+                Node::Leaf(entries) => entries
+                    .iter()
+                    .map(|(k, v)| key_size(k) + value_size(v))
+                    .sum::<usize>(),
+                Node::Branch(children) => children.iter().map(|(k, _)| key_size(k) + 8).sum(),
+            }
+    }
+
+    /// Where to split a node too large for a page, so that both halves fit in one.
+    pub(super) fn split_point(&self) -> usize {
+        let sizes: Vec<usize> = match self {
+            Node::Leaf(entries) => entries
+                .iter()
+                .map(|(k, v)| key_size(k) + value_size(v))
+                .collect(),
+            Node::Branch(children) => children.iter().map(|(k, _)| key_size(k) + 8).collect(),
+        };
+        let total: usize = sizes.iter().sum();
+        let mut size = 0;
+        for (i, entry) in sizes.iter().enumerate() {
+            // the entry straddling the middle goes to the half holding most of it
+            if size + entry / 2 > total / 2 {
+                return i.clamp(1, sizes.len() - 1);
+            }
+            size += entry;
+        }
+        sizes.len() - 1
+    }
+
+    /// Encode the node into a page.
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_SIZE);
+        match self {
+            Node::Leaf(entries) => {
+                buf.push(LEAF);
+                buf.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+                for (key, value) in entries {
+                    encode_key(&mut buf, key);
+                    match value {
+                        Value::Inline(s) => encode_inline(&mut buf, s),
+                        Value::Chain(chain) => encode_chain(&mut buf, chain),
+                    }
+                }
+            }
+            Node::Branch(children) => {
+                buf.push(BRANCH);
+                buf.extend_from_slice(&(children.len() as u16).to_be_bytes());
+                for (key, page) in children {
+                    encode_key(&mut buf, key);
+                    buf.extend_from_slice(&page.to_be_bytes());
+                }
+            }
+        }
+        buf.resize(CAPACITY, 0);
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_be_bytes());
+        buf
+    }
+
+    /// Decode the node page at a given offset, reading long keys with `load`.
+    ///
+    /// Fail with a `Corruption` if the page fails its checksum or is not a node.
+    pub(super) fn decode(
+        page: &[u8],
+        offset: u64,
+        load: &mut dyn FnMut(&Chain) -> Result<String>,
+    ) -> Result<Node> {
+        let mut node = Node::decode_body(page).map_err(|e| e.offset_by(offset))?;
+        let keys: Vec<&mut Key> = match &mut node {
+            Node::Leaf(entries) => entries.iter_mut().map(|(k, _)| k).collect(),
+            Node::Branch(children) => children.iter_mut().map(|(k, _)| k).collect(),
+        };
+        for key in keys {
+            if let Some(chain) = &key.chain {
+                key.text = load(chain)?;
+            }
+        }
+        Ok(node)
+    }
+
+    /// Decode a node page, leaving long keys empty. Offsets of a `Corruption` are within
+    /// the page.
+    fn decode_body(page: &[u8]) -> Result<Node> {
+        if page.len() != PAGE_SIZE {
+            return Err(KvsError::Corruption { offset: 0 });
+        }
+        let (body, crc) = page.split_at(CAPACITY);
+        if crc32fast::hash(body).to_be_bytes() != crc {
+            return Err(KvsError::Corruption {
+                offset: CAPACITY as u64,
+            });
+        }
+
+        let count = u16::from_be_bytes([body[1], body[2]]) as usize;
+        let mut pos = HEADER_LEN;
+        match body[0] {
+            LEAF => {
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = decode_key(body, &mut pos)?;
+                    let value = match decode_item(body, &mut pos)? {
+                        Item::Inline(s) => Value::Inline(s),
+                        Item::Chain(chain) => Value::Chain(chain),
+                    };
+                    entries.push((key, value));
+                }
+                Ok(Node::Leaf(entries))
+            }
+            BRANCH => {
+                let mut children = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = decode_key(body, &mut pos)?;
+                    children.push((key, read_u64(body, &mut pos)?));
+                }
+                Ok(Node::Branch(children))
+            }
+            _ => Err(KvsError::Corruption { offset: 0 }),
+        }
+    }
+}
+
+fn key_size(key: &Key) -> usize {
+    match key.chain {
+        Some(_) => 1 + CHAIN_LEN,
+        None => 5 + key.text.len(),
+    }
+}
+
+fn value_size(value: &Value) -> usize {
+    match value {
+        Value::Inline(s) => 5 + s.len(),
+        Value::Chain(_) => 1 + CHAIN_LEN,
+    }
+}
+
+/// Keys and values are a tag, then either the length as a `u32` and the bytes, or the
+/// length, first page and CRC32 of their chain.
+fn encode_inline(buf: &mut Vec<u8>, s: &str) {
+    buf.push(0);
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn encode_chain(buf: &mut Vec<u8>, chain: &Chain) {
+    buf.push(1);
+    buf.extend_from_slice(&chain.len.to_be_bytes());
+    buf.extend_from_slice(&chain.page.to_be_bytes());
+    buf.extend_from_slice(&chain.crc.to_be_bytes());
+}
+
+fn encode_key(buf: &mut Vec<u8>, key: &Key) {
+    match &key.chain {
+        Some(chain) => encode_chain(buf, chain),
+        None => encode_inline(buf, &key.text),
+    }
+}
+
+enum Item {
+    Inline(String),
+    Chain(Chain),
+}
+
+fn decode_item(buf: &[u8], pos: &mut usize) -> Result<Item> {
+    let start = *pos;
+    let tag = take(buf, pos, 1)?[0];
+    match tag {
+        0 => {
+            let len = u32::from_be_bytes(take(buf, pos, 4)?.try_into().unwrap()) as usize;
+            let text = String::from_utf8(take(buf, pos, len)?.to_vec());
+            Ok(Item::Inline(text.map_err(|_| KvsError::Corruption {
+                offset: start as u64,
+            })?))
+        }
+        1 => {
+            let len = read_u64(buf, pos)?;
+            let page = read_u64(buf, pos)?;
+            let crc = u32::from_be_bytes(take(buf, pos, 4)?.try_into().unwrap());
+            Ok(Item::Chain(Chain { page, len, crc }))
+        }
+        _ => Err(KvsError::Corruption {
+            offset: start as u64,
+        }),
+    }
+}
+
+/// Decode a key; the text of a long key is left for `Node::decode` to load.
+fn decode_key(buf: &[u8], pos: &mut usize) -> Result<Key> {
+    match decode_item(buf, pos)? {
+        Item::Inline(text) => Ok(Key { text, chain: None }),
+        Item::Chain(chain) => Ok(Key {
+            text: String::new(),
+            chain: Some(chain),
+        }),
+    }
+}
+
+fn read_u64(buf: &[u8], pos: &mut usize) -> Result<u64> {
+    Ok(u64::from_be_bytes(take(buf, pos, 8)?.try_into().unwrap()))
+}
+
+/// Bounds-checked slicing: damaged lengths are a `Corruption` rather than a panic.
+fn take<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let s = buf
+        .get(*pos..)
+        .and_then(|rest| rest.get(..len))
+        .ok_or(KvsError::Corruption {
+            offset: *pos as u64,
+        })?;
+    *pos += len;
+    Ok(s)
+}
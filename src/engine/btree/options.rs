@@ -0,0 +1,48 @@
+use super::BTreeStore;
+use crate::engine::SyncPolicy;
+use crate::err::Result;
+use std::path::PathBuf;
+
+/// Options for opening a `BTreeStore`.
+#[derive(Debug, Clone)]
+pub struct BTreeStoreOptions {
+    pub(super) cache_size: u64,
+    pub(super) sync: SyncPolicy,
+}
+
+impl Default for BTreeStoreOptions {
+    fn default() -> Self {
+        Self {
+            cache_size: 8 * 1024 * 1024,
+            sync: SyncPolicy::Never,
+        }
+    }
+}
+
+impl BTreeStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the bytes of pages kept decoded in the page cache. `0` disables the cache.
+    pub fn cache_size(mut self, bytes: u64) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// Set when writes are synced to disk. Syncing makes a write survive a crash of the
+    /// machine; a crash of the process alone never loses a completed write.
+    ///
+    /// The pages of a write are synced before its meta page whatever the policy, so a meta page
+    /// never reaches the disk before the tree it points to. The policy says when the meta page
+    /// itself is synced; until then, a crash of the machine may lose the write.
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// Open the BTreeStore at a given path with these options.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<BTreeStore> {
+        BTreeStore::open_with(path, self.clone())
+    }
+}
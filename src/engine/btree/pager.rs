@@ -0,0 +1,273 @@
+use super::node::{Chain, Node, Value, PAGE_SIZE};
+use crate::err::{KvsError, Result};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::sync::Arc;
+
+const MAGIC: &[u8; 8] = b"KVSBTREE";
+const VERSION: u32 = 1;
+/// Magic, version, page size, transaction id, root and page count, then a CRC32 of them.
+const META_LEN: usize = 40;
+/// Pages 0 and 1 hold the meta of every other transaction.
+pub(super) const META_PAGES: u64 = 2;
+
+/// The state of the tree as of a transaction.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Meta {
+    pub(super) txid: u64,
+    pub(super) root: u64,
+    pub(super) page_count: u64,
+}
+
+impl Meta {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(META_LEN + 4);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_be_bytes());
+        buf.extend_from_slice(&(PAGE_SIZE as u32).to_be_bytes());
+        buf.extend_from_slice(&self.txid.to_be_bytes());
+        buf.extend_from_slice(&self.root.to_be_bytes());
+        buf.extend_from_slice(&self.page_count.to_be_bytes());
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_be_bytes());
+        buf
+    }
+
+    /// Decode a meta page, or return `None` if it is torn or was never written.
+    fn decode(buf: &[u8]) -> Option<Meta> {
+        let (body, rest) = buf.split_at(META_LEN);
+        let u64_at = |pos: usize| u64::from_be_bytes(body[pos..pos + 8].try_into().unwrap());
+        let valid = crc32fast::hash(body).to_be_bytes() == rest[..4]
+            && body.starts_with(MAGIC)
+            && body[8..12] == VERSION.to_be_bytes()
+            && body[12..16] == (PAGE_SIZE as u32).to_be_bytes();
+        valid.then(|| Meta {
+            txid: u64_at(16),
+            root: u64_at(24),
+            page_count: u64_at(32),
+        })
+    }
+}
+
+/// A least-recently-used cache of decoded nodes, bounded by a number of pages.
+struct NodeCache {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<u64, (Arc<Node>, u64)>,
+    order: BTreeMap<u64, u64>,
+}
+
+impl NodeCache {
+    fn get(&mut self, page: u64) -> Option<Arc<Node>> {
+        self.tick += 1;
+        let (node, last_used) = self.entries.get_mut(&page)?;
+        self.order.remove(last_used);
+        self.order.insert(self.tick, page);
+        *last_used = self.tick;
+        Some(node.clone())
+    }
+
+    fn insert(&mut self, page: u64, node: Arc<Node>) {
+        if let Some((_, last_used)) = self.entries.remove(&page) {
+            self.order.remove(&last_used);
+        }
+        if self.capacity == 0 {
+            return;
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, page);
+        self.entries.insert(page, (node, self.tick));
+        while self.entries.len() > self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("cache order out of sync");
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// Reads and writes the pages of the file, and keeps track of the free ones.
+///
+/// Pages are never overwritten while the last transaction synced to disk uses them: pages
+/// freed by a transaction only become free once a meta written after it is synced.
+pub(super) struct Pager {
+    file: File,
+    pub(super) page_count: u64,
+    free: BTreeSet<u64>,
+    /// Pages freed by the current transaction.
+    pending: Vec<u64>,
+    /// Pages freed by transactions whose meta is written but not synced yet.
+    unsynced: Vec<u64>,
+    cache: NodeCache,
+}
+
+impl Pager {
+    pub(super) fn new(file: File, page_count: u64, cache_pages: usize) -> Pager {
+        Pager {
+            file,
+            page_count,
+            free: BTreeSet::new(),
+            pending: Vec::new(),
+            unsynced: Vec::new(),
+            cache: NodeCache {
+                capacity: cache_pages,
+                tick: 0,
+                entries: HashMap::new(),
+                order: BTreeMap::new(),
+            },
+        }
+    }
+
+    pub(super) fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Read the valid meta pages, newest first. A torn or never written one is skipped.
+    pub(super) fn read_metas(&mut self) -> Result<Vec<Meta>> {
+        let mut metas = Vec::new();
+        for page in 0..META_PAGES {
+            let mut buf = [0u8; META_LEN + 4];
+            self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+            if self.file.read_exact(&mut buf).is_err() {
+                continue;
+            }
+            metas.extend(Meta::decode(&buf));
+        }
+        metas.sort_by_key(|meta| std::cmp::Reverse(meta.txid));
+        Ok(metas)
+    }
+
+    /// Write the meta of a transaction over the one of the transaction before the last. The
+    /// pages the transaction released are freed by the next `synced`.
+    pub(super) fn write_meta(&mut self, meta: &Meta) -> Result<()> {
+        let mut buf = meta.encode();
+        buf.resize(PAGE_SIZE, 0);
+        self.write_at(meta.txid % META_PAGES, &buf)?;
+        self.unsynced.append(&mut self.pending);
+        Ok(())
+    }
+
+    /// Free the pages released by the transactions written so far, once the file is synced.
+    pub(super) fn synced(&mut self) {
+        self.free.extend(self.unsynced.drain(..));
+    }
+
+    /// Forget the pages freed by a transaction that failed, which the tree still uses.
+    pub(super) fn rollback(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Mark every page past the meta pages as free but the given ones.
+    pub(super) fn set_used(&mut self, used: &[bool]) {
+        self.free = (META_PAGES..self.page_count)
+            .filter(|&page| !used.get(page as usize).copied().unwrap_or(false))
+            .collect();
+    }
+
+    /// Number of pages free or released by transactions.
+    pub(super) fn free_pages(&self) -> u64 {
+        (self.free.len() + self.unsynced.len() + self.pending.len()) as u64
+    }
+
+    /// Allocate a run of consecutive pages, reusing free ones if possible.
+    fn alloc(&mut self, pages: u64) -> u64 {
+        if pages == 1 {
+            if let Some(page) = self.free.pop_first() {
+                return page;
+            }
+        } else {
+            let mut run = (0, 0);
+            for &page in &self.free {
+                run = if run.1 > 0 && run.0 + run.1 == page {
+                    (run.0, run.1 + 1)
+                } else {
+                    (page, 1)
+                };
+                if run.1 == pages {
+                    break;
+                }
+            }
+            if run.1 == pages {
+                for page in run.0..run.0 + pages {
+                    self.free.remove(&page);
+                }
+                return run.0;
+            }
+        }
+        self.page_count += pages;
+        self.page_count - pages
+    }
+
+    /// Release a run of pages once the current transaction is written.
+    pub(super) fn free(&mut self, page: u64, pages: u64) {
+        self.pending.extend(page..page + pages);
+    }
+
+    pub(super) fn free_value(&mut self, value: &Value) {
+        if let Value::Chain(chain) = value {
+            self.free(chain.page, chain.pages());
+        }
+    }
+
+    pub(super) fn read_node(&mut self, page: u64) -> Result<Arc<Node>> {
+        if let Some(node) = self.cache.get(page) {
+            return Ok(node);
+        }
+        let mut buf = vec![0u8; PAGE_SIZE];
+        self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+        self.file.read_exact(&mut buf)?;
+        let offset = page * PAGE_SIZE as u64;
+        let node = Arc::new(Node::decode(&buf, offset, &mut |chain| {
+            self.read_chain(chain)
+        })?);
+        self.cache.insert(page, node.clone());
+        Ok(node)
+    }
+
+    /// Write a node to a newly allocated page.
+    pub(super) fn write_node(&mut self, node: Node) -> Result<u64> {
+        let page = self.alloc(1);
+        self.write_at(page, &node.encode())?;
+        self.cache.insert(page, Arc::new(node));
+        Ok(page)
+    }
+
+    /// Write a long key or value to a newly allocated run of pages.
+    pub(super) fn write_chain(&mut self, text: &str) -> Result<Chain> {
+        let mut chain = Chain {
+            page: 0,
+            len: text.len() as u64,
+            crc: crc32fast::hash(text.as_bytes()),
+        };
+        chain.page = self.alloc(chain.pages());
+        self.write_at(chain.page, text.as_bytes())?;
+        Ok(chain)
+    }
+
+    /// Read a long key or value, failing with a `Corruption` if it fails its checksum.
+    pub(super) fn read_chain(&self, chain: &Chain) -> Result<String> {
+        let offset = chain.page * PAGE_SIZE as u64;
+        let corruption = || KvsError::Corruption { offset };
+        let mut buf = vec![0u8; chain.len.try_into().map_err(|_| corruption())?];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buf)?;
+        if crc32fast::hash(&buf) != chain.crc {
+            return Err(corruption());
+        }
+        String::from_utf8(buf).map_err(|_| corruption())
+    }
+
+    pub(super) fn read_value(&self, value: &Value) -> Result<String> {
+        match value {
+            Value::Inline(s) => Ok(s.clone()),
+            Value::Chain(chain) => self.read_chain(chain),
+        }
+    }
+
+    fn write_at(&mut self, page: u64, buf: &[u8]) -> Result<()> {
+        self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+        self.file.write_all(buf)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,272 @@
+use crate::engine::{KvsEngine, Layer, Operation, Stats};
+use crate::err::{KvsError, Result};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Faults injected into the operations a rule matches.
+///
+/// A rule matches every operation and key unless restricted with `ops` and `keys`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    ops: Vec<Operation>,
+    keys: Option<String>,
+    error_rate: f64,
+    partial_write_rate: f64,
+    latency: Duration,
+}
+
+impl Default for FaultRule {
+    fn default() -> Self {
+        FaultRule {
+            ops: Vec::new(),
+            keys: None,
+            error_rate: 0.0,
+            partial_write_rate: 0.0,
+            latency: Duration::default(),
+        }
+    }
+}
+
+impl FaultRule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restrict the rule to the given operations.
+    pub fn ops(mut self, ops: &[Operation]) -> Self {
+        self.ops = ops.to_vec();
+        self
+    }
+
+    /// Restrict the rule to keys matching a pattern, where `*` matches any run of
+    /// characters and `?` matches one character. `scan` and `stats` match on their prefix
+    /// and on the empty key respectively.
+    pub fn keys(mut self, pattern: impl Into<String>) -> Self {
+        self.keys = Some(pattern.into());
+        self
+    }
+
+    /// Fail the operation with `InjectedFault` with the given probability.
+    pub fn error_rate(mut self, rate: f64) -> Self {
+        self.error_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// With the given probability, store only the first half of the value of a `set`, then
+    /// fail it with `InjectedFault`.
+    pub fn partial_write_rate(mut self, rate: f64) -> Self {
+        self.partial_write_rate = rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Delay the operation by the given duration.
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    fn matches(&self, op: Operation, key: &str) -> bool {
+        (self.ops.is_empty() || self.ops.contains(&op))
+            && self.keys.as_ref().is_none_or(|p| glob_match(p, key))
+    }
+}
+
+impl FromStr for FaultRule {
+    type Err = KvsError;
+
+    /// Parse a comma separated list of settings, such as
+    /// `ops=set|remove,keys=user:*,error=0.1,partial=0.05,latency=20ms`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut rule = FaultRule::new();
+        for setting in s.split(',').filter(|s| !s.is_empty()) {
+            let mut kv = setting.splitn(2, '=');
+            let (name, value) = (
+                kv.next().ok_or(KvsError::Parse)?,
+                kv.next().ok_or(KvsError::Parse)?,
+            );
+            rule = match name {
+                "ops" => {
+                    let ops = value
+                        .split('|')
+                        .map(str::parse)
+                        .collect::<std::result::Result<Vec<Operation>, _>>()?;
+                    rule.ops(&ops)
+                }
+                "keys" => rule.keys(value),
+                "error" => rule.error_rate(value.parse().map_err(|_| KvsError::Parse)?),
+                "partial" => rule.partial_write_rate(value.parse().map_err(|_| KvsError::Parse)?),
+                "latency" => {
+                    let ms = value.strip_suffix("ms").ok_or(KvsError::Parse)?;
+                    rule.latency(Duration::from_millis(
+                        ms.parse().map_err(|_| KvsError::Parse)?,
+                    ))
+                }
+                _ => return Err(KvsError::Parse),
+            };
+        }
+        Ok(rule)
+    }
+}
+
+/// Match a key against a pattern of literal characters, `*` and `?`.
+fn glob_match(pattern: &str, key: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let key: Vec<char> = key.chars().collect();
+    let (mut p, mut k) = (0, 0);
+    // position of the last `*` and the key position it was tried at
+    let mut backtrack = None;
+    while k < key.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == key[k]) {
+            p += 1;
+            k += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, k));
+            p += 1;
+        } else if let Some((star, tried)) = backtrack {
+            backtrack = Some((star, tried + 1));
+            p = star + 1;
+            k = tried + 1;
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// An engine wrapper that injects errors, latency and partial writes into the operations of
+/// the inner engine, for testing how callers cope with failing storage.
+///
+/// Faults are drawn from a random generator seeded at creation, so the same sequence of
+/// operations sees the same faults.
+#[derive(Clone)]
+pub struct FaultyEngine<E: KvsEngine> {
+    inner: E,
+    rules: Arc<Vec<FaultRule>>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+/// A layer wrapping engines into `FaultyEngine`s with the given rules and seed.
+#[derive(Debug, Clone)]
+pub struct FaultLayer {
+    rules: Vec<FaultRule>,
+    seed: u64,
+}
+
+impl FaultLayer {
+    pub fn new(rules: Vec<FaultRule>, seed: u64) -> Self {
+        FaultLayer { rules, seed }
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for FaultLayer {
+    type Engine = FaultyEngine<E>;
+
+    fn layer(&self, inner: E) -> FaultyEngine<E> {
+        FaultyEngine {
+            inner,
+            rules: Arc::new(self.rules.clone()),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(self.seed))),
+        }
+    }
+}
+
+/// What happens to an operation.
+enum Fault {
+    None,
+    Error,
+    PartialWrite,
+}
+
+impl<E: KvsEngine> FaultyEngine<E> {
+    pub fn new(inner: E, seed: u64) -> Self {
+        FaultyEngine {
+            inner,
+            rules: Arc::new(Vec::new()),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+        }
+    }
+
+    /// Add a rule. Every matching rule applies its latency; the first one to draw a fault
+    /// decides the outcome.
+    pub fn with_rule(mut self, rule: FaultRule) -> Self {
+        Arc::make_mut(&mut self.rules).push(rule);
+        self
+    }
+
+    /// Get the wrapped engine.
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    fn inject(&self, op: Operation, key: &str) -> Fault {
+        let mut latency = Duration::default();
+        let mut fault = Fault::None;
+        {
+            let mut rng = self.rng.lock().unwrap();
+            for rule in self.rules.iter().filter(|r| r.matches(op, key)) {
+                latency += rule.latency;
+                if let Fault::None = fault {
+                    if rng.gen_bool(rule.error_rate) {
+                        fault = Fault::Error;
+                    } else if op == Operation::Set && rng.gen_bool(rule.partial_write_rate) {
+                        fault = Fault::PartialWrite;
+                    }
+                }
+            }
+        }
+
+        if latency > Duration::default() {
+            sleep(latency);
+        }
+        fault
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for FaultyEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        match self.inject(Operation::Set, &key) {
+            Fault::None => self.inner.set(key, value),
+            Fault::Error => Err(KvsError::InjectedFault),
+            Fault::PartialWrite => {
+                let mut end = value.len() / 2;
+                while !value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                self.inner.set(key, value[..end].to_owned())?;
+                Err(KvsError::InjectedFault)
+            }
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.inject(Operation::Get, &key) {
+            Fault::None => self.inner.get(key),
+            _ => Err(KvsError::InjectedFault),
+        }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.inject(Operation::Remove, &key) {
+            Fault::None => self.inner.remove(key),
+            _ => Err(KvsError::InjectedFault),
+        }
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        match self.inject(Operation::Scan, &prefix) {
+            Fault::None => self.inner.scan(prefix),
+            _ => Err(KvsError::InjectedFault),
+        }
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        match self.inject(Operation::Stats, "") {
+            Fault::None => self.inner.stats(),
+            _ => Err(KvsError::InjectedFault),
+        }
+    }
+}
@@ -1,11 +1,29 @@
-use super::KvsEngine;
-use crate::err::{KeyNonExist, Result};
+use super::offload::{io_pool, offload};
+use super::{AsyncKvsEngine, KvsEngine, KvsFuture, Stats};
+use crate::err::{KvsError, Result};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{remove_file, rename, File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, remove_file, rename, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+pub use self::admin::{LogRecord, SegmentReport};
+use self::cache::Cache;
+pub use self::crypto::EncryptionKey;
+pub use self::options::{KvStoreOptions, SyncPolicy};
+pub use self::segment::SegmentFormat;
+use self::segment::{encode, Segment};
+pub use self::sharded::ShardedKvStore;
+
+mod admin;
+mod cache;
+mod crypto;
+mod options;
+mod segment;
+mod sharded;
 
 #[derive(Serialize, Deserialize)]
 enum Command {
@@ -22,166 +40,619 @@ impl Command {
     }
 }
 
-struct RawKvStore {
-    path: PathBuf,
-    log_file: File,
-    current_cursor: u64,
-    mem_table: HashMap<String, u64>,
-    threshold: u64,
+/// Position of a command in the log.
+#[derive(Clone, Copy)]
+struct CommandPos {
+    gen: u64,
+    offset: u64,
+    len: u64,
 }
 
-#[derive(Clone)]
-pub struct KvStore(Arc<Mutex<RawKvStore>>);
+/// Path of the log segment of a given generation.
+///
+/// Generation 0 is the single `log` file written by earlier versions.
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    if gen == 0 {
+        dir.join("log")
+    } else {
+        dir.join(format!("{}.log", gen))
+    }
+}
 
-impl KvsEngine for KvStore {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.append_command(&Command::Set { key, value })
+/// Path a compacted segment is written to before it is renamed to its `log_path`.
+fn compaction_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.tmp", gen))
+}
+
+/// Make renames, creations and removals in a directory durable.
+pub(super) fn sync_dir(dir: &Path) -> Result<()> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
     }
+    Ok(())
+}
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let mut guard = self.0.lock().unwrap();
-        let raw = &mut *guard;
+/// Abort the process if the `KVS_CRASH_POINT` environment variable names this point.
+///
+/// Lets the crash tests kill a store between any two steps of a compaction. Only built with
+/// the `failpoints` feature.
+#[cfg(feature = "failpoints")]
+fn crash_point(name: &str) {
+    if std::env::var_os("KVS_CRASH_POINT").is_some_and(|p| p == name) {
+        std::process::abort();
+    }
+}
 
-        let off: u64;
-        match raw.mem_table.get(&key) {
-            None => return Ok(None),
-            Some(offset) => {
-                off = *offset;
+#[cfg(not(feature = "failpoints"))]
+fn crash_point(_name: &str) {}
+
+/// Bring a directory left by a crash back to a consistent state.
+///
+/// A compaction writes and syncs `<gen>.log.tmp`, renames it to `<gen>.log`, then removes
+/// the older segments from the oldest up. Segments are replayed in ascending order, so
+/// any prefix of that sequence replays to the same index and only the temporary file,
+/// which may be incomplete, has to go. Earlier versions compacted into `new_log` and
+/// renamed it over `log` after removing the latter; if `log` is gone, `new_log` holds
+/// the whole store.
+fn recover(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "tmp") {
+            remove_file(path)?;
+        }
+    }
+
+    let new_log = dir.join("new_log");
+    if new_log.exists() {
+        if dir.join("log").exists() {
+            remove_file(new_log)?;
+        } else {
+            rename(new_log, dir.join("log"))?;
+        }
+    }
+
+    sync_dir(dir)
+}
+
+/// Generations of the log segments in a directory, in ascending order.
+fn list_gens(dir: &Path) -> Result<Vec<u64>> {
+    let mut gens = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        let gen = match path.file_name().and_then(OsStr::to_str) {
+            Some("log") => Some(0),
+            Some(name) => name.strip_suffix(".log").and_then(|g| g.parse().ok()),
+            None => None,
+        };
+        gens.extend(gen);
+    }
+    gens.sort_unstable();
+    Ok(gens)
+}
+
+struct RawKvStore {
+    path: PathBuf,
+    options: KvStoreOptions,
+    /// One file per segment; the one of `active_gen` is also written to.
+    files: BTreeMap<u64, Segment>,
+    active_gen: u64,
+    current_cursor: u64,
+    /// The `Set` command holding the value of each live key.
+    mem_table: HashMap<String, CommandPos>,
+    /// The `Remove` command of each removed key still recorded in the log.
+    tombstones: HashMap<String, CommandPos>,
+    cache: Cache,
+    /// Bytes of the commands in `mem_table`.
+    live_bytes: u64,
+    /// Bytes of all other commands, which the next compaction drops.
+    stale_bytes: u64,
+    compactions: u64,
+    compaction_duration: Duration,
+    last_compaction: Option<SystemTime>,
+    last_sync: Instant,
+}
+
+impl RawKvStore {
+    /// Point the index at a command written at `pos`, and account for the bytes it makes stale.
+    fn index(&mut self, command: &Command, pos: CommandPos) {
+        let key = command.get_key();
+        self.cache.remove(&key);
+        let old = match command {
+            Command::Set { .. } => {
+                self.live_bytes += pos.len;
+                self.tombstones.remove(&key);
+                self.mem_table.insert(key, pos)
+            }
+            Command::Remove { .. } => {
+                // a tombstone is dropped by compaction along with the value it removes
+                self.stale_bytes += pos.len;
+                self.tombstones.insert(key.clone(), pos);
+                self.mem_table.remove(&key)
             }
         };
+        if let Some(old) = old {
+            self.live_bytes -= old.len;
+            self.stale_bytes += old.len;
+        }
+    }
 
-        match Self::read_command_from(&mut raw.log_file, Some(off))?.0 {
-            Command::Set { value, .. } => Ok(Some(value)),
-            Command::Remove { .. } => Ok(None),
+    /// Open the segment of a given generation and replay its commands into the index.
+    fn load(&mut self, gen: u64, writable: bool) -> Result<()> {
+        let segment = Segment::open(&log_path(&self.path, gen), gen, writable)?;
+        self.files.insert(gen, segment);
+        self.active_gen = gen;
+        self.current_cursor = 0;
+        self.tail()?;
+        if writable {
+            let segment = self.files.get_mut(&gen).expect("segment not opened");
+            if segment.format.is_none() {
+                let key = self.options.encryption_key.as_ref();
+                self.current_cursor = segment.write_header(key)?;
+            }
+            // drop a record torn by a crash, so new records are not appended after it
+            segment.file.set_len(self.current_cursor)?;
         }
+        Ok(())
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        let mut guard = self.0.lock().unwrap();
-        let raw = &mut *guard;
+    /// Replay the commands of the active segment past `current_cursor` into the index.
+    ///
+    /// Stops before an incomplete record, which may still be being written, or a last record
+    /// failing its checksum, which a crash tore. A record failing its checksum with more
+    /// records after it is a `Corruption`.
+    fn tail(&mut self) -> Result<()> {
+        let gen = self.active_gen;
+        let mut segment = match self.files.remove(&gen) {
+            Some(segment) => segment,
+            None => return Ok(()),
+        };
 
-        match raw.mem_table.get(&key) {
-            None => Err(Box::new(KeyNonExist)),
-            _ => {
-                drop(guard);
-                self.append_command(&Command::Remove { key })
+        // the header of a segment just created by a writer may not be written yet
+        let header = segment.read_header(&self.options.decryption_keys);
+        if let Ok(Some(start)) = header {
+            let mut cursor = self.current_cursor.max(start);
+            let mut offset = Some(cursor);
+            let replayed = loop {
+                match segment.read_record(offset.take()) {
+                    Ok((cmd, size)) => {
+                        let pos = CommandPos {
+                            gen,
+                            offset: cursor,
+                            len: size,
+                        };
+                        self.index(&cmd, pos);
+                        cursor += size;
+                    }
+                    Err(e) if e.is_io(io::ErrorKind::UnexpectedEof) => break Ok(()),
+                    Err(KvsError::Corruption { .. }) | Err(KvsError::Serialization(_))
+                        if segment.at_end()? =>
+                    {
+                        break Ok(())
+                    }
+                    Err(e) => break Err(e),
+                }
+            };
+            self.current_cursor = cursor;
+            if let Err(e) = replayed {
+                self.files.insert(gen, segment);
+                return Err(e);
             }
         }
+
+        self.files.insert(gen, segment);
+        header?;
+        Ok(())
     }
-}
 
-impl KvStore {
-    /// Open the KvStore at a given path.
+    /// Catch up with what a writer in another process did since the last call. Only used in
+    /// read-only mode.
     ///
-    /// Return the KvStore.
-    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
+    /// The writer only appends to its active segment and starts the next generation once done
+    /// with it, whether to rotate or to compact, so the index stays right by tailing the active
+    /// segment and then loading the newer ones. A compaction then removes the segments from
+    /// the oldest up; once the first one is gone the index is rebuilt, letting go of the files.
+    fn refresh(&mut self) -> Result<()> {
+        let first = self.files.keys().next().cloned();
+        if first.is_some_and(|gen| !log_path(&self.path, gen).exists()) {
+            return self.reload();
+        }
 
-        let log = path.join("log");
-        let mut log_file = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(log)?;
+        let moved_on = log_path(&self.path, self.active_gen + 1).exists();
+        self.tail()?;
+        if moved_on {
+            let active_gen = self.active_gen;
+            for gen in list_gens(&self.path)?
+                .into_iter()
+                .filter(|&g| g > active_gen)
+            {
+                if let Err(e) = self.load(gen, false) {
+                    return if e.is_io(io::ErrorKind::NotFound) {
+                        self.reload()
+                    } else {
+                        Err(e)
+                    };
+                }
+            }
+        }
+        Ok(())
+    }
 
-        let mut mem_table = HashMap::new();
-        let mut current_cursor = 0u64;
-        while let Ok((cmd, size)) = Self::read_command_from(&mut log_file, None) {
-            mem_table.insert(cmd.get_key(), current_cursor);
-            current_cursor += size;
+    /// Rebuild the index from the segments on disk, without modifying them.
+    ///
+    /// A segment removed by a concurrent compaction between listing and opening it is retried.
+    fn reload(&mut self) -> Result<()> {
+        let mut attempts = 0;
+        loop {
+            self.files.clear();
+            self.mem_table.clear();
+            self.tombstones.clear();
+            self.cache = Cache::new(self.options.cache_size);
+            self.live_bytes = 0;
+            self.stale_bytes = 0;
+            self.active_gen = 0;
+            self.current_cursor = 0;
+
+            let mut result = Ok(());
+            for gen in list_gens(&self.path)? {
+                result = self.load(gen, false);
+                if result.is_err() {
+                    break;
+                }
+            }
+            match result {
+                Err(e) if attempts < 3 && e.is_io(io::ErrorKind::NotFound) => attempts += 1,
+                result => return result,
+            }
         }
+    }
 
-        const DEFAULT_THRESHOLD: u64 = 128 * 1024;
-        let threshold = if current_cursor < DEFAULT_THRESHOLD {
-            DEFAULT_THRESHOLD
+    /// Amount of dead bytes at which compaction is started.
+    fn compaction_trigger(&self) -> u64 {
+        let ratio = self.options.compaction_ratio;
+        let by_ratio = if ratio >= 1.0 {
+            u64::MAX
         } else {
-            current_cursor * 2
+            (self.live_bytes as f64 * ratio / (1.0 - ratio)) as u64
         };
-
-        Ok(Self(Arc::new(Mutex::new(RawKvStore {
-            path,
-            log_file,
-            current_cursor,
-            mem_table,
-            threshold,
-        }))))
+        self.options.compaction_threshold.max(by_ratio)
     }
 
-    fn append_command(&self, command: &Command) -> Result<()> {
-        let mut guard = self.0.lock().unwrap();
-        let raw = &mut *guard;
+    fn read(&mut self, pos: CommandPos) -> Result<Command> {
+        let segment = self.files.get_mut(&pos.gen).expect("segment not opened");
+        if self.options.mmap && pos.gen != self.active_gen {
+            Ok(segment.read_mapped(pos.offset)?.0)
+        } else {
+            Ok(segment.read_record(Some(pos.offset))?.0)
+        }
+    }
 
-        let new_cursor = Self::append_command_to(&mut raw.log_file, command, raw.current_cursor)?;
+    fn append(&mut self, command: &Command) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
 
-        raw.mem_table.insert(command.get_key(), raw.current_cursor);
-        raw.current_cursor = new_cursor;
+        let segment = self
+            .files
+            .get_mut(&self.active_gen)
+            .expect("segment not opened");
+        let new_cursor = segment.append_record(command, self.current_cursor)?;
 
-        if raw.current_cursor >= raw.threshold {
-            drop(guard);
+        let pos = CommandPos {
+            gen: self.active_gen,
+            offset: self.current_cursor,
+            len: new_cursor - self.current_cursor,
+        };
+        self.index(command, pos);
+        self.current_cursor = new_cursor;
+        self.sync(false)?;
+
+        if self.current_cursor >= self.options.segment_size {
+            self.sync(true)?;
+            let gen = self.active_gen + 1;
+            self.load(gen, true)?;
+        }
+        if self.stale_bytes >= self.compaction_trigger() {
             self.compact()?;
         }
 
         Ok(())
     }
 
-    fn compact(&self) -> Result<()> {
+    /// Sync the active segment if the sync policy requires it. With `sealing` set, any policy
+    /// other than `Never` syncs.
+    fn sync(&mut self, sealing: bool) -> Result<()> {
+        let due = match self.options.sync {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => sealing || self.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.files[&self.active_gen].file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Copy the live records of all segments into a new segment, then remove the old ones.
+    ///
+    /// Every step leaves the directory in a state `recover` can deal with.
+    fn compact(&mut self) -> Result<()> {
+        let start = Instant::now();
+
+        let compaction_gen = self.active_gen + 1;
+        let mut compaction = Segment::open(
+            &compaction_path(&self.path, compaction_gen),
+            compaction_gen,
+            true,
+        )?;
+        let encryption_key = self.options.encryption_key.clone();
+        let mut new_cursor = compaction.write_header(encryption_key.as_ref())?;
+
+        // copy into a new index, so a failed compaction leaves the current one intact
+        let mut mem_table = HashMap::with_capacity(self.mem_table.len());
+        let mut live_bytes = 0u64;
+        for (key, pos) in &self.mem_table {
+            let segment = self.files.get_mut(&pos.gen).expect("segment not opened");
+            // encrypted records are bound to their position, and records written otherwise,
+            // in plain, with a rotated out key or without checksums, are encoded again
+            let record = if encryption_key.is_none() && segment.encoded_with(None) {
+                segment.read_raw(pos.offset, pos.len)?
+            } else {
+                encode(
+                    &segment.read_record(Some(pos.offset))?.0,
+                    encryption_key.as_ref(),
+                    compaction_gen,
+                    new_cursor,
+                )?
+            };
+
+            let new_pos = CommandPos {
+                gen: compaction_gen,
+                offset: new_cursor,
+                len: record.len() as u64,
+            };
+            new_cursor = compaction.write_raw(&record, new_cursor)?;
+            live_bytes += new_pos.len;
+            mem_table.insert(key.clone(), new_pos);
+            if mem_table.len() == self.mem_table.len() / 2 {
+                crash_point("compact-write");
+            }
+        }
+
+        crash_point("compact-sync");
+        compaction.file.sync_all()?;
+        crash_point("compact-rename");
+        rename(
+            compaction_path(&self.path, compaction_gen),
+            log_path(&self.path, compaction_gen),
+        )?;
+        crash_point("compact-sync-dir");
+        sync_dir(&self.path)?;
+
+        let stale_gens: Vec<u64> = self.files.keys().cloned().collect();
+        self.mem_table = mem_table;
+        self.tombstones.clear();
+        self.live_bytes = live_bytes;
+        self.stale_bytes = 0;
+        self.files.clear();
+        self.files.insert(compaction_gen, compaction);
+        self.load(compaction_gen + 1, true)?;
+
+        // oldest first, so a crash never leaves an older segment without the newer ones
+        for gen in stale_gens {
+            remove_file(log_path(&self.path, gen))?;
+            crash_point("compact-remove");
+        }
+        sync_dir(&self.path)?;
+        crash_point("compact-done");
+
+        self.compactions += 1;
+        self.compaction_duration += start.elapsed();
+        self.last_compaction = Some(SystemTime::now());
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+pub struct KvStore(Arc<Mutex<RawKvStore>>);
+
+impl KvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
         let mut guard = self.0.lock().unwrap();
+        guard.append(&Command::Set { key, value })
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let mut guard = self.lock()?;
         let raw = &mut *guard;
 
-        let new_log = raw.path.join("new_log");
-        let mut new_log = OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open(new_log)?;
-
-        let mut new_cursor = 0u64;
-        for (.., v) in std::mem::replace(&mut raw.mem_table, Default::default()) {
-            let c = &Self::read_command_from(&mut raw.log_file, Some(v))?.0;
-            if let Command::Set { key, .. } = c {
-                let n = Self::append_command_to(&mut new_log, c, new_cursor)?;
-                raw.mem_table.insert(key.clone(), new_cursor);
-                new_cursor = n;
+        let pos = match raw.mem_table.get(&key) {
+            None => return Ok(None),
+            Some(pos) => *pos,
+        };
+        if let Some(value) = raw.cache.get(&key) {
+            return Ok(Some(value));
+        }
+
+        match raw.read(pos)? {
+            Command::Set { value, .. } => {
+                raw.cache.insert(key, value.clone());
+                Ok(Some(value))
             }
+            Command::Remove { .. } => Ok(None),
         }
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut guard = self.0.lock().unwrap();
+        let raw = &mut *guard;
 
-        raw.log_file = new_log;
-        if new_cursor as f64 >= raw.threshold as f64 * 0.9 {
-            raw.threshold *= 2;
+        match raw.mem_table.get(&key) {
+            None => Err(KvsError::KeyNotFound),
+            _ => raw.append(&Command::Remove { key }),
         }
-        raw.current_cursor = new_cursor;
+    }
 
-        remove_file(raw.path.join("log"))?;
-        rename(raw.path.join("new_log"), raw.path.join("log"))?;
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut guard = self.lock()?;
+        let raw = &mut *guard;
 
-        Ok(())
+        let mut keys: Vec<String> = raw
+            .mem_table
+            .keys()
+            .filter(|k| k.starts_with(&prefix))
+            .cloned()
+            .collect();
+        keys.sort_unstable();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            let value = match raw.cache.get(&key) {
+                Some(value) => value,
+                None => match raw.read(raw.mem_table[&key])? {
+                    Command::Set { value, .. } => value,
+                    Command::Remove { .. } => continue,
+                },
+            };
+            pairs.push((key, value));
+        }
+        Ok(pairs)
     }
 
-    fn read_command_from(file: &mut File, offset: Option<u64>) -> Result<(Command, u64)> {
-        if let Some(off) = offset {
-            file.seek(SeekFrom::Start(off as u64))?;
+    fn stats(&self) -> Result<Stats> {
+        let guard = self.lock()?;
+        let mut file_size = 0;
+        for segment in guard.files.values() {
+            file_size += segment.file.metadata()?.len();
         }
 
-        let mut s = [0u8; 8];
-        file.read_exact(&mut s)?;
-        let vsize = usize::from_be_bytes(s);
+        Ok(Stats {
+            engine: "kvs".to_owned(),
+            live_keys: guard.mem_table.len() as u64,
+            tombstones: guard.tombstones.len() as u64,
+            live_bytes: guard.live_bytes,
+            dead_bytes: guard.stale_bytes,
+            compactions: guard.compactions,
+            compaction_duration: guard.compaction_duration,
+            last_compaction: guard.last_compaction,
+            threshold: guard.compaction_trigger(),
+            file_size,
+            ..Default::default()
+        })
+    }
+}
+
+/// Disk I/O is run on a shared pool; lookups answered from memory resolve immediately.
+impl AsyncKvsEngine for KvStore {
+    fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::set(&store, key, value))
+    }
 
-        let mut e = vec![0; vsize];
-        file.read_exact(&mut e)?;
-        let r: Command = serde_json::from_slice(&e)?;
+    fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        if let Some(value) = self.get_in_memory(&key) {
+            return Box::pin(async { Ok(value) });
+        }
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::get(&store, key))
+    }
 
-        Ok((r, vsize as u64 + 8))
+    fn remove(&self, key: String) -> KvsFuture<()> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::remove(&store, key))
     }
 
-    fn append_command_to(file: &mut File, command: &Command, offset: u64) -> Result<u64> {
-        file.seek(SeekFrom::Start(offset as u64))?;
+    fn scan(&self, prefix: String) -> KvsFuture<Vec<(String, String)>> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::scan(&store, prefix))
+    }
 
-        let s = serde_json::to_string(command)?;
-        let b = s.into_bytes();
-        let l = b.len().to_be_bytes();
-        file.write_all(&l)?;
-        file.write_all(&b)?;
+    fn stats(&self) -> KvsFuture<Stats> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::stats(&store))
+    }
+}
 
-        Ok(offset + 8 + b.len() as u64)
+impl KvStore {
+    /// Open the KvStore at a given path with the default options.
+    ///
+    /// Return the KvStore.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open the KvStore at a given path with the given options.
+    ///
+    /// Return the KvStore.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        let path = path.into();
+        let writable = !options.read_only;
+        if writable {
+            fs::create_dir_all(&path)?;
+            recover(&path)?;
+        }
+
+        let mut raw = RawKvStore {
+            path,
+            cache: Cache::new(options.cache_size),
+            options,
+            files: BTreeMap::new(),
+            active_gen: 0,
+            current_cursor: 0,
+            mem_table: HashMap::new(),
+            tombstones: HashMap::new(),
+            live_bytes: 0,
+            stale_bytes: 0,
+            compactions: 0,
+            compaction_duration: Duration::default(),
+            last_compaction: None,
+            last_sync: Instant::now(),
+        };
+        if writable {
+            let mut gens = list_gens(&raw.path)?;
+            if gens.is_empty() {
+                gens.push(1);
+            }
+            let last = gens.last().cloned();
+            for gen in gens {
+                raw.load(gen, Some(gen) == last)?;
+            }
+            // write in the current format and key from the start, in a segment of its own
+            let key = raw.options.encryption_key.as_ref();
+            if !raw.files[&raw.active_gen].encoded_with(key) {
+                raw.load(raw.active_gen + 1, true)?;
+            }
+        } else {
+            raw.reload()?;
+        }
+
+        Ok(Self(Arc::new(Mutex::new(raw))))
+    }
+
+    /// Lock the store for reading, first catching up with the writer in read-only mode.
+    fn lock(&self) -> Result<MutexGuard<'_, RawKvStore>> {
+        let mut guard = self.0.lock().unwrap();
+        if guard.options.read_only {
+            guard.refresh()?;
+        }
+        Ok(guard)
+    }
+
+    /// Get the value of a key if it is known without reading the log: the key is missing or
+    /// its value is cached. Read-only stores always go to the log to catch up with the writer,
+    /// and a store busy with a write or compaction is not waited for.
+    fn get_in_memory(&self, key: &str) -> Option<Option<String>> {
+        let mut guard = self.0.try_lock().ok()?;
+        if guard.options.read_only {
+            return None;
+        }
+        if !guard.mem_table.contains_key(key) {
+            return Some(None);
+        }
+        guard.cache.get(key).map(Some)
     }
 }
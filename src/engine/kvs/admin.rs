@@ -0,0 +1,194 @@
+use super::segment::{Segment, SegmentFormat};
+use super::KvStoreOptions;
+use super::{list_gens, log_path, recover, Command, KvStore};
+use crate::err::{KvsError, Result};
+use std::fs::OpenOptions;
+use std::io;
+use std::path::PathBuf;
+
+/// A record of a log segment, as found by `KvStore::inspect`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogRecord {
+    pub gen: u64,
+    pub offset: u64,
+    /// Length of the record, framing included.
+    pub len: u64,
+    pub key: String,
+    /// The value set, or `None` for a removal.
+    pub value: Option<String>,
+}
+
+/// What `KvStore::inspect` found reading a log segment.
+#[derive(Debug, Clone)]
+pub struct SegmentReport {
+    pub gen: u64,
+    pub path: PathBuf,
+    /// `None` if the segment is too short to tell.
+    pub format: Option<SegmentFormat>,
+    pub records: u64,
+    /// Length of the part of the file holding a header and whole, valid records.
+    pub valid_len: u64,
+    pub file_len: u64,
+    /// Why reading stopped before the end of the file, if it did.
+    pub error: Option<String>,
+}
+
+impl SegmentReport {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl KvStore {
+    /// Read every record of the log segments in a directory, without opening the store or
+    /// modifying any file, and report on each segment.
+    ///
+    /// Reading a segment stops at its first torn or corrupt record.
+    pub fn inspect(
+        path: impl Into<PathBuf>,
+        f: impl FnMut(LogRecord),
+    ) -> Result<Vec<SegmentReport>> {
+        Self::inspect_with(path, &KvStoreOptions::default(), f)
+    }
+
+    /// Like `inspect`, decrypting with the keys of the given options.
+    pub fn inspect_with(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+        mut f: impl FnMut(LogRecord),
+    ) -> Result<Vec<SegmentReport>> {
+        let path = path.into();
+        let mut reports = Vec::new();
+        for gen in list_gens(&path)? {
+            let mut segment = Segment::open(&log_path(&path, gen), gen, false)?;
+            let mut report = SegmentReport {
+                gen,
+                path: log_path(&path, gen),
+                format: None,
+                records: 0,
+                valid_len: 0,
+                file_len: segment.file.metadata()?.len(),
+                error: None,
+            };
+
+            match segment.read_header(&options.decryption_keys) {
+                Ok(Some(start)) => report.valid_len = start,
+                Ok(None) if report.file_len == 0 => {}
+                Ok(None) => report.error = Some("header cut short".to_owned()),
+                // not a corruption, and nothing else could be read
+                Err(e @ KvsError::WrongKey) => return Err(e),
+                Err(e) => report.error = Some(format!("bad header: {}", e)),
+            }
+            report.format = segment.format;
+
+            let mut offset = report.format.map(|_| report.valid_len);
+            while report.error.is_none() && report.valid_len < report.file_len {
+                match segment.read_record(offset.take()) {
+                    Ok((command, len)) => {
+                        let (key, value) = match command {
+                            Command::Set { key, value } => (key, Some(value)),
+                            Command::Remove { key } => (key, None),
+                        };
+                        f(LogRecord {
+                            gen,
+                            offset: report.valid_len,
+                            len,
+                            key,
+                            value,
+                        });
+                        report.records += 1;
+                        report.valid_len += len;
+                    }
+                    Err(e) => {
+                        report.error =
+                            Some(format!("{} at offset {}", describe(&e), report.valid_len))
+                    }
+                }
+            }
+            reports.push(report);
+        }
+        Ok(reports)
+    }
+
+    /// Truncate every log segment of a directory before its first torn or corrupt record,
+    /// and clean up after an interrupted compaction. The store must not be open.
+    ///
+    /// Return the reports of the segments as they were before the repair. Records past a
+    /// corruption in the middle of a segment are lost.
+    pub fn repair(path: impl Into<PathBuf>) -> Result<Vec<SegmentReport>> {
+        Self::repair_with(path, &KvStoreOptions::default())
+    }
+
+    /// Like `repair`, decrypting with the keys of the given options.
+    pub fn repair_with(
+        path: impl Into<PathBuf>,
+        options: &KvStoreOptions,
+    ) -> Result<Vec<SegmentReport>> {
+        let path = path.into();
+        recover(&path)?;
+        let reports = Self::inspect_with(&path, options, |_| ())?;
+        for report in reports.iter().filter(|r| r.valid_len < r.file_len) {
+            let file = OpenOptions::new().write(true).open(&report.path)?;
+            file.set_len(report.valid_len)?;
+            file.sync_all()?;
+        }
+        Ok(reports)
+    }
+
+    /// Compact the log now, whatever the amount of stale data.
+    pub fn compact(&self) -> Result<()> {
+        let mut guard = self.0.lock().unwrap();
+        if guard.options.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        guard.compact()
+    }
+
+    /// Check that every entry of the index points at a readable record of its key, and that
+    /// the live bytes add up.
+    ///
+    /// Return a description of each inconsistency found.
+    pub fn check_index(&self) -> Result<Vec<String>> {
+        let mut guard = self.lock()?;
+        let raw = &mut *guard;
+
+        let entries: Vec<_> = raw
+            .mem_table
+            .iter()
+            .map(|(key, pos)| (key.clone(), *pos, true))
+            .chain(
+                raw.tombstones
+                    .iter()
+                    .map(|(key, pos)| (key.clone(), *pos, false)),
+            )
+            .collect();
+        let mut problems = Vec::new();
+        let mut live_bytes = 0;
+        for (key, pos, live) in entries {
+            let at = format!("segment {} offset {}", pos.gen, pos.offset);
+            match raw.read(pos) {
+                Ok(Command::Set { key: ref k, .. }) if live && *k == key => live_bytes += pos.len,
+                Ok(Command::Remove { key: ref k }) if !live && *k == key => {}
+                Ok(_) => {
+                    problems.push(format!("{}: index points at another record at {}", key, at))
+                }
+                Err(e) => problems.push(format!("{}: {} at {}", key, describe(&e), at)),
+            }
+        }
+        if live_bytes != raw.live_bytes {
+            problems.push(format!(
+                "live records hold {} bytes, accounted as {}",
+                live_bytes, raw.live_bytes
+            ));
+        }
+        Ok(problems)
+    }
+}
+
+fn describe(e: &KvsError) -> String {
+    match e {
+        KvsError::Corruption { .. } => "checksum mismatch".to_owned(),
+        e if e.is_io(io::ErrorKind::UnexpectedEof) => "record cut short".to_owned(),
+        e => e.to_string(),
+    }
+}
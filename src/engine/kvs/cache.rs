@@ -0,0 +1,64 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// A least-recently-used cache of values, bounded by the bytes of keys and values it holds.
+pub(super) struct Cache {
+    capacity: u64,
+    size: u64,
+    tick: u64,
+    entries: HashMap<String, (String, u64)>,
+    order: BTreeMap<u64, String>,
+}
+
+impl Cache {
+    pub(super) fn new(capacity: u64) -> Self {
+        Cache {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub(super) fn get(&mut self, key: &str) -> Option<String> {
+        let tick = self.next_tick();
+        let (value, last_used) = self.entries.get_mut(key)?;
+        let key = self
+            .order
+            .remove(last_used)
+            .expect("cache order out of sync");
+        self.order.insert(tick, key);
+        *last_used = tick;
+        Some(value.clone())
+    }
+
+    pub(super) fn insert(&mut self, key: String, value: String) {
+        let size = (key.len() + value.len()) as u64;
+        if size > self.capacity {
+            return;
+        }
+        self.remove(&key);
+
+        let tick = self.next_tick();
+        self.size += size;
+        self.order.insert(tick, key.clone());
+        self.entries.insert(key, (value, tick));
+
+        while self.size > self.capacity {
+            let (_, oldest) = self.order.pop_first().expect("cache size out of sync");
+            self.remove(&oldest);
+        }
+    }
+
+    pub(super) fn remove(&mut self, key: &str) {
+        if let Some((value, last_used)) = self.entries.remove(key) {
+            self.order.remove(&last_used);
+            self.size -= (key.len() + value.len()) as u64;
+        }
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
@@ -0,0 +1,111 @@
+use crate::err::{KvsError, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::Rng;
+use std::fmt;
+use std::path::Path;
+use zeroize::{Zeroize, Zeroizing};
+
+const NONCE_LEN: usize = 24;
+
+/// A 256-bit key encrypting the records of a `KvStore` with XChaCha20-Poly1305.
+///
+/// The key material is wiped from memory when the key is dropped.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    cipher: XChaCha20Poly1305,
+    fingerprint: [u8; 8],
+}
+
+impl EncryptionKey {
+    /// The environment variable the binaries read a key from when no key file is given.
+    pub const ENV: &'static str = "KVS_ENCRYPTION_KEY";
+
+    pub fn new(mut bytes: [u8; 32]) -> Self {
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&bytes));
+        bytes.zeroize();
+        // the tag of an empty message identifies the key without revealing anything about it
+        let tag = cipher
+            .encrypt(XNonce::from_slice(&[0u8; NONCE_LEN]), &b""[..])
+            .expect("encrypting an empty message can not fail");
+        let mut fingerprint = [0u8; 8];
+        fingerprint.copy_from_slice(&tag[..8]);
+        EncryptionKey {
+            cipher,
+            fingerprint,
+        }
+    }
+
+    /// Parse a key written as 64 hexadecimal digits.
+    pub fn from_hex(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(KvsError::Parse);
+        }
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| KvsError::Parse)?;
+        }
+        Ok(Self::new(*bytes))
+    }
+
+    /// Read a key written as hexadecimal digits from a file.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        Self::from_hex(&Zeroizing::new(std::fs::read_to_string(path)?))
+    }
+
+    /// Read a key written as hexadecimal digits from an environment variable, if it is set.
+    pub fn from_env(var: &str) -> Result<Option<Self>> {
+        match std::env::var(var) {
+            Ok(hex) => Ok(Some(Self::from_hex(&Zeroizing::new(hex))?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub(super) fn fingerprint(&self) -> [u8; 8] {
+        self.fingerprint
+    }
+
+    /// Encrypt a payload under a random nonce, which is prepended to the result. The payload
+    /// only opens again with the same associated data.
+    pub(super) fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce[..]);
+        let payload = Payload {
+            msg: plaintext,
+            aad,
+        };
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .expect("encryption can not fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend_from_slice(&ciphertext);
+        sealed
+    }
+
+    /// Decrypt a payload sealed with `seal` and the same associated data, or return `None` if
+    /// it was tampered with.
+    pub(super) fn open(&self, sealed: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        self.cipher.decrypt(XNonce::from_slice(nonce), payload).ok()
+    }
+}
+
+/// Shows the fingerprint only.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey(")?;
+        for byte in &self.fingerprint {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, ")")
+    }
+}
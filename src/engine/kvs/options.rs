@@ -0,0 +1,143 @@
+use super::{EncryptionKey, KvStore};
+use crate::err::{KvsError, Result};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// When appended records are flushed to disk with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncPolicy {
+    /// Leave flushing to the operating system.
+    Never,
+    /// Sync after every write.
+    Always,
+    /// Sync on a write if the last sync is older than the interval.
+    Interval(Duration),
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    /// Parse `never`, `always`, or an interval in milliseconds such as `100ms`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            _ => s
+                .strip_suffix("ms")
+                .and_then(|ms| ms.parse().ok())
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .ok_or(KvsError::Parse),
+        }
+    }
+}
+
+/// Options for opening a `KvStore`.
+///
+/// Compaction starts once the stale bytes in the log reach both
+/// `compaction_threshold` and `compaction_ratio` of the log size.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(super) compaction_threshold: u64,
+    pub(super) compaction_ratio: f64,
+    pub(super) segment_size: u64,
+    pub(super) sync: SyncPolicy,
+    pub(super) cache_size: u64,
+    pub(super) read_only: bool,
+    pub(super) mmap: bool,
+    pub(super) encryption_key: Option<EncryptionKey>,
+    /// Keys records may be encrypted with, the encryption key included.
+    pub(super) decryption_keys: Vec<EncryptionKey>,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            compaction_threshold: 128 * 1024,
+            compaction_ratio: 0.5,
+            segment_size: 1024 * 1024,
+            sync: SyncPolicy::Never,
+            cache_size: 0,
+            read_only: false,
+            mmap: false,
+            encryption_key: None,
+            decryption_keys: Vec::new(),
+        }
+    }
+}
+
+impl KvStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the minimum amount of stale bytes before compaction is started.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Set the minimum fraction of the log, between 0 and 1, that must be stale
+    /// before compaction is started.
+    pub fn compaction_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_ratio = ratio.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Set the size at which the active log segment is sealed and a new one is started.
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes.max(1);
+        self
+    }
+
+    /// Set when writes are synced to disk.
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// Set the amount of key and value bytes kept in the read cache. `0` disables the cache.
+    pub fn cache_size(mut self, bytes: u64) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    /// Open the store without creating or modifying any file. Writes return an error.
+    ///
+    /// Reads see the records appended since by a writer in another process, and keep
+    /// working across its compactions.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Read records of sealed segments through memory maps rather than file reads.
+    ///
+    /// The active segment, still growing, is always read from the file.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Encrypt the records written from now on with a key.
+    ///
+    /// Records written in plain or with a key given to `decryption_key` are re-encrypted with
+    /// this one by the next compaction. Opening a store encrypted with another key fails with
+    /// `WrongKey`.
+    pub fn encryption_key(mut self, key: EncryptionKey) -> Self {
+        self.decryption_keys.push(key.clone());
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Accept an additional key for reading records, such as one being rotated out.
+    pub fn decryption_key(mut self, key: EncryptionKey) -> Self {
+        self.decryption_keys.push(key);
+        self
+    }
+
+    /// Open the KvStore at a given path with these options.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, self.clone())
+    }
+}
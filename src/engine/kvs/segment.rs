@@ -0,0 +1,311 @@
+use super::crypto::EncryptionKey;
+use super::Command;
+use crate::err::{KvsError, Result};
+use crc32fast::Hasher;
+use memmap2::Mmap;
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Start of the segments written by this version: a magic number, a format version and a
+/// byte of flags. Encrypted segments follow it with the fingerprint of their key.
+///
+/// The records of encrypted segments are authenticated along with their generation and
+/// offset, so that a record moved, copied or swapped with another fails to open. Encrypted
+/// segments written by earlier versions lack the flag saying so, and their records are
+/// authenticated alone.
+///
+/// Segments written by earlier versions have no header and start with the big-endian length
+/// of their first record, whose first byte is zero.
+const MAGIC: &[u8; 6] = b"KVSLOG";
+const VERSION: u8 = 1;
+const HEADER_LEN: u64 = 8;
+const FLAG_ENCRYPTED: u8 = 1;
+const FLAG_POSITIONED: u8 = 2;
+const FINGERPRINT_LEN: u64 = 8;
+
+/// How the records of a segment are laid out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentFormat {
+    /// No header; each record is its length followed by the JSON of the command.
+    Legacy,
+    /// A header; each record is its length, a CRC32 of the JSON and the JSON.
+    Checksummed,
+    /// A header naming the key; each record is its length, a CRC32 of the payload and the
+    /// payload, which is the JSON encrypted under a random nonce and bound to the position of
+    /// the record.
+    Encrypted,
+}
+
+impl SegmentFormat {
+    fn header_len(self) -> u64 {
+        match self {
+            SegmentFormat::Legacy => 0,
+            SegmentFormat::Checksummed => HEADER_LEN,
+            SegmentFormat::Encrypted => HEADER_LEN + FINGERPRINT_LEN,
+        }
+    }
+}
+
+/// A log segment file.
+pub(super) struct Segment {
+    pub(super) file: File,
+    gen: u64,
+    /// `None` until the header, or the first record of a legacy segment, is written.
+    pub(super) format: Option<SegmentFormat>,
+    /// The key of an encrypted segment.
+    key: Option<EncryptionKey>,
+    /// Whether the records of an encrypted segment are bound to their position.
+    positioned: bool,
+    /// A map of the whole file, once sealed, for `read_mapped`.
+    map: Option<Mmap>,
+}
+
+impl Segment {
+    pub(super) fn open(path: &Path, gen: u64, writable: bool) -> Result<Segment> {
+        let file = OpenOptions::new()
+            .create(writable)
+            .read(true)
+            .write(writable)
+            .truncate(false)
+            .open(path)?;
+        Ok(Segment {
+            file,
+            gen,
+            format: None,
+            key: None,
+            positioned: false,
+            map: None,
+        })
+    }
+
+    /// Detect the format of the segment, if not known yet, picking its key among `keys`.
+    ///
+    /// Return the offset of the first record, or `None` if too little is written to tell.
+    /// Fail with `WrongKey` if the segment is encrypted with none of the keys.
+    pub(super) fn read_header(&mut self, keys: &[EncryptionKey]) -> Result<Option<u64>> {
+        if let Some(format) = self.format {
+            return Ok(Some(format.header_len()));
+        }
+
+        let mut header = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        (&mut self.file)
+            .take(HEADER_LEN + FINGERPRINT_LEN)
+            .read_to_end(&mut header)?;
+        if header.len() < HEADER_LEN as usize {
+            return Ok(None);
+        }
+
+        let format = if header.starts_with(MAGIC) {
+            match (header[6], header[7] & !FLAG_POSITIONED) {
+                (VERSION, 0) if header[7] == 0 => SegmentFormat::Checksummed,
+                (VERSION, FLAG_ENCRYPTED) => SegmentFormat::Encrypted,
+                _ => return Err(KvsError::Corruption { offset: 6 }),
+            }
+        } else if header[0] == 0 {
+            SegmentFormat::Legacy
+        } else {
+            return Err(KvsError::Corruption { offset: 0 });
+        };
+
+        if format == SegmentFormat::Encrypted {
+            let fingerprint = match header.get(HEADER_LEN as usize..) {
+                Some(fingerprint) if fingerprint.len() == FINGERPRINT_LEN as usize => fingerprint,
+                _ => return Ok(None),
+            };
+            let key = keys.iter().find(|key| key.fingerprint() == fingerprint);
+            self.key = Some(key.ok_or(KvsError::WrongKey)?.clone());
+            self.positioned = header[7] & FLAG_POSITIONED != 0;
+        }
+        self.format = Some(format);
+        Ok(Some(format.header_len()))
+    }
+
+    /// Start an empty segment with a header, to be written with the given key or in plain.
+    ///
+    /// Return the offset of the first record.
+    pub(super) fn write_header(&mut self, key: Option<&EncryptionKey>) -> Result<u64> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(MAGIC)?;
+        match key {
+            None => {
+                self.file.write_all(&[VERSION, 0])?;
+                self.format = Some(SegmentFormat::Checksummed);
+            }
+            Some(key) => {
+                self.file
+                    .write_all(&[VERSION, FLAG_ENCRYPTED | FLAG_POSITIONED])?;
+                self.file.write_all(&key.fingerprint())?;
+                self.format = Some(SegmentFormat::Encrypted);
+            }
+        }
+        self.key = key.cloned();
+        self.positioned = true;
+        Ok(self.format.map_or(0, SegmentFormat::header_len))
+    }
+
+    /// Whether records are written to the segment the way `encode` does with the given key.
+    pub(super) fn encoded_with(&self, key: Option<&EncryptionKey>) -> bool {
+        match (self.format, key) {
+            (Some(SegmentFormat::Checksummed), None) => true,
+            (Some(SegmentFormat::Encrypted), Some(key)) => {
+                self.positioned
+                    && self.key.as_ref().map(EncryptionKey::fingerprint) == Some(key.fingerprint())
+            }
+            _ => false,
+        }
+    }
+
+    /// Read the record at a given offset, or at the current position.
+    ///
+    /// Return the command and the length of the record. A record cut short by the end of the
+    /// file is an `UnexpectedEof` error, and a record failing its checksum or authentication
+    /// is a `Corruption`; only when it ends the file, as `at_end` tells, may a crash have torn
+    /// it.
+    pub(super) fn read_record(&mut self, offset: Option<u64>) -> Result<(Command, u64)> {
+        let offset = match offset {
+            Some(off) => self.file.seek(SeekFrom::Start(off))?,
+            None => self.file.stream_position()?,
+        };
+        let checksummed = self.format != Some(SegmentFormat::Legacy);
+
+        let mut s = [0u8; 8];
+        self.file.read_exact(&mut s)?;
+        let vsize = usize::from_be_bytes(s);
+        let mut crc = [0u8; 4];
+        if checksummed {
+            self.file.read_exact(&mut crc)?;
+        }
+
+        // a torn record may carry a bogus length; read what is there rather than
+        // allocating for the length up front
+        let mut e = Vec::new();
+        (&mut self.file).take(vsize as u64).read_to_end(&mut e)?;
+        if e.len() != vsize {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        let r = self.decode(offset, checksummed.then_some(crc), &e)?;
+
+        let prefix = if checksummed { 12 } else { 8 };
+        Ok((r, prefix + vsize as u64))
+    }
+
+    /// Whether the last record read ended the file.
+    pub(super) fn at_end(&mut self) -> Result<bool> {
+        Ok(self.file.stream_position()? >= self.file.metadata()?.len())
+    }
+
+    /// Read the record at a given offset like `read_record`, but by copying it out of a map of
+    /// the file instead of reading the file. Only for sealed segments, which no longer grow.
+    ///
+    /// The file is mapped on first use. Its length is checked against the record, so a
+    /// record past the end of the map is an `UnexpectedEof` error rather than a crash.
+    pub(super) fn read_mapped(&mut self, offset: u64) -> Result<(Command, u64)> {
+        if self.map.is_none() {
+            // Safety: sealed segments are never written to again, and are only truncated
+            // by `KvStore::repair`, which must not run while the store is open.
+            self.map = Some(unsafe { Mmap::map(&self.file)? });
+        }
+        let map = self.map.as_ref().expect("segment not mapped");
+        let checksummed = self.format != Some(SegmentFormat::Legacy);
+        let prefix = if checksummed { 12 } else { 8 };
+        let eof = || io::Error::from(io::ErrorKind::UnexpectedEof);
+
+        let record = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| map.get(offset..))
+            .filter(|record| record.len() >= prefix)
+            .ok_or_else(eof)?;
+        let mut s = [0u8; 8];
+        s.copy_from_slice(&record[..8]);
+        let vsize = usize::from_be_bytes(s);
+        let mut crc = [0u8; 4];
+        if checksummed {
+            crc.copy_from_slice(&record[8..12]);
+        }
+        let payload = record
+            .get(prefix..)
+            .and_then(|payload| payload.get(..vsize))
+            .ok_or_else(eof)?;
+        let r = self.decode(offset, checksummed.then_some(crc), payload)?;
+
+        Ok((r, (prefix + vsize) as u64))
+    }
+
+    /// Check and decrypt the payload of the record at a given offset, then parse the command.
+    fn decode(&self, offset: u64, crc: Option<[u8; 4]>, payload: &[u8]) -> Result<Command> {
+        let corruption = KvsError::Corruption { offset };
+        if crc.is_some_and(|crc| checksum(payload) != u32::from_be_bytes(crc)) {
+            return Err(corruption);
+        }
+        let position = position(self.gen, offset);
+        let aad: &[u8] = if self.positioned { &position } else { &[] };
+        match &self.key {
+            Some(key) => Ok(serde_json::from_slice(
+                &key.open(payload, aad).ok_or(corruption)?,
+            )?),
+            None => Ok(serde_json::from_slice(payload)?),
+        }
+    }
+
+    /// Read the raw bytes of a record.
+    pub(super) fn read_raw(&mut self, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; len as usize];
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Write raw records at a given offset.
+    ///
+    /// Return the offset past them.
+    pub(super) fn write_raw(&mut self, buf: &[u8], offset: u64) -> Result<u64> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(buf)?;
+        Ok(offset + buf.len() as u64)
+    }
+
+    /// Write a command at a given offset, in the format of a segment with a header.
+    ///
+    /// Return the offset past its record.
+    pub(super) fn append_record(&mut self, command: &Command, offset: u64) -> Result<u64> {
+        let buf = encode(command, self.key.as_ref(), self.gen, offset)?;
+        self.write_raw(&buf, offset)
+    }
+}
+
+/// Encode a command into a record, encrypted with the given key or in plain. An encrypted
+/// record only opens at the given generation and offset.
+pub(super) fn encode(
+    command: &Command,
+    key: Option<&EncryptionKey>,
+    gen: u64,
+    offset: u64,
+) -> Result<Vec<u8>> {
+    let mut b = serde_json::to_vec(command)?;
+    if let Some(key) = key {
+        b = key.seal(&b, &position(gen, offset));
+    }
+    let mut buf = Vec::with_capacity(b.len() + 12);
+    buf.extend_from_slice(&b.len().to_be_bytes());
+    buf.extend_from_slice(&checksum(&b).to_be_bytes());
+    buf.extend_from_slice(&b);
+    Ok(buf)
+}
+
+/// The associated data binding an encrypted record to its generation and offset.
+fn position(gen: u64, offset: u64) -> [u8; 16] {
+    let mut position = [0u8; 16];
+    position[..8].copy_from_slice(&gen.to_be_bytes());
+    position[8..].copy_from_slice(&offset.to_be_bytes());
+    position
+}
+
+fn checksum(buf: &[u8]) -> u32 {
+    let mut hasher = Hasher::new();
+    hasher.update(buf);
+    hasher.finalize()
+}
@@ -0,0 +1,148 @@
+use super::{list_gens, KvStore, KvStoreOptions};
+use crate::engine::offload::{io_pool, offload};
+use crate::engine::{AsyncKvsEngine, KvsEngine, KvsFuture, Stats};
+use crate::err::{KvsError, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Name of the file recording the number of shards of a store.
+const SHARDS_FILE: &str = "shards";
+
+/// A store partitioning keys by hash across independent `KvStore`s, each with its own log
+/// and compaction, in the `shard-<n>` subdirectories of its path.
+///
+/// Writes to different shards proceed in parallel. The number of shards can not change once
+/// the store is created.
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Arc<Vec<KvStore>>,
+}
+
+impl ShardedKvStore {
+    /// Open a store with a given number of shards at a given path, with the default options.
+    pub fn open(path: impl Into<PathBuf>, shards: usize) -> Result<ShardedKvStore> {
+        Self::open_with(path, shards, KvStoreOptions::default())
+    }
+
+    /// Open a store with a given number of shards at a given path, opening every shard with
+    /// the given options.
+    ///
+    /// Fail with `ShardMismatch` if the store was created with another number of shards, or
+    /// the path holds an unsharded `KvStore`.
+    pub fn open_with(
+        path: impl Into<PathBuf>,
+        shards: usize,
+        options: KvStoreOptions,
+    ) -> Result<ShardedKvStore> {
+        let path = path.into();
+        match Self::shard_count(&path)? {
+            Some(count) if count != shards => return Err(KvsError::ShardMismatch),
+            Some(_) => {}
+            None if shards == 0 || options.read_only => return Err(KvsError::ShardMismatch),
+            None => {
+                fs::create_dir_all(&path)?;
+                if !list_gens(&path)?.is_empty() {
+                    return Err(KvsError::ShardMismatch);
+                }
+                fs::write(path.join(SHARDS_FILE), format!("{}\n", shards))?;
+            }
+        }
+
+        let shards = (0..shards)
+            .map(|i| options.open(path.join(format!("shard-{}", i))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(ShardedKvStore {
+            shards: Arc::new(shards),
+        })
+    }
+
+    /// Get the number of shards recorded at a given path, or `None` if it holds no sharded
+    /// store.
+    pub fn shard_count(path: impl AsRef<Path>) -> Result<Option<usize>> {
+        match fs::read_to_string(path.as_ref().join(SHARDS_FILE)) {
+            Ok(count) => Ok(Some(
+                count
+                    .trim()
+                    .parse()
+                    .map_err(|_| KvsError::Corruption { offset: 0 })?,
+            )),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        // a hash that never changes, unlike the one of the standard library
+        let hash = crc32fast::hash(key.as_bytes()) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(self.shard(&key), key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(self.shard(&key), key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(self.shard(&key), key)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for shard in self.shards.iter() {
+            pairs.extend(KvsEngine::scan(shard, prefix.clone())?);
+        }
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    /// The sums of the statistics of the shards, and the last compaction of any of them.
+    fn stats(&self) -> Result<Stats> {
+        let mut total = Stats {
+            engine: "kvs".to_owned(),
+            ..Default::default()
+        };
+        for shard in self.shards.iter() {
+            let stats = KvsEngine::stats(shard)?;
+            total.live_keys += stats.live_keys;
+            total.tombstones += stats.tombstones;
+            total.live_bytes += stats.live_bytes;
+            total.dead_bytes += stats.dead_bytes;
+            total.compactions += stats.compactions;
+            total.compaction_duration += stats.compaction_duration;
+            total.last_compaction = total.last_compaction.max(stats.last_compaction);
+            total.threshold += stats.threshold;
+            total.file_size += stats.file_size;
+        }
+        Ok(total)
+    }
+}
+
+impl AsyncKvsEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        AsyncKvsEngine::set(self.shard(&key), key, value)
+    }
+
+    fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        AsyncKvsEngine::get(self.shard(&key), key)
+    }
+
+    fn remove(&self, key: String) -> KvsFuture<()> {
+        AsyncKvsEngine::remove(self.shard(&key), key)
+    }
+
+    fn scan(&self, prefix: String) -> KvsFuture<Vec<(String, String)>> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::scan(&store, prefix))
+    }
+
+    fn stats(&self) -> KvsFuture<Stats> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::stats(&store))
+    }
+}
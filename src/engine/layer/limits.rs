@@ -0,0 +1,98 @@
+use super::Layer;
+use crate::engine::{KvsEngine, Stats};
+use crate::err::{KvsError, Result};
+
+/// A layer rejecting oversized keys and values, or all writes in read-only mode.
+#[derive(Debug, Clone, Default)]
+pub struct LimitsLayer {
+    max_key_size: Option<usize>,
+    max_value_size: Option<usize>,
+    read_only: bool,
+}
+
+impl LimitsLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reject keys longer than the given number of bytes.
+    pub fn max_key_size(mut self, bytes: usize) -> Self {
+        self.max_key_size = Some(bytes);
+        self
+    }
+
+    /// Reject values longer than the given number of bytes.
+    pub fn max_value_size(mut self, bytes: usize) -> Self {
+        self.max_value_size = Some(bytes);
+        self
+    }
+
+    /// Reject `set` and `remove`.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for LimitsLayer {
+    type Engine = LimitsEngine<E>;
+
+    fn layer(&self, inner: E) -> LimitsEngine<E> {
+        LimitsEngine {
+            inner,
+            limits: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LimitsEngine<E: KvsEngine> {
+    inner: E,
+    limits: LimitsLayer,
+}
+
+impl<E: KvsEngine> LimitsEngine<E> {
+    fn check_key(&self, key: &str) -> Result<()> {
+        match self.limits.max_key_size {
+            Some(max) if key.len() > max => Err(KvsError::LimitExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_write(&self) -> Result<()> {
+        if self.limits.read_only {
+            return Err(KvsError::ReadOnly);
+        }
+        Ok(())
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for LimitsEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.check_write()?;
+        self.check_key(&key)?;
+        match self.limits.max_value_size {
+            Some(max) if value.len() > max => Err(KvsError::LimitExceeded),
+            _ => self.inner.set(key, value),
+        }
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.check_key(&key)?;
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.check_write()?;
+        self.check_key(&key)?;
+        self.inner.remove(key)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.inner.scan(prefix)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        self.inner.stats()
+    }
+}
@@ -0,0 +1,130 @@
+use super::Layer;
+use crate::engine::{KvsEngine, Operation, Stats};
+use crate::err::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const OPERATIONS: [Operation; 5] = [
+    Operation::Set,
+    Operation::Get,
+    Operation::Remove,
+    Operation::Scan,
+    Operation::Stats,
+];
+
+/// Counters of one kind of operation.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OperationMetrics {
+    /// Number of operations.
+    pub count: u64,
+    /// Number of operations that returned an error.
+    pub errors: u64,
+    /// Total time spent in the operations.
+    pub total: Duration,
+    /// Time spent in the slowest operation.
+    pub max: Duration,
+}
+
+#[derive(Default)]
+struct Counters {
+    count: AtomicU64,
+    errors: AtomicU64,
+    total_nanos: AtomicU64,
+    max_nanos: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, elapsed: Duration, failed: bool) {
+        let nanos = elapsed.as_nanos() as u64;
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> OperationMetrics {
+        OperationMetrics {
+            count: self.count.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            total: Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed)),
+            max: Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// A layer counting and timing every operation.
+///
+/// The counters are reported in `Stats::operations`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsLayer;
+
+impl MetricsLayer {
+    pub fn new() -> Self {
+        MetricsLayer
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for MetricsLayer {
+    type Engine = MetricsEngine<E>;
+
+    fn layer(&self, inner: E) -> MetricsEngine<E> {
+        MetricsEngine {
+            inner,
+            counters: Arc::new(Default::default()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsEngine<E: KvsEngine> {
+    inner: E,
+    counters: Arc<[Counters; 5]>,
+}
+
+impl<E: KvsEngine> MetricsEngine<E> {
+    /// Get the counters of every operation.
+    pub fn metrics(&self) -> BTreeMap<Operation, OperationMetrics> {
+        OPERATIONS
+            .iter()
+            .zip(self.counters.iter())
+            .map(|(op, counters)| (*op, counters.snapshot()))
+            .collect()
+    }
+
+    fn measure<T>(&self, op: Operation, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let res = f();
+        let index = OPERATIONS.iter().position(|o| *o == op).unwrap();
+        self.counters[index].record(start.elapsed(), res.is_err());
+        res
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for MetricsEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.measure(Operation::Set, || self.inner.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.measure(Operation::Get, || self.inner.get(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.measure(Operation::Remove, || self.inner.remove(key))
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.measure(Operation::Scan, || self.inner.scan(prefix))
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let mut stats = self.measure(Operation::Stats, || self.inner.stats())?;
+        stats.operations = self.metrics();
+        Ok(stats)
+    }
+}
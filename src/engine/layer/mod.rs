@@ -0,0 +1,26 @@
+use super::KvsEngine;
+
+/// Wraps an engine to add behavior around its operations.
+///
+/// Layers stack in the order they are applied, the last one being the outermost:
+///
+/// ```ignore
+/// let engine = KvStore::open(path)?
+///     .layer(LimitsLayer::new().max_key_size(256))
+///     .layer(MetricsLayer::new());
+/// ```
+pub trait Layer<E: KvsEngine> {
+    /// The engine produced by the layer.
+    type Engine: KvsEngine;
+
+    /// Wrap an engine.
+    fn layer(&self, inner: E) -> Self::Engine;
+}
+
+mod limits;
+mod metrics;
+mod slow_log;
+
+pub use self::limits::*;
+pub use self::metrics::*;
+pub use self::slow_log::*;
@@ -0,0 +1,71 @@
+use super::Layer;
+use crate::engine::{KvsEngine, Operation, Stats};
+use crate::err::Result;
+use std::time::{Duration, Instant};
+
+/// A layer logging every operation at debug level, and operations slower than a threshold
+/// as warnings.
+#[derive(Debug, Clone, Copy)]
+pub struct SlowLogLayer {
+    threshold: Duration,
+}
+
+impl SlowLogLayer {
+    pub fn new(threshold: Duration) -> Self {
+        SlowLogLayer { threshold }
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for SlowLogLayer {
+    type Engine = SlowLogEngine<E>;
+
+    fn layer(&self, inner: E) -> SlowLogEngine<E> {
+        SlowLogEngine {
+            inner,
+            threshold: self.threshold,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SlowLogEngine<E: KvsEngine> {
+    inner: E,
+    threshold: Duration,
+}
+
+impl<E: KvsEngine> SlowLogEngine<E> {
+    fn log<T>(&self, op: Operation, key: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        let start = Instant::now();
+        let res = f();
+        let elapsed = start.elapsed();
+
+        if elapsed >= self.threshold {
+            warn!("slow {:?} of {:?} took {:?}", op, key, elapsed);
+        } else {
+            debug!("{:?} of {:?} took {:?}", op, key, elapsed);
+        }
+        res
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for SlowLogEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.log(Operation::Set, &key.clone(), || self.inner.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.log(Operation::Get, &key.clone(), || self.inner.get(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.log(Operation::Remove, &key.clone(), || self.inner.remove(key))
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        self.log(Operation::Scan, &prefix.clone(), || self.inner.scan(prefix))
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        self.log(Operation::Stats, "", || self.inner.stats())
+    }
+}
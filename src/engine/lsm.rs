@@ -0,0 +1,573 @@
+use super::kvs::sync_dir;
+use super::offload::{io_pool, offload};
+use super::{AsyncKvsEngine, KvsEngine, KvsFuture, Stats, SyncPolicy};
+use crate::err::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, remove_file, rename, File};
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+pub use self::options::LsmStoreOptions;
+use self::table::{table_path, Table, TableBuilder};
+use self::wal::Wal;
+
+mod bloom;
+mod options;
+mod table;
+mod wal;
+
+/// A key and its value, or `None` for a removal.
+type Entry = (String, Option<String>);
+
+/// Name of the file listing the tables of each level.
+const MANIFEST: &str = "MANIFEST";
+
+/// The tables making up the store, rewritten as a whole after every flush and compaction.
+///
+/// Tables and logs the manifest does not account for are left over from an interrupted flush
+/// or compaction, and are removed on open.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Manifest {
+    next_id: u64,
+    /// The log the memtable is written to. Older logs are flushed.
+    wal: u64,
+    /// The ids of the tables of each level. Level 0 goes from the newest to the oldest, the
+    /// other levels in key order.
+    levels: Vec<Vec<u64>>,
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+/// The id and extension of a table or log file.
+fn parse_file_name(path: &Path) -> Option<(u64, &str)> {
+    let ext = path.extension().and_then(OsStr::to_str)?;
+    let id = path.file_stem().and_then(OsStr::to_str)?.parse().ok()?;
+    Some((id, ext))
+}
+
+struct RawLsmStore {
+    path: PathBuf,
+    options: LsmStoreOptions,
+    /// The latest writes, newer than any table.
+    memtable: BTreeMap<String, Option<String>>,
+    /// Bytes of the keys and values of the memtable.
+    memtable_bytes: u64,
+    wal: Wal,
+    wal_id: u64,
+    /// Logs of an interrupted flush, whose writes are in the memtable as well.
+    old_wals: Vec<u64>,
+    levels: Vec<Vec<Table>>,
+    /// For each level, the last key of the latest compaction out of it, so that compactions
+    /// take turns over its tables.
+    compact_pointers: Vec<String>,
+    next_id: u64,
+    last_sync: Instant,
+    compactions: u64,
+    compaction_duration: Duration,
+    last_compaction: Option<SystemTime>,
+}
+
+impl RawLsmStore {
+    fn new_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id - 1
+    }
+
+    fn get(&mut self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        for (level, tables) in self.levels.iter_mut().enumerate() {
+            let candidates = if level == 0 {
+                &mut tables[..]
+            } else {
+                // tables of deeper levels do not overlap; only one may hold the key
+                let i = tables.partition_point(|t| t.last_key.as_str() < key);
+                let end = (i + 1).min(tables.len());
+                &mut tables[i..end]
+            };
+            for table in candidates {
+                if let Some(value) = table.get(key)? {
+                    return Ok(value);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterators over the entries from the first key with a given prefix, from the newest
+    /// source to the oldest. The memtable is copied up to the last key with the prefix; the
+    /// tables are read lazily, until the caller stops.
+    fn sources(&self, prefix: &str) -> Result<Vec<Source>> {
+        let memtable: Vec<Entry> = self
+            .memtable
+            .range(prefix.to_owned()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let mut sources: Vec<Source> = vec![Box::new(memtable.into_iter().map(Ok))];
+        for table in self.levels.iter().flatten() {
+            if table.last_key.as_str() >= prefix {
+                sources.push(Box::new(table.iter_from(prefix)?));
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Record a write in the log and the memtable, flushing it once full.
+    fn write(&mut self, key: String, value: Option<String>) -> Result<()> {
+        self.wal.append(&key, value.as_deref())?;
+        self.sync()?;
+
+        self.memtable_bytes += (key.len() + value.as_ref().map_or(0, String::len)) as u64;
+        let key_len = key.len();
+        if let Some(old) = self.memtable.insert(key, value) {
+            self.memtable_bytes -= (key_len + old.map_or(0, |v| v.len())) as u64;
+        }
+
+        // overwrites leave the memtable as large but grow the log
+        if self.wal.len >= self.options.memtable_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        let due = match self.options.sync {
+            SyncPolicy::Never => false,
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if due {
+            self.wal.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Write the memtable to a new level 0 table and start a new log, then compact the levels
+    /// that outgrew their size.
+    fn flush(&mut self) -> Result<()> {
+        if self.memtable.is_empty() {
+            return Ok(());
+        }
+        let id = self.new_id();
+        let mut builder = self.table_builder(id)?;
+        for (key, value) in &self.memtable {
+            builder.add(key, value.as_deref())?;
+        }
+        let table = builder.finish()?;
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].insert(0, table);
+
+        let wal_id = self.new_id();
+        self.wal = Wal::open(&wal_path(&self.path, wal_id), &mut BTreeMap::new())?;
+        let old_wal = mem::replace(&mut self.wal_id, wal_id);
+        self.write_manifest()?;
+        for id in self.old_wals.drain(..).chain(Some(old_wal)) {
+            remove_file(wal_path(&self.path, id))?;
+        }
+        self.memtable.clear();
+        self.memtable_bytes = 0;
+
+        while let Some(level) = self.level_to_compact() {
+            self.compact(level)?;
+        }
+        Ok(())
+    }
+
+    /// The level whose tables must move down: level 0 once it holds too many tables, or a
+    /// deeper level once it holds too many bytes.
+    fn level_to_compact(&self) -> Option<usize> {
+        if self
+            .levels
+            .first()
+            .is_some_and(|l| l.len() >= self.options.level0_tables)
+        {
+            return Some(0);
+        }
+        (1..self.levels.len()).find(|&level| {
+            let size: u64 = self.levels[level].iter().map(|t| t.size).sum();
+            let limit = 10u64
+                .saturating_pow(level as u32 - 1)
+                .saturating_mul(self.options.level_size);
+            size > limit
+        })
+    }
+
+    /// Merge tables of a level with the tables they overlap in the next one: all of level 0,
+    /// whose tables overlap each other, or the next table in turn of a deeper level.
+    ///
+    /// The merged tables are written before the manifest switches to them, so an interrupted
+    /// compaction leaves the store as it was.
+    fn compact(&mut self, level: usize) -> Result<()> {
+        let start = Instant::now();
+        while self.levels.len() < level + 2 {
+            self.levels.push(Vec::new());
+        }
+        self.compact_pointers
+            .resize(self.levels.len(), String::new());
+
+        let upper: Vec<usize> = if level == 0 {
+            (0..self.levels[0].len()).collect()
+        } else {
+            let pointer = &self.compact_pointers[level];
+            let tables = &self.levels[level];
+            let i = tables.iter().position(|t| t.first_key > *pointer);
+            vec![i.unwrap_or(0)]
+        };
+        let upper_tables = upper.iter().map(|&i| &self.levels[level][i]);
+        let first = upper_tables.clone().map(|t| &t.first_key).min().cloned();
+        let last = upper_tables.map(|t| &t.last_key).max().cloned();
+        let (first, last) = match (first, last) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return Ok(()),
+        };
+        let lower: Vec<usize> = (0..self.levels[level + 1].len())
+            .filter(|&i| self.levels[level + 1][i].overlaps(&first, &last))
+            .collect();
+
+        let mut sources: Vec<Source> = Vec::new();
+        for &i in &upper {
+            sources.push(Box::new(self.levels[level][i].iter_from("")?));
+        }
+        for &i in &lower {
+            sources.push(Box::new(self.levels[level + 1][i].iter_from("")?));
+        }
+        // removals can be dropped once no deeper level may hold a value they hide
+        let bottom = self.levels[level + 2..].iter().all(Vec::is_empty);
+
+        let mut outputs = Vec::new();
+        let mut builder = None;
+        for entry in Merge::new(sources)? {
+            let (key, value) = entry?;
+            if value.is_none() && bottom {
+                continue;
+            }
+            let mut b = match builder.take() {
+                Some(b) => b,
+                None => {
+                    let id = self.new_id();
+                    self.table_builder(id)?
+                }
+            };
+            b.add(&key, value.as_deref())?;
+            if b.size() >= self.options.table_size {
+                outputs.push(b.finish()?);
+            } else {
+                builder = Some(b);
+            }
+        }
+        outputs.extend(builder.map(TableBuilder::finish).transpose()?);
+
+        let mut obsolete = Vec::new();
+        for &i in upper.iter().rev() {
+            obsolete.push(self.levels[level].remove(i));
+        }
+        for &i in lower.iter().rev() {
+            obsolete.push(self.levels[level + 1].remove(i));
+        }
+        let next = &mut self.levels[level + 1];
+        next.extend(outputs);
+        next.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        self.compact_pointers[level] = last;
+        self.write_manifest()?;
+        for table in obsolete {
+            remove_file(&table.path)?;
+        }
+
+        self.compactions += 1;
+        self.compaction_duration += start.elapsed();
+        self.last_compaction = Some(SystemTime::now());
+        Ok(())
+    }
+
+    fn table_builder(&self, id: u64) -> Result<TableBuilder> {
+        TableBuilder::create(
+            id,
+            table_path(&self.path, id),
+            self.options.block_size,
+            self.options.bloom_bits_per_key,
+        )
+    }
+
+    /// Replace the manifest with one listing the current tables.
+    fn write_manifest(&self) -> Result<()> {
+        let manifest = Manifest {
+            next_id: self.next_id,
+            wal: self.wal_id,
+            levels: self
+                .levels
+                .iter()
+                .map(|tables| tables.iter().map(|t| t.id).collect())
+                .collect(),
+        };
+        let tmp = self.path.join(format!("{}.tmp", MANIFEST));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&manifest)?)?;
+        file.sync_all()?;
+        rename(tmp, self.path.join(MANIFEST))?;
+        sync_dir(&self.path)
+    }
+}
+
+/// A store built as a log-structured merge tree.
+///
+/// Writes go to a write-ahead log and an in-memory table, which is flushed to an immutable
+/// sorted table once full. Tables are organised in levels and merged down by compaction. Only
+/// the block indexes and bloom filters of the tables are kept in memory, so unlike `KvStore`
+/// the keys need not fit in memory.
+#[derive(Clone)]
+pub struct LsmStore(Arc<Mutex<RawLsmStore>>);
+
+impl KvsEngine for LsmStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.lock().unwrap().write(key, Some(value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.lock().unwrap().get(&key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let mut guard = self.0.lock().unwrap();
+        if guard.get(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        guard.write(key, None)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let guard = self.0.lock().unwrap();
+        let mut result = Vec::new();
+        for entry in Merge::new(guard.sources(&prefix)?)? {
+            let (key, value) = entry?;
+            if !key.starts_with(&prefix) {
+                break;
+            }
+            result.extend(value.map(|value| (key, value)));
+        }
+        Ok(result)
+    }
+
+    /// Counting live keys reads every table.
+    fn stats(&self) -> Result<Stats> {
+        let guard = self.0.lock().unwrap();
+        let (mut live_keys, mut tombstones, mut live_bytes) = (0, 0, 0);
+        for entry in Merge::new(guard.sources("")?)? {
+            match entry? {
+                (key, Some(value)) => {
+                    live_keys += 1;
+                    live_bytes += (key.len() + value.len()) as u64;
+                }
+                (_, None) => tombstones += 1,
+            }
+        }
+
+        let tables = guard.levels.iter().flatten();
+        let data_bytes: u64 = tables.clone().map(|t| t.data_bytes).sum();
+        let mut file_size: u64 = tables.map(|t| t.size).sum();
+        file_size += guard.wal.len;
+        Ok(Stats {
+            engine: "lsm".to_owned(),
+            live_keys,
+            tombstones,
+            live_bytes,
+            dead_bytes: (guard.memtable_bytes + data_bytes).saturating_sub(live_bytes),
+            compactions: guard.compactions,
+            compaction_duration: guard.compaction_duration,
+            last_compaction: guard.last_compaction,
+            file_size,
+            ..Default::default()
+        })
+    }
+}
+
+/// Disk I/O is run on a shared pool; lookups answered by the memtable resolve immediately,
+/// unless a write, flush or compaction holds the store.
+impl AsyncKvsEngine for LsmStore {
+    fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::set(&store, key, value))
+    }
+
+    fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        // the lock is held through whole compactions, so the caller never waits for it
+        let cached = match self.0.try_lock() {
+            Ok(guard) => guard.memtable.get(&key).cloned(),
+            Err(_) => None,
+        };
+        if let Some(value) = cached {
+            return Box::pin(async { Ok(value) });
+        }
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::get(&store, key))
+    }
+
+    fn remove(&self, key: String) -> KvsFuture<()> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::remove(&store, key))
+    }
+
+    fn scan(&self, prefix: String) -> KvsFuture<Vec<(String, String)>> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::scan(&store, prefix))
+    }
+
+    fn stats(&self) -> KvsFuture<Stats> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::stats(&store))
+    }
+}
+
+impl LsmStore {
+    /// Open the LsmStore at a given path with the default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmStore> {
+        Self::open_with(path, LsmStoreOptions::default())
+    }
+
+    /// Open the LsmStore at a given path with the given options.
+    ///
+    /// The writes of the log not yet flushed are replayed into the memtable, and the files
+    /// left over from an interrupted flush or compaction are removed.
+    pub fn open_with(path: impl Into<PathBuf>, options: LsmStoreOptions) -> Result<LsmStore> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        let manifest: Manifest = match fs::read(path.join(MANIFEST)) {
+            Ok(b) => serde_json::from_slice(&b)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Manifest::default(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let live: HashSet<u64> = manifest.levels.iter().flatten().copied().collect();
+        let mut wals = Vec::new();
+        let mut next_id = manifest.next_id;
+        for entry in fs::read_dir(&path)? {
+            let file = entry?.path();
+            let (id, ext) = match parse_file_name(&file) {
+                Some(name) => name,
+                None => continue,
+            };
+            next_id = next_id.max(id + 1);
+            match ext {
+                "sst" if !live.contains(&id) => remove_file(&file)?,
+                "wal" if id < manifest.wal => remove_file(&file)?,
+                "wal" => wals.push(id),
+                _ => {}
+            }
+        }
+        wals.sort_unstable();
+
+        let levels = manifest
+            .levels
+            .iter()
+            .map(|ids| {
+                ids.iter()
+                    .map(|&id| Table::open(id, table_path(&path, id)))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let wal_id = match wals.pop() {
+            Some(id) => id,
+            None => {
+                next_id += 1;
+                next_id - 1
+            }
+        };
+        let mut memtable = BTreeMap::new();
+        for &id in &wals {
+            Wal::open(&wal_path(&path, id), &mut memtable)?;
+        }
+        let wal = Wal::open(&wal_path(&path, wal_id), &mut memtable)?;
+        let memtable_bytes = memtable
+            .iter()
+            .map(|(k, v)| (k.len() + v.as_ref().map_or(0, String::len)) as u64)
+            .sum();
+
+        Ok(LsmStore(Arc::new(Mutex::new(RawLsmStore {
+            path,
+            options,
+            memtable,
+            memtable_bytes,
+            wal,
+            wal_id,
+            old_wals: wals,
+            levels,
+            compact_pointers: Vec::new(),
+            next_id,
+            last_sync: Instant::now(),
+            compactions: 0,
+            compaction_duration: Duration::default(),
+            last_compaction: None,
+        }))))
+    }
+
+    /// Write the memtable to a table, so that the log holds nothing to replay on open.
+    pub fn flush(&self) -> Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+type Source = Box<dyn Iterator<Item = Result<Entry>> + Send>;
+
+/// Merges sources of entries sorted by key into one, keeping for each key the entry of the
+/// first source holding it.
+struct Merge {
+    sources: Vec<Source>,
+    heads: Vec<Option<Entry>>,
+}
+
+impl Merge {
+    fn new(sources: Vec<Source>) -> Result<Merge> {
+        let mut merge = Merge {
+            heads: sources.iter().map(|_| None).collect(),
+            sources,
+        };
+        for i in 0..merge.sources.len() {
+            merge.advance(i)?;
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, i: usize) -> Result<()> {
+        self.heads[i] = self.sources[i].next().transpose()?;
+        Ok(())
+    }
+
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        // `min_by_key` keeps the first of equal keys, which is the newest
+        let i = match (0..self.heads.len())
+            .filter(|&i| self.heads[i].is_some())
+            .min_by_key(|&i| self.heads[i].as_ref().map(|(k, _)| k))
+        {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let entry = self.heads[i].take().expect("merge head is empty");
+        self.advance(i)?;
+        for j in 0..self.heads.len() {
+            if self.heads[j].as_ref().is_some_and(|(k, _)| *k == entry.0) {
+                self.advance(j)?;
+            }
+        }
+        Ok(Some(entry))
+    }
+}
+
+impl Iterator for Merge {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_entry().transpose()
+    }
+}
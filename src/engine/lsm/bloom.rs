@@ -0,0 +1,65 @@
+/// A bloom filter over the keys of a table, telling which keys it surely does not hold.
+///
+/// Probes are derived from a single CRC32 of the key by double hashing.
+pub(super) struct Bloom {
+    bits: Vec<u8>,
+    probes: u8,
+}
+
+impl Bloom {
+    /// Build a filter from the hashes of the keys, with about `bits_per_key` bits each.
+    pub(super) fn build(hashes: &[u32], bits_per_key: usize) -> Bloom {
+        // ln(2) times the bits per key minimises false positives
+        let probes = ((bits_per_key as f64 * 0.69) as u8).clamp(1, 30);
+        let len = (hashes.len() * bits_per_key).max(64).div_ceil(8);
+        let mut bloom = Bloom {
+            bits: vec![0; len],
+            probes,
+        };
+        let nbits = bloom.bits.len() as u32 * 8;
+        for &hash in hashes {
+            for bit in probe(hash, probes) {
+                let bit = bit % nbits;
+                bloom.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    /// Whether a key with the given hash may be in the set.
+    pub(super) fn may_contain(&self, hash: u32) -> bool {
+        let nbits = self.bits.len() as u32 * 8;
+        probe(hash, self.probes).all(|bit| {
+            let bit = bit % nbits;
+            self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+        })
+    }
+
+    /// The bits followed by the number of probes.
+    pub(super) fn encode(&self) -> Vec<u8> {
+        let mut buf = self.bits.clone();
+        buf.push(self.probes);
+        buf
+    }
+
+    /// Decode a filter written by `encode`, or return `None` if it is malformed.
+    pub(super) fn decode(buf: &[u8]) -> Option<Bloom> {
+        match buf.split_last() {
+            Some((&probes, bits)) if !bits.is_empty() && probes > 0 => Some(Bloom {
+                bits: bits.to_vec(),
+                probes,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The hash of a key the filter is built from and probed with.
+pub(super) fn hash(key: &str) -> u32 {
+    crc32fast::hash(key.as_bytes())
+}
+
+fn probe(hash: u32, probes: u8) -> impl Iterator<Item = u32> {
+    let delta = hash.rotate_right(17);
+    (0..u32::from(probes)).map(move |i| hash.wrapping_add(delta.wrapping_mul(i)))
+}
@@ -0,0 +1,88 @@
+use super::LsmStore;
+use crate::engine::SyncPolicy;
+use crate::err::Result;
+use std::path::PathBuf;
+
+/// Options for opening an `LsmStore`.
+///
+/// Level 0 holds the tables flushed from the memtable, which may overlap. Every deeper level
+/// is a run of non-overlapping tables, `level_size` bytes in total for level 1 and ten times
+/// more for each level below.
+#[derive(Debug, Clone)]
+pub struct LsmStoreOptions {
+    pub(super) memtable_size: u64,
+    pub(super) block_size: usize,
+    pub(super) table_size: u64,
+    pub(super) level0_tables: usize,
+    pub(super) level_size: u64,
+    pub(super) bloom_bits_per_key: usize,
+    pub(super) sync: SyncPolicy,
+}
+
+impl Default for LsmStoreOptions {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            table_size: 2 * 1024 * 1024,
+            level0_tables: 4,
+            level_size: 10 * 1024 * 1024,
+            bloom_bits_per_key: 10,
+            sync: SyncPolicy::Never,
+        }
+    }
+}
+
+impl LsmStoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the size of the write-ahead log at which the memtable is flushed to a table. This
+    /// bounds both the memory held by the memtable and the log replayed on open.
+    pub fn memtable_size(mut self, bytes: u64) -> Self {
+        self.memtable_size = bytes.max(1);
+        self
+    }
+
+    /// Set the size of the data blocks of tables, the unit read from disk by a lookup.
+    pub fn block_size(mut self, bytes: usize) -> Self {
+        self.block_size = bytes.max(1);
+        self
+    }
+
+    /// Set the size at which compaction starts a new output table.
+    pub fn table_size(mut self, bytes: u64) -> Self {
+        self.table_size = bytes.max(1);
+        self
+    }
+
+    /// Set the number of level 0 tables that triggers their compaction into level 1.
+    pub fn level0_tables(mut self, tables: usize) -> Self {
+        self.level0_tables = tables.max(1);
+        self
+    }
+
+    /// Set the size of level 1 beyond which its tables are compacted into level 2.
+    pub fn level_size(mut self, bytes: u64) -> Self {
+        self.level_size = bytes.max(1);
+        self
+    }
+
+    /// Set the bits of bloom filter per key. More bits spare more block reads for missing keys.
+    pub fn bloom_bits_per_key(mut self, bits: usize) -> Self {
+        self.bloom_bits_per_key = bits.max(1);
+        self
+    }
+
+    /// Set when writes to the write-ahead log are synced to disk.
+    pub fn sync(mut self, policy: SyncPolicy) -> Self {
+        self.sync = policy;
+        self
+    }
+
+    /// Open the LsmStore at a given path with these options.
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<LsmStore> {
+        LsmStore::open_with(path, self.clone())
+    }
+}
@@ -0,0 +1,401 @@
+use super::bloom::{self, Bloom};
+use super::Entry;
+use crate::err::{KvsError, Result};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// End of every table file.
+const MAGIC: &[u8; 8] = b"KVSSST01";
+/// The offsets of the index and the filter, the entry count, the data bytes and the magic.
+const FOOTER_LEN: u64 = 40;
+
+/// Where a data block lies, and the last key it holds.
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// An immutable file of entries sorted by key.
+///
+/// The file is a run of data blocks, each a list of entries followed by a CRC32, then an index
+/// holding the first key of the table and the last key and position of every block, then a
+/// bloom filter of the keys, then a footer. Only the index and the filter are kept in memory.
+pub(super) struct Table {
+    pub(super) id: u64,
+    pub(super) path: PathBuf,
+    file: File,
+    pub(super) first_key: String,
+    pub(super) last_key: String,
+    index: Arc<Vec<BlockHandle>>,
+    bloom: Bloom,
+    /// Bytes of the keys and values of the entries.
+    pub(super) data_bytes: u64,
+    /// Size of the file.
+    pub(super) size: u64,
+}
+
+impl Table {
+    /// Open the table at a given path, reading its index and filter.
+    ///
+    /// Fail with a `Corruption` if the footer, the index or the filter is damaged.
+    pub(super) fn open(id: u64, path: PathBuf) -> Result<Table> {
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            return Err(KvsError::Corruption { offset: 0 });
+        }
+        let footer_offset = size - FOOTER_LEN;
+        let mut footer = [0u8; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(footer_offset))?;
+        file.read_exact(&mut footer)?;
+        if &footer[32..] != MAGIC {
+            return Err(KvsError::Corruption {
+                offset: footer_offset + 32,
+            });
+        }
+        let index_offset = read_u64(&footer, 0);
+        let bloom_offset = read_u64(&footer, 8);
+        if index_offset > bloom_offset || bloom_offset > footer_offset {
+            return Err(KvsError::Corruption {
+                offset: footer_offset,
+            });
+        }
+
+        let index = read_block(&mut file, index_offset, bloom_offset - index_offset)?;
+        let (first_key, handles) = decode_index(&index).map_err(|e| e.offset_by(index_offset))?;
+        let last_key = match handles.last() {
+            Some(handle) => handle.last_key.clone(),
+            None => {
+                return Err(KvsError::Corruption {
+                    offset: index_offset,
+                })
+            }
+        };
+
+        let bloom = read_block(&mut file, bloom_offset, footer_offset - bloom_offset)?;
+        Ok(Table {
+            id,
+            path,
+            file,
+            first_key,
+            last_key,
+            index: Arc::new(handles),
+            bloom: Bloom::decode(&bloom).ok_or(KvsError::Corruption {
+                offset: bloom_offset,
+            })?,
+            data_bytes: read_u64(&footer, 24),
+            size,
+        })
+    }
+
+    /// Whether the key range of the table overlaps a given one.
+    pub(super) fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key.as_str() <= last && first <= self.last_key.as_str()
+    }
+
+    /// Look up a key. Return `None` if the table holds no entry for it, or the entry, which is
+    /// `Some(None)` for a removal.
+    pub(super) fn get(&mut self, key: &str) -> Result<Option<Option<String>>> {
+        if key < self.first_key.as_str()
+            || key > self.last_key.as_str()
+            || !self.bloom.may_contain(bloom::hash(key))
+        {
+            return Ok(None);
+        }
+        let i = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < key);
+        let handle = match self.index.get(i) {
+            Some(handle) => handle,
+            None => return Ok(None),
+        };
+
+        let block = read_block(&mut self.file, handle.offset, handle.len)?;
+        let mut pos = 0;
+        while pos < block.len() {
+            let (k, value) =
+                read_entry(&block, &mut pos).map_err(|e| e.offset_by(handle.offset))?;
+            if k == key {
+                return Ok(Some(value));
+            }
+            if k.as_str() > key {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterate over the entries with a key not less than `start`, reading one block at a time
+    /// through a file handle of its own.
+    pub(super) fn iter_from(&self, start: &str) -> Result<TableIter> {
+        let block = self
+            .index
+            .partition_point(|handle| handle.last_key.as_str() < start);
+        Ok(TableIter {
+            file: File::open(&self.path)?,
+            index: self.index.clone(),
+            block,
+            buf: Vec::new(),
+            buf_offset: 0,
+            pos: 0,
+            start: start.to_owned(),
+        })
+    }
+}
+
+/// The entries of a table in key order, from a starting key.
+pub(super) struct TableIter {
+    file: File,
+    index: Arc<Vec<BlockHandle>>,
+    /// The next block to read.
+    block: usize,
+    buf: Vec<u8>,
+    /// Offset of the block in `buf`.
+    buf_offset: u64,
+    pos: usize,
+    start: String,
+}
+
+impl TableIter {
+    fn next_entry(&mut self) -> Result<Option<Entry>> {
+        loop {
+            while self.pos < self.buf.len() {
+                let entry = read_entry(&self.buf, &mut self.pos)
+                    .map_err(|e| e.offset_by(self.buf_offset))?;
+                if entry.0 >= self.start {
+                    return Ok(Some(entry));
+                }
+            }
+            let handle = match self.index.get(self.block) {
+                Some(handle) => handle,
+                None => return Ok(None),
+            };
+            self.buf = read_block(&mut self.file, handle.offset, handle.len)?;
+            self.buf_offset = handle.offset;
+            self.pos = 0;
+            self.block += 1;
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                // an error ends the iteration
+                self.block = self.index.len();
+                self.buf.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Writes the entries of a new table, which must be added in increasing key order.
+pub(super) struct TableBuilder {
+    id: u64,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    block_size: usize,
+    bits_per_key: usize,
+    block: Vec<u8>,
+    block_last: String,
+    first_key: Option<String>,
+    index: Vec<u8>,
+    hashes: Vec<u32>,
+    offset: u64,
+    entries: u64,
+    data_bytes: u64,
+}
+
+impl TableBuilder {
+    pub(super) fn create(
+        id: u64,
+        path: PathBuf,
+        block_size: usize,
+        bits_per_key: usize,
+    ) -> Result<TableBuilder> {
+        Ok(TableBuilder {
+            id,
+            writer: BufWriter::new(File::create(&path)?),
+            path,
+            block_size,
+            bits_per_key,
+            block: Vec::new(),
+            block_last: String::new(),
+            first_key: None,
+            index: Vec::new(),
+            hashes: Vec::new(),
+            offset: 0,
+            entries: 0,
+            data_bytes: 0,
+        })
+    }
+
+    pub(super) fn add(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        if self.first_key.is_none() {
+            self.first_key = Some(key.to_owned());
+        }
+        write_str(&mut self.block, key);
+        match value {
+            Some(value) => {
+                self.block.push(1);
+                write_str(&mut self.block, value);
+            }
+            None => self.block.push(0),
+        }
+        self.block_last = key.to_owned();
+        self.hashes.push(bloom::hash(key));
+        self.entries += 1;
+        self.data_bytes += (key.len() + value.map_or(0, str::len)) as u64;
+
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Bytes written so far, including the block being built.
+    pub(super) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let len = write_block(&mut self.writer, &self.block)?;
+        write_str(&mut self.index, &self.block_last);
+        self.index.extend_from_slice(&self.offset.to_be_bytes());
+        self.index.extend_from_slice(&len.to_be_bytes());
+        self.offset += len;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Write the index, filter and footer, sync the file, and open it as a table.
+    pub(super) fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+
+        let mut index = Vec::new();
+        write_str(&mut index, self.first_key.as_deref().unwrap_or_default());
+        index.extend_from_slice(&self.index);
+        let index_offset = self.offset;
+        let bloom_offset = index_offset + write_block(&mut self.writer, &index)?;
+        let bloom = Bloom::build(&self.hashes, self.bits_per_key);
+        write_block(&mut self.writer, &bloom.encode())?;
+
+        self.writer.write_all(&index_offset.to_be_bytes())?;
+        self.writer.write_all(&bloom_offset.to_be_bytes())?;
+        self.writer.write_all(&self.entries.to_be_bytes())?;
+        self.writer.write_all(&self.data_bytes.to_be_bytes())?;
+        self.writer.write_all(MAGIC)?;
+        let file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+
+        Table::open(self.id, self.path)
+    }
+}
+
+/// Path of the table of a given id in a directory.
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+/// Write a block followed by its CRC32. Return the bytes written.
+fn write_block(writer: &mut impl Write, block: &[u8]) -> Result<u64> {
+    writer.write_all(block)?;
+    writer.write_all(&crc32fast::hash(block).to_be_bytes())?;
+    Ok(block.len() as u64 + 4)
+}
+
+/// Read a block written by `write_block`, checking its CRC32.
+fn read_block(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let corruption = KvsError::Corruption { offset };
+    if len < 4 {
+        return Err(corruption);
+    }
+    let mut buf = vec![0u8; usize::try_from(len).map_err(|_| corruption)?];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    let crc = buf.split_off(buf.len() - 4);
+    if crc32fast::hash(&buf).to_be_bytes() != crc[..] {
+        return Err(KvsError::Corruption { offset });
+    }
+    Ok(buf)
+}
+
+/// The index is the first key of the table, then the last key, offset and length of each
+/// block.
+///
+/// Offsets of a `Corruption` here and below are within the buffer.
+fn decode_index(index: &[u8]) -> Result<(String, Vec<BlockHandle>)> {
+    let mut pos = 0;
+    let first_key = read_str(index, &mut pos)?;
+    let mut handles = Vec::new();
+    while pos < index.len() {
+        let last_key = read_str(index, &mut pos)?;
+        let offset = read_u64(slice(index, pos, 8)?, 0);
+        let len = read_u64(slice(index, pos + 8, 8)?, 0);
+        pos += 16;
+        handles.push(BlockHandle {
+            last_key,
+            offset,
+            len,
+        });
+    }
+    Ok((first_key, handles))
+}
+
+/// An entry is its key, a byte telling a value from a removal, and the value if any.
+fn read_entry(buf: &[u8], pos: &mut usize) -> Result<Entry> {
+    let key = read_str(buf, pos)?;
+    let tag = *slice(buf, *pos, 1)?.first().unwrap_or(&0);
+    *pos += 1;
+    match tag {
+        0 => Ok((key, None)),
+        1 => Ok((key, Some(read_str(buf, pos)?))),
+        _ => Err(KvsError::Corruption {
+            offset: *pos as u64 - 1,
+        }),
+    }
+}
+
+/// Strings are their length as a big-endian `u32` followed by their bytes.
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(buf: &[u8], pos: &mut usize) -> Result<String> {
+    let mut len = [0u8; 4];
+    len.copy_from_slice(slice(buf, *pos, 4)?);
+    let len = u32::from_be_bytes(len) as usize;
+    let s = String::from_utf8(slice(buf, *pos + 4, len)?.to_vec()).map_err(|_| {
+        KvsError::Corruption {
+            offset: *pos as u64,
+        }
+    })?;
+    *pos += 4 + len;
+    Ok(s)
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&buf[pos..pos + 8]);
+    u64::from_be_bytes(b)
+}
+
+/// Bounds-checked slicing: damaged lengths are a `Corruption` rather than a panic.
+fn slice(buf: &[u8], pos: usize, len: usize) -> Result<&[u8]> {
+    buf.get(pos..)
+        .and_then(|rest| rest.get(..len))
+        .ok_or(KvsError::Corruption { offset: pos as u64 })
+}
@@ -0,0 +1,99 @@
+use crate::err::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// A write recorded in the log: a value, or a removal when `value` is `None`.
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: String,
+    value: Option<String>,
+}
+
+/// The write-ahead log of the memtable.
+///
+/// Each record is its length, a CRC32 of the JSON and the JSON. A record cut short, or
+/// failing its checksum as the last one of the file, ends the log: it is a write that never
+/// completed. A record failing its checksum before others is corruption.
+pub(super) struct Wal {
+    pub(super) file: File,
+    /// Bytes of the complete records.
+    pub(super) len: u64,
+}
+
+impl Wal {
+    /// Open the log at a given path, creating it if needed, and replay its records into a
+    /// memtable. The log is truncated after the last complete record; it is left as it is if
+    /// a record in the middle is corrupt.
+    pub(super) fn open(
+        path: &Path,
+        memtable: &mut BTreeMap<String, Option<String>>,
+    ) -> Result<Wal> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+
+        let mut reader = BufReader::new(&mut file);
+        let mut valid_len = 0;
+        loop {
+            match read_record(&mut reader, valid_len) {
+                Ok((record, len)) => {
+                    memtable.insert(record.key, record.value);
+                    valid_len += len;
+                }
+                Err(e) if e.is_io(io::ErrorKind::UnexpectedEof) => break,
+                Err(KvsError::Corruption { .. }) if reader.fill_buf()?.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        file.set_len(valid_len)?;
+        file.seek(SeekFrom::Start(valid_len))?;
+        Ok(Wal {
+            file,
+            len: valid_len,
+        })
+    }
+
+    /// Append a write.
+    pub(super) fn append(&mut self, key: &str, value: Option<&str>) -> Result<()> {
+        let record = Record {
+            key: key.to_owned(),
+            value: value.map(str::to_owned),
+        };
+        let b = serde_json::to_vec(&record)?;
+        let mut buf = Vec::with_capacity(b.len() + 12);
+        buf.extend_from_slice(&(b.len() as u64).to_be_bytes());
+        buf.extend_from_slice(&crc32fast::hash(&b).to_be_bytes());
+        buf.extend_from_slice(&b);
+        self.file.write_all(&buf)?;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Read the record at a given offset of the log.
+fn read_record(reader: &mut impl Read, offset: u64) -> Result<(Record, u64)> {
+    let mut s = [0u8; 8];
+    reader.read_exact(&mut s)?;
+    let len = u64::from_be_bytes(s);
+    let mut crc = [0u8; 4];
+    reader.read_exact(&mut crc)?;
+
+    // a torn record may carry a bogus length; read what is there rather than
+    // allocating for the length up front
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    if crc32fast::hash(&payload) != u32::from_be_bytes(crc) {
+        return Err(KvsError::Corruption { offset });
+    }
+    Ok((serde_json::from_slice(&payload)?, 12 + len))
+}
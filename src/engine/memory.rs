@@ -0,0 +1,141 @@
+use crate::engine::{KvsEngine, Stats};
+use crate::err::{KvsError, Result};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+struct RawMemStore {
+    map: RwLock<BTreeMap<String, String>>,
+    snapshot: Option<PathBuf>,
+}
+
+impl RawMemStore {
+    fn write_snapshot(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &*self.map.read().unwrap())?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+impl Drop for RawMemStore {
+    fn drop(&mut self) {
+        if let Some(path) = &self.snapshot {
+            if let Err(e) = self.write_snapshot(path) {
+                error!("failed to write snapshot {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// A write of a batch applied with `MemStore::write_batch`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Set the key to the value.
+    Set(String, String),
+    /// Remove the key, if it exists.
+    Remove(String),
+}
+
+/// A store that keeps all data in memory.
+///
+/// Data is lost when the last clone is dropped, unless the store is opened with a snapshot
+/// file to load from and write back to.
+#[derive(Clone)]
+pub struct MemStore(Arc<RawMemStore>);
+
+impl MemStore {
+    /// Create an empty store.
+    pub fn new() -> MemStore {
+        MemStore(Arc::new(RawMemStore {
+            map: RwLock::new(BTreeMap::new()),
+            snapshot: None,
+        }))
+    }
+
+    /// Create a store with the content of a snapshot file, if it exists.
+    ///
+    /// The content is written back to the file when the last clone of the store is dropped.
+    pub fn with_snapshot(path: impl Into<PathBuf>) -> Result<MemStore> {
+        let path = path.into();
+        let map = match File::open(&path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(MemStore(Arc::new(RawMemStore {
+            map: RwLock::new(map),
+            snapshot: Some(path),
+        })))
+    }
+
+    /// Apply the writes of a batch in order, all at once: readers see either none or all of
+    /// them.
+    pub fn write_batch(&self, batch: impl IntoIterator<Item = BatchOp>) -> Result<()> {
+        let mut map = self.0.map.write().unwrap();
+        for op in batch {
+            match op {
+                BatchOp::Set(key, value) => map.insert(key, value),
+                BatchOp::Remove(key) => map.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    /// Write the content to the snapshot file now, if the store has one.
+    pub fn flush(&self) -> Result<()> {
+        match &self.0.snapshot {
+            Some(path) => self.0.write_snapshot(path),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvsEngine for MemStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.map.write().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.0.map.read().unwrap().get(&key).cloned())
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        match self.0.map.write().unwrap().remove(&key) {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound),
+        }
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let map = self.0.map.read().unwrap();
+        Ok(map
+            .range(prefix.clone()..)
+            .take_while(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let map = self.0.map.read().unwrap();
+        Ok(Stats {
+            engine: "memory".to_owned(),
+            live_keys: map.len() as u64,
+            live_bytes: map.iter().map(|(k, v)| (k.len() + v.len()) as u64).sum(),
+            ..Default::default()
+        })
+    }
+}
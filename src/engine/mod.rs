@@ -1,5 +1,18 @@
-use crate::err::Result;
+use crate::err::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+mod btree;
+mod faulty;
 mod kvs;
+mod layer;
+mod lsm;
+mod memory;
+mod offload;
 mod sled;
 
 pub trait KvsEngine: Clone + Send + 'static {
@@ -17,7 +30,136 @@ pub trait KvsEngine: Clone + Send + 'static {
     ///
     /// Return an error if the key does not exit or value is not read successfully.
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Get all key/value pairs whose key starts with a given prefix, ordered by key.
+    ///
+    /// Return an error if the values are not read successfully.
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>>;
+
+    /// Get statistics about the data held by the engine.
+    ///
+    /// Return an error if the statistics can not be collected.
+    fn stats(&self) -> Result<Stats>;
+
+    /// Wrap the engine with a layer.
+    fn layer<L: Layer<Self>>(self, layer: L) -> L::Engine {
+        layer.layer(self)
+    }
+}
+
+/// The future returned by the operations of `AsyncKvsEngine`.
+pub type KvsFuture<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+/// The non-blocking counterpart of `KvsEngine`.
+///
+/// Operations return futures that resolve once the operation is done, without blocking the
+/// thread polling them on disk I/O.
+pub trait AsyncKvsEngine: Clone + Send + 'static {
+    /// Set the value of a string key to a string.
+    fn set(&self, key: String, value: String) -> KvsFuture<()>;
+
+    /// Get the string value of a string key. If the key does not exist, resolve to `None`.
+    fn get(&self, key: String) -> KvsFuture<Option<String>>;
+
+    /// Remove a given string key.
+    ///
+    /// Resolve to an error if the key does not exist.
+    fn remove(&self, key: String) -> KvsFuture<()>;
+
+    /// Get all key/value pairs whose key starts with a given prefix, ordered by key.
+    fn scan(&self, prefix: String) -> KvsFuture<Vec<(String, String)>>;
+
+    /// Get statistics about the data held by the engine.
+    fn stats(&self) -> KvsFuture<Stats>;
+}
+
+/// An operation of `KvsEngine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Operation {
+    Set,
+    Get,
+    Remove,
+    Scan,
+    Stats,
+}
+
+impl FromStr for Operation {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "set" => Ok(Operation::Set),
+            "get" => Ok(Operation::Get),
+            "remove" | "rm" => Ok(Operation::Remove),
+            "scan" => Ok(Operation::Scan),
+            "stats" => Ok(Operation::Stats),
+            _ => Err(KvsError::Parse),
+        }
+    }
+}
+
+/// Statistics of a storage engine.
+///
+/// Fields without an equivalent in the engine are left as zero (or `None`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stats {
+    /// Name of the engine, `kvs`, `lsm`, `btree`, `sled` or `memory`.
+    pub engine: String,
+    /// Number of keys currently holding a value.
+    pub live_keys: u64,
+    /// Number of removed keys whose removal is still recorded on disk.
+    pub tombstones: u64,
+    /// Bytes of the records that hold the current values.
+    pub live_bytes: u64,
+    /// Bytes of overwritten or removed records waiting for compaction.
+    pub dead_bytes: u64,
+    /// Number of compactions since the engine was opened.
+    pub compactions: u64,
+    /// Total time spent in compaction since the engine was opened.
+    pub compaction_duration: Duration,
+    /// When the last compaction finished.
+    pub last_compaction: Option<SystemTime>,
+    /// Dead bytes at which the next compaction is triggered.
+    pub threshold: u64,
+    /// Size of the data files on disk.
+    pub file_size: u64,
+    /// Counters of the operations served, if the engine is wrapped with a `MetricsLayer`.
+    #[serde(default)]
+    pub operations: BTreeMap<Operation, OperationMetrics>,
+}
+
+/// One `name: value` line per field, then one line per operation.
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "engine: {}", self.engine)?;
+        writeln!(f, "live_keys: {}", self.live_keys)?;
+        writeln!(f, "tombstones: {}", self.tombstones)?;
+        writeln!(f, "live_bytes: {}", self.live_bytes)?;
+        writeln!(f, "dead_bytes: {}", self.dead_bytes)?;
+        writeln!(f, "compactions: {}", self.compactions)?;
+        writeln!(f, "compaction_duration: {:?}", self.compaction_duration)?;
+        match self.last_compaction.and_then(|t| t.elapsed().ok()) {
+            Some(ago) => writeln!(f, "last_compaction: {}s ago", ago.as_secs())?,
+            None => writeln!(f, "last_compaction: never")?,
+        }
+        writeln!(f, "threshold: {}", self.threshold)?;
+        writeln!(f, "file_size: {}", self.file_size)?;
+        for (op, metrics) in &self.operations {
+            writeln!(
+                f,
+                "{:?}: count {}, errors {}, total {:?}, max {:?}",
+                op, metrics.count, metrics.errors, metrics.total, metrics.max
+            )?;
+        }
+        Ok(())
+    }
 }
 
+pub use self::btree::*;
+pub use self::faulty::*;
 pub use self::kvs::*;
+pub use self::layer::*;
+pub use self::lsm::*;
+pub use self::memory::*;
+pub use self::offload::*;
 pub use self::sled::*;
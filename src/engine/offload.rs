@@ -0,0 +1,89 @@
+use crate::engine::{AsyncKvsEngine, KvsEngine, KvsFuture, Stats};
+use crate::err::{KvsError, Result};
+use crate::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use futures::channel::oneshot;
+use std::sync::{Arc, OnceLock};
+
+/// An adapter running the operations of a blocking engine on a thread pool, turning it into
+/// an `AsyncKvsEngine`.
+pub struct Offload<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    pool: Arc<P>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> Clone for Offload<E, P> {
+    fn clone(&self) -> Self {
+        Offload {
+            engine: self.engine.clone(),
+            pool: self.pool.clone(),
+        }
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> Offload<E, P> {
+    pub fn new(engine: E, pool: P) -> Self {
+        Offload {
+            engine,
+            pool: Arc::new(pool),
+        }
+    }
+
+    /// Get the wrapped engine.
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+}
+
+impl<E, P> AsyncKvsEngine for Offload<E, P>
+where
+    E: KvsEngine,
+    P: ThreadPool + Send + Sync + 'static,
+{
+    fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        let engine = self.engine.clone();
+        offload(&*self.pool, move || engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        let engine = self.engine.clone();
+        offload(&*self.pool, move || engine.get(key))
+    }
+
+    fn remove(&self, key: String) -> KvsFuture<()> {
+        let engine = self.engine.clone();
+        offload(&*self.pool, move || engine.remove(key))
+    }
+
+    fn scan(&self, prefix: String) -> KvsFuture<Vec<(String, String)>> {
+        let engine = self.engine.clone();
+        offload(&*self.pool, move || engine.scan(prefix))
+    }
+
+    fn stats(&self) -> KvsFuture<Stats> {
+        let engine = self.engine.clone();
+        offload(&*self.pool, move || engine.stats())
+    }
+}
+
+/// Run a blocking job on a thread pool, resolving to its result.
+///
+/// If the job panics, the future resolves to `Panicked`.
+pub(crate) fn offload<P, T, F>(pool: &P, job: F) -> KvsFuture<T>
+where
+    P: ThreadPool,
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    pool.spawn(move || {
+        // the receiver may be gone if the future was dropped
+        let _ = tx.send(job());
+    });
+    Box::pin(async move { rx.await.map_err(|_| KvsError::Panicked)? })
+}
+
+/// The pool the engines offload their disk I/O to, started on first use.
+pub(crate) fn io_pool() -> &'static SharedQueueThreadPool {
+    static POOL: OnceLock<SharedQueueThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| SharedQueueThreadPool::new(0).expect("unable to start the I/O pool"))
+}
@@ -1,5 +1,6 @@
-use crate::engine::KvsEngine;
-use crate::err::{KeyNonExist, Result};
+use crate::engine::offload::{io_pool, offload};
+use crate::engine::{AsyncKvsEngine, KvsEngine, KvsFuture, Stats};
+use crate::err::{KvsError, Result};
 use sled;
 use std::path::PathBuf;
 use std::thread::sleep;
@@ -18,8 +19,8 @@ impl SledStore {
     fn try_open(path: PathBuf, times: usize) -> Result<SledStore> {
         match sled::open(&path) {
             Err(e) => {
-                if times <= 0 {
-                    Err(Box::new(e))
+                if times == 0 {
+                    Err(e.into())
                 } else {
                     sleep(Duration::from_millis(10));
                     Self::try_open(path, times - 1)
@@ -28,6 +29,11 @@ impl SledStore {
             Ok(db) => Ok(SledStore { db }),
         }
     }
+
+    /// Get a CRC32 of all keys and values, reading every one of them.
+    pub fn checksum(&self) -> Result<u32> {
+        Ok(self.db.checksum()?)
+    }
 }
 
 impl KvsEngine for SledStore {
@@ -37,15 +43,15 @@ impl KvsEngine for SledStore {
                 self.db.flush()?;
                 Ok(())
             }
-            Err(e) => Err(Box::new(e)),
+            Err(e) => Err(e.into()),
         }
     }
 
     fn get(&self, key: String) -> Result<Option<String>> {
         match self.db.get(key) {
             Ok(None) => Ok(None),
-            Ok(Some(v)) => Ok(Some(String::from_utf8(v.to_vec())?)),
-            Err(e) => Err(Box::new(e)),
+            Ok(Some(v)) => Ok(Some(utf8(&v)?)),
+            Err(e) => Err(e.into()),
         }
     }
 
@@ -55,7 +61,89 @@ impl KvsEngine for SledStore {
                 self.db.flush()?;
                 Ok(())
             }
-            _ => Err(Box::new(KeyNonExist)),
+            Ok(None) => Err(KvsError::KeyNotFound),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for kv in self.db.scan_prefix(prefix) {
+            let (k, v) = kv?;
+            pairs.push((utf8(&k)?, utf8(&v)?));
+        }
+        Ok(pairs)
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        let mut live_bytes = 0u64;
+        for kv in self.db.iter() {
+            let (k, v) = kv?;
+            live_bytes += (k.len() + v.len()) as u64;
         }
+
+        Ok(Stats {
+            engine: "sled".to_owned(),
+            live_keys: self.db.len() as u64,
+            live_bytes,
+            file_size: self.db.size_on_disk()?,
+            ..Default::default()
+        })
+    }
+}
+
+/// Writes wait for sled's background flush; reads, removals, scans and stats, which may go to
+/// disk, are run on a shared pool.
+impl AsyncKvsEngine for SledStore {
+    fn set(&self, key: String, value: String) -> KvsFuture<()> {
+        let db = self.db.clone();
+        Box::pin(async move {
+            db.insert(key.as_bytes(), value.as_bytes())?;
+            db.flush_async().await?;
+            Ok(())
+        })
     }
+
+    fn get(&self, key: String) -> KvsFuture<Option<String>> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::get(&store, key))
+    }
+
+    fn remove(&self, key: String) -> KvsFuture<()> {
+        let db = self.db.clone();
+        let removed = offload(io_pool(), move || {
+            let removed = db.remove(key)?;
+            Ok((db, removed))
+        });
+        Box::pin(async move {
+            match removed.await? {
+                (db, Some(_)) => {
+                    db.flush_async().await?;
+                    Ok(())
+                }
+                (_, None) => Err(KvsError::KeyNotFound),
+            }
+        })
+    }
+
+    fn scan(&self, prefix: String) -> KvsFuture<Vec<(String, String)>> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::scan(&store, prefix))
+    }
+
+    fn stats(&self) -> KvsFuture<Stats> {
+        let store = self.clone();
+        offload(io_pool(), move || KvsEngine::stats(&store))
+    }
+}
+
+/// Read a key or value as a string. The store only writes strings, so anything else was
+/// not written by it.
+fn utf8(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|e| {
+        KvsError::Serialization(serde::de::Error::custom(format!(
+            "key or value is not UTF-8: {}",
+            e
+        )))
+    })
 }
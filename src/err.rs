@@ -1,50 +1,107 @@
-use serde::export::Formatter;
 use std::error::Error;
 use std::fmt;
+use std::fmt::Formatter;
+use std::io;
 
-pub type Result<T> = std::result::Result<T, Box<dyn Error>>;
+pub type Result<T> = std::result::Result<T, KvsError>;
 
+/// The errors of the store, its engines, network and thread pools.
 #[derive(Debug)]
-pub struct KeyNonExist;
+pub enum KvsError {
+    /// An I/O operation failed.
+    Io(io::Error),
+    /// Data on disk failed its checksum or is malformed, at the given byte offset of its file.
+    Corruption { offset: u64 },
+    /// The key to remove does not exist.
+    KeyNotFound,
+    /// A record or message could not be encoded or decoded.
+    Serialization(serde_json::Error),
+    /// The peer sent something the protocol does not allow.
+    Protocol(String),
+    /// The data directory was written by another engine.
+    EngineMismatch,
+    /// The sled engine failed.
+    Sled(sled::Error),
+    /// An option or argument could not be parsed.
+    Parse,
+    /// The store was opened read-only.
+    ReadOnly,
+    /// The operation was failed on purpose by a `FaultyEngine`.
+    InjectedFault,
+    /// A key or value is larger than allowed.
+    LimitExceeded,
+    /// The data is encrypted with none of the given keys.
+    WrongKey,
+    /// The shard count does not match the store.
+    ShardMismatch,
+    /// A job offloaded to a thread pool panicked.
+    Panicked,
+}
 
-impl fmt::Display for KeyNonExist {
+impl fmt::Display for KvsError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "Key not found")
+        match self {
+            KvsError::Io(e) => write!(f, "I/O error: {}", e),
+            KvsError::Corruption { offset } => write!(f, "Data corrupted at offset {}", offset),
+            KvsError::KeyNotFound => write!(f, "Key not found"),
+            KvsError::Serialization(e) => write!(f, "Serialization failed: {}", e),
+            KvsError::Protocol(msg) => write!(f, "Protocol error: {}", msg),
+            KvsError::EngineMismatch => write!(f, "Server not match"),
+            KvsError::Sled(e) => write!(f, "Sled error: {}", e),
+            KvsError::Parse => write!(f, "Parse failed"),
+            KvsError::ReadOnly => write!(f, "Store is read-only"),
+            KvsError::InjectedFault => write!(f, "Injected fault"),
+            KvsError::LimitExceeded => write!(f, "Key or value too large"),
+            KvsError::WrongKey => write!(f, "Encryption key missing or wrong"),
+            KvsError::ShardMismatch => write!(f, "Shard count does not match the store"),
+            KvsError::Panicked => write!(f, "Operation panicked"),
+        }
     }
 }
 
-impl Error for KeyNonExist {
-    fn description(&self) -> &str {
-        "Key not found"
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(e) => Some(e),
+            KvsError::Serialization(e) => Some(e),
+            KvsError::Sled(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct ParseError;
+impl KvsError {
+    /// Whether this is an I/O error of the given kind.
+    pub fn is_io(&self, kind: io::ErrorKind) -> bool {
+        matches!(self, KvsError::Io(e) if e.kind() == kind)
+    }
 
-impl fmt::Display for ParseError {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "Parse failed")
+    /// Shift the offset of a `Corruption` found in a buffer by where the buffer starts in its
+    /// file.
+    pub(crate) fn offset_by(self, base: u64) -> KvsError {
+        match self {
+            KvsError::Corruption { offset } => KvsError::Corruption {
+                offset: base + offset,
+            },
+            e => e,
+        }
     }
 }
 
-impl Error for ParseError {
-    fn description(&self) -> &str {
-        "Parse failed"
+impl From<io::Error> for KvsError {
+    fn from(e: io::Error) -> Self {
+        KvsError::Io(e)
     }
 }
 
-#[derive(Debug)]
-pub struct ServerNotMatch;
-
-impl fmt::Display for ServerNotMatch {
-    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "Server not match")
+impl From<serde_json::Error> for KvsError {
+    fn from(e: serde_json::Error) -> Self {
+        KvsError::Serialization(e)
     }
 }
 
-impl Error for ServerNotMatch {
-    fn description(&self) -> &str {
-        "Server not match"
+impl From<sled::Error> for KvsError {
+    fn from(e: sled::Error) -> Self {
+        KvsError::Sled(e)
     }
 }
@@ -1,23 +1,205 @@
-use crate::err::Result;
+use crate::err::{KvsError, Result};
+use crate::network::protocol::{self, Frame, Handshake};
 use crate::network::{Request, Response};
-use std::net::{Shutdown, SocketAddr, TcpStream};
+use serde::Deserialize;
+use serde_json::Deserializer;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
 
+/// Requests `pipeline` sends ahead of their responses.
+pub const MAX_IN_FLIGHT: usize = 128;
+
+/// A connection to a `KvsServer`, sending any number of requests, one after the other or
+/// pipelined.
+///
+/// The server closes connections left idle for too long; a request on such a connection
+/// fails, and a new client has to be connected.
 pub struct KvsClient {
-    tcp_stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+    /// The handshake accepted by the server, or `None` for the legacy JSON protocol.
+    handshake: Option<Handshake>,
+    next_id: u64,
+    /// IDs of the requests sent with the legacy JSON protocol and not answered yet.
+    in_flight: VecDeque<u64>,
 }
 
 impl KvsClient {
+    /// Connect with the framed protocol.
+    ///
+    /// Fail with `Protocol` if the server shares no protocol version with the client.
     pub fn connect(addr: SocketAddr) -> Result<Self> {
+        let mut client = Self::connect_json(addr)?;
+        Handshake {
+            version: protocol::VERSION,
+            features: protocol::FEATURES,
+        }
+        .write_to(&mut client.writer)?;
+        let handshake = Handshake::read_from(&mut client.reader)?;
+        if handshake.version == 0 {
+            return Err(KvsError::Protocol("no protocol version shared".to_owned()));
+        }
+        client.handshake = Some(handshake);
+        Ok(client)
+    }
+
+    /// Connect with the legacy JSON protocol, for servers predating the framed one.
+    pub fn connect_json(addr: SocketAddr) -> Result<Self> {
+        let tcp_stream = TcpStream::connect(addr)?;
         Ok(KvsClient {
-            tcp_stream: TcpStream::connect(addr)?,
+            reader: BufReader::new(tcp_stream.try_clone()?),
+            writer: BufWriter::new(tcp_stream),
+            handshake: None,
+            next_id: 0,
+            in_flight: VecDeque::new(),
         })
     }
 
-    pub fn do_request(&mut self, req: &Request) -> Result<Response> {
-        serde_json::to_writer(&self.tcp_stream, req)?;
-        self.tcp_stream.shutdown(Shutdown::Write)?;
+    /// The version and features negotiated with the server, or `None` for the legacy JSON
+    /// protocol.
+    pub fn handshake(&self) -> Option<Handshake> {
+        self.handshake
+    }
 
-        let resp: Response = serde_json::from_reader(&self.tcp_stream)?;
+    /// Send a request and wait for its response.
+    pub fn do_request(&mut self, req: &Request) -> Result<Response> {
+        let id = self.send(req)?;
+        let (got, resp) = self.recv()?;
+        // a malformed request may be answered with an error of another ID
+        if got != id && !matches!(resp, Response::Error { .. }) {
+            return Err(KvsError::Protocol(format!(
+                "response to request {} while waiting for {}",
+                got, id
+            )));
+        }
         Ok(resp)
     }
+
+    /// Send requests without waiting for their responses, and return the responses in the
+    /// order of the requests.
+    ///
+    /// At most `MAX_IN_FLIGHT` requests are sent ahead of their responses, so that neither
+    /// side blocks writing to a peer busy writing too. Servers without pipelining are sent
+    /// one request at a time.
+    pub fn pipeline(&mut self, reqs: &[Request]) -> Result<Vec<Response>> {
+        let window = match self.handshake {
+            Some(h) if h.features & protocol::FEATURE_PIPELINING != 0 => MAX_IN_FLIGHT,
+            _ => 1,
+        };
+        let mut resps: Vec<Option<Response>> = reqs.iter().map(|_| None).collect();
+        let mut index = HashMap::new();
+        let mut sent = 0;
+        let mut received = 0;
+        while received < reqs.len() {
+            while sent < reqs.len() && sent - received < window {
+                index.insert(self.send(&reqs[sent])?, sent);
+                sent += 1;
+            }
+            let (id, resp) = self.recv()?;
+            let i = index
+                .remove(&id)
+                .ok_or_else(|| KvsError::Protocol(format!("response to unknown request {}", id)))?;
+            resps[i] = Some(resp);
+            received += 1;
+        }
+        Ok(resps.into_iter().flatten().collect())
+    }
+
+    /// Get many keys in one request. Return a `Value` or `NotFound`, or an `Error`, for each
+    /// key in order.
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Response>> {
+        self.batch(Request::MGet { keys })
+    }
+
+    /// Set many keys in one request. Return a `Success` or an `Error` for each pair in order.
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<Vec<Response>> {
+        self.batch(Request::MSet { pairs })
+    }
+
+    /// Remove many keys in one request. Return a `Success` or `NotFound`, or an `Error`, for
+    /// each key in order.
+    pub fn mdel(&mut self, keys: Vec<String>) -> Result<Vec<Response>> {
+        self.batch(Request::MDel { keys })
+    }
+
+    /// Send a request on many keys, or pipeline a request per key to a server taking none.
+    fn batch(&mut self, req: Request) -> Result<Vec<Response>> {
+        let batched = self
+            .handshake
+            .is_some_and(|h| h.features & protocol::FEATURE_BATCH != 0);
+        if !batched {
+            let reqs: Vec<Request> = match req {
+                Request::MGet { keys } => {
+                    keys.into_iter().map(|key| Request::Get { key }).collect()
+                }
+                Request::MSet { pairs } => pairs
+                    .into_iter()
+                    .map(|(key, value)| Request::Set { key, value })
+                    .collect(),
+                Request::MDel { keys } => keys
+                    .into_iter()
+                    .map(|key| Request::Remove { key })
+                    .collect(),
+                req => vec![req],
+            };
+            return self.pipeline(&reqs);
+        }
+
+        match self.do_request(&req)? {
+            Response::Batch(resps) => Ok(resps),
+            Response::Error { code, message } => Err(KvsError::Protocol(format!(
+                "batch request rejected ({}): {}",
+                code, message
+            ))),
+            resp => Err(KvsError::Protocol(format!(
+                "unexpected response to a batch request: {:?}",
+                resp
+            ))),
+        }
+    }
+
+    /// Queue a request to be sent, without waiting for its response. Return its ID.
+    ///
+    /// Requests are buffered until `recv` or `flush`.
+    pub fn send(&mut self, req: &Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        match self.handshake {
+            Some(_) => req.to_frame(id).write_to(&mut self.writer)?,
+            None => {
+                serde_json::to_writer(&mut self.writer, req)?;
+                self.in_flight.push_back(id);
+            }
+        }
+        Ok(id)
+    }
+
+    /// Send the requests queued by `send`.
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Wait for the next response, after sending the queued requests. Return the ID of its
+    /// request with it.
+    ///
+    /// The legacy JSON protocol has no IDs: its responses are matched to requests by order.
+    pub fn recv(&mut self) -> Result<(u64, Response)> {
+        self.flush()?;
+        if self.handshake.is_none() {
+            // a response ends with its closing quote or brace, so reading it consumes
+            // nothing of the next one
+            let resp = Response::deserialize(&mut Deserializer::from_reader(&mut self.reader))?;
+            let id = self
+                .in_flight
+                .pop_front()
+                .ok_or_else(|| KvsError::Protocol("response to no request".to_owned()))?;
+            return Ok((id, resp));
+        }
+
+        let frame = Frame::read_from(&mut self.reader)?
+            .ok_or_else(|| KvsError::Protocol("connection closed by server".to_owned()))?;
+        Ok((frame.id, Response::from_frame(&frame)?))
+    }
 }
@@ -0,0 +1,465 @@
+//! A REST gateway speaking HTTP/1.1 and JSON, for clients that only have HTTP.
+//!
+//! - `GET /keys/{key}` answers `{"key": ..., "value": ...}`.
+//! - `PUT /keys/{key}` sets the key to the value of a `{"value": ...}` body.
+//! - `DELETE /keys/{key}` removes the key.
+//! - `GET /keys?prefix={prefix}` answers the keys starting with the prefix, and their values,
+//!   ordered by key.
+//! - `POST /batch/get` and `POST /batch/delete` take an array of keys, and `POST /batch/put`
+//!   an array of `{"key": ..., "value": ...}`. They answer an array holding the outcome for
+//!   each key in order: its `status`, and its `value` or `error`.
+//!
+//! Keys in paths and queries are percent-encoded. Failures are answered with a status and an
+//! `{"code": ..., "message": ...}` body: 404 for a missing key, 400 for a malformed request,
+//! 413 for a key, value or body too large, 403 for a write to a read-only store and 500 for
+//! any other failure of the engine.
+
+use super::protocol::MAX_FRAME_LEN;
+use super::server::DEFAULT_IDLE_TIMEOUT;
+use super::{cut_short, read_line, ErrorCode};
+use crate::engine::KvsEngine;
+use crate::err::{KvsError, Result};
+use crate::thread_pool::ThreadPool;
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Lines of the request line and headers longer than this are rejected.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+/// Requests with more headers than this are rejected.
+const MAX_HEADERS: usize = 100;
+
+/// A key and its value.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValueBody {
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    code: ErrorCode,
+    message: String,
+}
+
+impl From<&KvsError> for ErrorBody {
+    fn from(e: &KvsError) -> Self {
+        ErrorBody {
+            code: e.into(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// The outcome for one key of a batch request.
+#[derive(Debug, Serialize)]
+struct BatchResult {
+    key: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    query: Option<String>,
+    keep_alive: bool,
+    body: Vec<u8>,
+}
+
+struct HttpResponse {
+    status: u16,
+    /// A JSON body, if any.
+    body: Option<Vec<u8>>,
+    /// The methods a path allows, for a 405.
+    allow: Option<&'static str>,
+}
+
+impl HttpResponse {
+    fn json(status: u16, body: &impl Serialize) -> Result<HttpResponse> {
+        Ok(HttpResponse {
+            status,
+            body: Some(serde_json::to_vec(body)?),
+            allow: None,
+        })
+    }
+
+    fn no_content() -> HttpResponse {
+        HttpResponse {
+            status: 204,
+            body: None,
+            allow: None,
+        }
+    }
+
+    fn error(e: &KvsError) -> HttpResponse {
+        let body = serde_json::to_vec(&ErrorBody::from(e)).expect("error body is serializable");
+        HttpResponse {
+            status: status_of(e),
+            body: Some(body),
+            allow: None,
+        }
+    }
+
+    fn method_not_allowed(allow: &'static str) -> HttpResponse {
+        HttpResponse {
+            allow: Some(allow),
+            status: 405,
+            ..HttpResponse::error(&KvsError::Protocol("method not allowed".to_owned()))
+        }
+    }
+
+    fn write_to(&self, writer: &mut impl Write, keep_alive: bool) -> Result<()> {
+        let body = self.body.as_deref().unwrap_or_default();
+        write!(
+            writer,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason(self.status)
+        )?;
+        if self.body.is_some() {
+            write!(writer, "Content-Type: application/json\r\n")?;
+        }
+        write!(writer, "Content-Length: {}\r\n", body.len())?;
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if !keep_alive {
+            write!(writer, "Connection: close\r\n")?;
+        }
+        write!(writer, "\r\n")?;
+        writer.write_all(body)?;
+        Ok(())
+    }
+}
+
+fn status_of(e: &KvsError) -> u16 {
+    match ErrorCode::from(e) {
+        ErrorCode::KeyNotFound => 404,
+        ErrorCode::Protocol => 400,
+        ErrorCode::LimitExceeded => 413,
+        ErrorCode::ReadOnly => 403,
+        _ => 500,
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Serves the REST gateway over TCP, each connection carrying any number of requests until
+/// the client closes it, asks for it to be closed or leaves it idle for longer than the idle
+/// timeout.
+pub struct HttpServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    tcp_listener: TcpListener,
+    thread_pool: P,
+    idle_timeout: Option<Duration>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> HttpServer<E, P> {
+    pub fn new(engine: E, tcp_listener: TcpListener, thread_pool: P) -> Self {
+        Self {
+            engine,
+            tcp_listener,
+            thread_pool,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+
+    /// Set how long a connection may wait for its next request before it is closed. `None`
+    /// keeps idle connections open until the client closes them.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn do_loop(&mut self) -> Result<()> {
+        for stream in self.tcp_listener.incoming() {
+            let e = self.engine.clone();
+            let stream = stream?;
+            let idle_timeout = self.idle_timeout;
+            self.thread_pool.spawn(move || {
+                if let Err(e) = Self::process_stream(&e, stream, idle_timeout) {
+                    warn!("HTTP connection failed: {}", e);
+                }
+            })
+        }
+
+        Ok(())
+    }
+
+    fn process_stream(engine: &E, stream: TcpStream, idle_timeout: Option<Duration>) -> Result<()> {
+        stream.set_read_timeout(idle_timeout)?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        loop {
+            let req = match read_request(&mut reader, &mut writer) {
+                Ok(Some(req)) => req,
+                Ok(None) => return Ok(()),
+                Err(KvsError::Io(e)) => {
+                    info!("closing HTTP connection: {}", e);
+                    return Ok(());
+                }
+                Err(e) => {
+                    // the stream can not be resynchronized after a malformed request
+                    warn!("unable to read HTTP request: {}", e);
+                    HttpResponse::error(&e).write_to(&mut writer, false)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+            };
+
+            info!("processing HTTP request {} {}", req.method, req.path);
+            let resp = Self::route(engine, &req).unwrap_or_else(|e| {
+                if !matches!(e, KvsError::KeyNotFound) {
+                    error!("HTTP request {} {} failed: {}", req.method, req.path, e);
+                }
+                HttpResponse::error(&e)
+            });
+            resp.write_to(&mut writer, req.keep_alive)?;
+            // pipelined requests already read are answered in one write
+            if !req.keep_alive || reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            if !req.keep_alive {
+                return Ok(());
+            }
+        }
+    }
+
+    fn route(engine: &E, req: &HttpRequest) -> Result<HttpResponse> {
+        if let Some(key) = req.path.strip_prefix("/keys/") {
+            let key = percent_decode(key, false)?;
+            return match req.method.as_str() {
+                "GET" => match engine.get(key.clone())? {
+                    Some(value) => HttpResponse::json(200, &Entry { key, value }),
+                    None => Err(KvsError::KeyNotFound),
+                },
+                "PUT" => {
+                    let body: ValueBody = parse_body(&req.body)?;
+                    engine.set(key, body.value)?;
+                    Ok(HttpResponse::no_content())
+                }
+                "DELETE" => {
+                    engine.remove(key)?;
+                    Ok(HttpResponse::no_content())
+                }
+                _ => Ok(HttpResponse::method_not_allowed("GET, PUT, DELETE")),
+            };
+        }
+
+        match (req.path.as_str(), req.method.as_str()) {
+            ("/keys", "GET") => {
+                let prefix = match &req.query {
+                    Some(query) => query_param(query, "prefix")?.unwrap_or_default(),
+                    None => String::new(),
+                };
+                let entries: Vec<Entry> = engine
+                    .scan(prefix)?
+                    .into_iter()
+                    .map(|(key, value)| Entry { key, value })
+                    .collect();
+                HttpResponse::json(200, &entries)
+            }
+            ("/batch/get", "POST") => {
+                let keys: Vec<String> = parse_body(&req.body)?;
+                let results: Vec<BatchResult> = keys
+                    .into_iter()
+                    .map(|key| {
+                        let result = engine.get(key.clone()).and_then(|value| {
+                            value
+                                .map(|value| (200, Some(value)))
+                                .ok_or(KvsError::KeyNotFound)
+                        });
+                        Self::batch_result(key, result)
+                    })
+                    .collect();
+                HttpResponse::json(200, &results)
+            }
+            ("/batch/put", "POST") => {
+                let entries: Vec<Entry> = parse_body(&req.body)?;
+                let results: Vec<BatchResult> = entries
+                    .into_iter()
+                    .map(|Entry { key, value }| {
+                        let result = engine.set(key.clone(), value).map(|_| (204, None));
+                        Self::batch_result(key, result)
+                    })
+                    .collect();
+                HttpResponse::json(200, &results)
+            }
+            ("/batch/delete", "POST") => {
+                let keys: Vec<String> = parse_body(&req.body)?;
+                let results: Vec<BatchResult> = keys
+                    .into_iter()
+                    .map(|key| {
+                        let result = engine.remove(key.clone()).map(|_| (204, None));
+                        Self::batch_result(key, result)
+                    })
+                    .collect();
+                HttpResponse::json(200, &results)
+            }
+            ("/keys", _) => Ok(HttpResponse::method_not_allowed("GET")),
+            ("/batch/get" | "/batch/put" | "/batch/delete", _) => {
+                Ok(HttpResponse::method_not_allowed("POST"))
+            }
+            _ => HttpResponse::json(
+                404,
+                &ErrorBody {
+                    code: ErrorCode::Protocol,
+                    message: format!("no resource at {}", req.path),
+                },
+            ),
+        }
+    }
+
+    /// The outcome for one key of a batch request, from its status and value or its error.
+    fn batch_result(key: String, result: Result<(u16, Option<String>)>) -> BatchResult {
+        match result {
+            Ok((status, value)) => BatchResult {
+                key,
+                status,
+                value,
+                error: None,
+            },
+            Err(e) => {
+                if !matches!(e, KvsError::KeyNotFound) {
+                    error!("HTTP batch request on {} failed: {}", key, e);
+                }
+                BatchResult {
+                    key,
+                    status: status_of(&e),
+                    value: None,
+                    error: Some(ErrorBody::from(&e)),
+                }
+            }
+        }
+    }
+}
+
+/// Read a request, or return `None` if the stream ends before one starts.
+///
+/// A client expecting `100 Continue` before sending its body is sent it on `writer`.
+fn read_request(reader: &mut impl BufRead, writer: &mut impl Write) -> Result<Option<HttpRequest>> {
+    let line = match read_line(reader, MAX_LINE_LEN)? {
+        Some(line) => String::from_utf8(line)
+            .map_err(|_| KvsError::Protocol("request line is not UTF-8".to_owned()))?,
+        None => return Ok(None),
+    };
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if version.starts_with("HTTP/1.") => {
+            (method, target, version)
+        }
+        _ => return Err(KvsError::Protocol(format!("bad request line {:?}", line))),
+    };
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query.to_owned())),
+        None => (target, None),
+    };
+
+    let mut keep_alive = version != "HTTP/1.0";
+    let mut content_length = 0;
+    let mut expect_continue = false;
+    for i in 0.. {
+        let line = read_line(reader, MAX_LINE_LEN)?.ok_or_else(cut_short)?;
+        if line.is_empty() {
+            break;
+        }
+        if i == MAX_HEADERS {
+            return Err(KvsError::Protocol("too many headers".to_owned()));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| KvsError::Protocol(format!("bad header {:?}", line)))?;
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                content_length = value
+                    .parse()
+                    .map_err(|_| KvsError::Protocol(format!("bad content length {:?}", value)))?;
+            }
+            "connection" => keep_alive = !value.eq_ignore_ascii_case("close"),
+            "expect" => expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            "transfer-encoding" => {
+                return Err(KvsError::Protocol(
+                    "transfer encodings are not supported".to_owned(),
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    if content_length > MAX_FRAME_LEN as usize {
+        return Err(KvsError::LimitExceeded);
+    }
+    if expect_continue && content_length > 0 {
+        write!(writer, "HTTP/1.1 100 Continue\r\n\r\n")?;
+        writer.flush()?;
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Some(HttpRequest {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query,
+        keep_alive,
+        body,
+    }))
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T> {
+    serde_json::from_slice(body).map_err(|e| KvsError::Protocol(format!("bad body: {}", e)))
+}
+
+/// The decoded value of a parameter of a query string.
+fn query_param(query: &str, name: &str) -> Result<Option<String>> {
+    for param in query.split('&') {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        if percent_decode(key, true)? == name {
+            return percent_decode(value, true).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+/// Decode `%XX` escapes, and `+` as a space in a query string.
+fn percent_decode(s: &str, query: bool) -> Result<String> {
+    let bad = || KvsError::Protocol(format!("bad percent-encoding {:?}", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'%' => {
+                let hex = rest.get(..2).ok_or_else(bad)?;
+                let hex = std::str::from_utf8(hex).map_err(|_| bad())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| bad())?);
+                rest = &rest[2..];
+            }
+            b'+' if query => bytes.push(b' '),
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| bad())
+}
@@ -0,0 +1,515 @@
+//! The text protocol of memcached, so that memcached clients can use the store.
+//!
+//! Supported commands are `get`, `gets`, `set`, `add`, `replace`, `cas`, `delete`, `incr`,
+//! `decr`, `version` and `quit`; any other is answered with `ERROR`.
+//!
+//! Items are stored in the engine as their flags, expiry time and CAS token, followed by
+//! their data, which has to be UTF-8. A value set through another protocol is seen as an item
+//! with no flags, no expiry and a CAS token derived from the value. Expired items are removed
+//! when next read. The checks of `add`, `replace`, `cas`, `incr` and `decr` are atomic with
+//! respect to the other memcached commands, not to writes made through other protocols.
+//!
+//! The other protocols should read the engine through an `ItemLayer`, which strips the
+//! metadata from items and hides the expired ones, as `kvs-server` does. Without it they see
+//! the metadata at the start of the values.
+
+use super::read_line;
+use super::server::DEFAULT_IDLE_TIMEOUT;
+use crate::engine::{KvsEngine, Layer, Stats};
+use crate::err::{KvsError, Result};
+use crate::thread_pool::ThreadPool;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Command lines longer than this are rejected.
+const MAX_LINE_LEN: u64 = 2048;
+/// Keys longer than this are rejected.
+const MAX_KEY_LEN: usize = 250;
+/// Data larger than this is rejected, like the default item size limit of memcached.
+pub const MAX_ITEM_LEN: usize = 1024 * 1024;
+/// Expiry times up to this many seconds are relative to now, longer ones are Unix times.
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+/// Number of locks the keys are spread over.
+const LOCK_STRIPES: usize = 64;
+/// The first character of an item stored in the engine.
+const ITEM_MARK: char = '\0';
+
+/// An item: data and the metadata memcached keeps with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Item {
+    flags: u32,
+    /// The Unix time at which the item expires, or 0 if it never does.
+    expires: u64,
+    cas: u64,
+    data: String,
+}
+
+impl Item {
+    fn encode(&self) -> String {
+        format!(
+            "{}{} {} {}\n{}",
+            ITEM_MARK, self.flags, self.expires, self.cas, self.data
+        )
+    }
+
+    fn decode(value: String) -> Item {
+        Self::decode_item(&value).unwrap_or_else(|| Item {
+            flags: 0,
+            expires: 0,
+            cas: crc32fast::hash(value.as_bytes()).into(),
+            data: value,
+        })
+    }
+
+    fn decode_item(value: &str) -> Option<Item> {
+        let (header, data) = value.strip_prefix(ITEM_MARK)?.split_once('\n')?;
+        let mut fields = header.split(' ');
+        let item = Item {
+            flags: fields.next()?.parse().ok()?,
+            expires: fields.next()?.parse().ok()?,
+            cas: fields.next()?.parse().ok()?,
+            data: data.to_owned(),
+        };
+        fields.next().is_none().then_some(item)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires != 0 && self.expires <= unix_time()
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |t| t.as_secs())
+}
+
+/// The expiry time of an item from the `exptime` of a command: 0 for never, up to 30 days
+/// relative to now, a Unix time beyond, and already expired if negative.
+fn expires_at(exptime: i64) -> u64 {
+    match exptime {
+        0 => 0,
+        t if t < 0 => 1,
+        t if t <= MAX_RELATIVE_EXPTIME => unix_time() + t as u64,
+        t => t as u64,
+    }
+}
+
+/// How a storage command treats the item it replaces.
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Set,
+    Add,
+    Replace,
+    Cas(u64),
+}
+
+/// The items of an engine, shared by the connections of a server.
+#[derive(Clone)]
+struct Cache<E: KvsEngine> {
+    engine: E,
+    locks: Arc<Vec<Mutex<()>>>,
+    next_cas: Arc<AtomicU64>,
+}
+
+impl<E: KvsEngine> Cache<E> {
+    fn new(engine: E) -> Self {
+        // tokens keep increasing across restarts, so a token read before one is not reused
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |t| t.as_nanos() as u64);
+        Cache {
+            engine,
+            locks: Arc::new((0..LOCK_STRIPES).map(|_| Mutex::new(())).collect()),
+            next_cas: Arc::new(AtomicU64::new(nanos)),
+        }
+    }
+
+    fn lock(&self, key: &str) -> MutexGuard<'_, ()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let lock = &self.locks[hasher.finish() as usize % LOCK_STRIPES];
+        lock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Read an item, taking no lock. An expired item is returned as such.
+    fn read(&self, key: &str) -> Result<Option<Item>> {
+        Ok(self.engine.get(key.to_owned())?.map(Item::decode))
+    }
+
+    /// Read an item, removing it if it has expired.
+    fn get(&self, key: &str) -> Result<Option<Item>> {
+        match self.read(key)? {
+            Some(item) if item.is_expired() => {
+                let _guard = self.lock(key);
+                // the item may have been replaced since
+                if let Some(item) = self.read(key)? {
+                    if !item.is_expired() {
+                        return Ok(Some(item));
+                    }
+                    self.remove(key)?;
+                }
+                Ok(None)
+            }
+            item => Ok(item),
+        }
+    }
+
+    fn remove(&self, key: &str) -> Result<bool> {
+        match self.engine.remove(key.to_owned()) {
+            Err(KvsError::KeyNotFound) => Ok(false),
+            result => result.map(|_| true),
+        }
+    }
+
+    fn store(&self, mode: Mode, key: &str, flags: u32, exptime: i64, data: String) -> Result<&str> {
+        let _guard = self.lock(key);
+        let old = self.read(key)?.filter(|item| !item.is_expired());
+        match (mode, old) {
+            (Mode::Add, Some(_)) | (Mode::Replace, None) => return Ok("NOT_STORED"),
+            (Mode::Cas(_), None) => return Ok("NOT_FOUND"),
+            (Mode::Cas(cas), Some(item)) if item.cas != cas => return Ok("EXISTS"),
+            _ => {}
+        }
+        let item = Item {
+            flags,
+            expires: expires_at(exptime),
+            cas: self.next_cas.fetch_add(1, Ordering::Relaxed),
+            data,
+        };
+        self.engine.set(key.to_owned(), item.encode())?;
+        Ok("STORED")
+    }
+
+    fn delete(&self, key: &str) -> Result<&str> {
+        let _guard = self.lock(key);
+        let found = match self.read(key)? {
+            Some(item) => self.remove(key)? && !item.is_expired(),
+            None => false,
+        };
+        Ok(if found { "DELETED" } else { "NOT_FOUND" })
+    }
+
+    /// Add to or subtract from the number held by an item. A sum wraps around at 2^64 and a
+    /// difference stops at 0.
+    fn incr(&self, key: &str, delta: u64, incr: bool) -> Result<String> {
+        let _guard = self.lock(key);
+        let mut item = match self.read(key)?.filter(|item| !item.is_expired()) {
+            Some(item) => item,
+            None => return Ok("NOT_FOUND".to_owned()),
+        };
+        let value: u64 = match item.data.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                return Ok(
+                    "CLIENT_ERROR cannot increment or decrement non-numeric value".to_owned(),
+                )
+            }
+        };
+        let value = if incr {
+            value.wrapping_add(delta)
+        } else {
+            value.saturating_sub(delta)
+        };
+        item.data = value.to_string();
+        item.cas = self.next_cas.fetch_add(1, Ordering::Relaxed);
+        self.engine.set(key.to_owned(), item.encode())?;
+        Ok(item.data)
+    }
+}
+
+/// A layer showing the items stored by memcached clients to the other protocols as their
+/// data alone, and expired items as missing. Writes go through unchanged, so a value set
+/// through it replaces an item with one without flags or expiry.
+#[derive(Debug, Clone, Default)]
+pub struct ItemLayer;
+
+impl ItemLayer {
+    pub fn new() -> Self {
+        ItemLayer
+    }
+}
+
+impl<E: KvsEngine> Layer<E> for ItemLayer {
+    type Engine = ItemEngine<E>;
+
+    fn layer(&self, inner: E) -> ItemEngine<E> {
+        ItemEngine { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct ItemEngine<E: KvsEngine> {
+    inner: E,
+}
+
+impl<E: KvsEngine> KvsEngine for ItemEngine<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let item = self.inner.get(key)?.map(Item::decode);
+        Ok(item.filter(|item| !item.is_expired()).map(|item| item.data))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+
+    fn scan(&self, prefix: String) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .inner
+            .scan(prefix)?
+            .into_iter()
+            .map(|(key, value)| (key, Item::decode(value)))
+            .filter(|(_, item)| !item.is_expired())
+            .map(|(key, item)| (key, item.data))
+            .collect())
+    }
+
+    fn stats(&self) -> Result<Stats> {
+        self.inner.stats()
+    }
+}
+
+/// What to answer a command with.
+enum Reply {
+    Text(Vec<u8>),
+    /// The command asked for no reply.
+    None,
+    Quit,
+}
+
+impl Reply {
+    fn line(line: impl AsRef<str>) -> Reply {
+        Reply::Text(format!("{}\r\n", line.as_ref()).into_bytes())
+    }
+}
+
+/// Serves the commands of memcached clients over TCP, each connection carrying any number of
+/// commands until the client closes it or leaves it idle for longer than the idle timeout.
+pub struct MemcacheServer<E: KvsEngine, P: ThreadPool> {
+    cache: Cache<E>,
+    tcp_listener: TcpListener,
+    thread_pool: P,
+    idle_timeout: Option<Duration>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> MemcacheServer<E, P> {
+    pub fn new(engine: E, tcp_listener: TcpListener, thread_pool: P) -> Self {
+        Self {
+            cache: Cache::new(engine),
+            tcp_listener,
+            thread_pool,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+
+    /// Set how long a connection may wait for its next command before it is closed. `None`
+    /// keeps idle connections open until the client closes them.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn do_loop(&mut self) -> Result<()> {
+        for stream in self.tcp_listener.incoming() {
+            let cache = self.cache.clone();
+            let stream = stream?;
+            let idle_timeout = self.idle_timeout;
+            self.thread_pool.spawn(move || {
+                if let Err(e) = Self::process_stream(&cache, stream, idle_timeout) {
+                    warn!("memcache connection failed: {}", e);
+                }
+            })
+        }
+
+        Ok(())
+    }
+
+    fn process_stream(
+        cache: &Cache<E>,
+        stream: TcpStream,
+        idle_timeout: Option<Duration>,
+    ) -> Result<()> {
+        stream.set_read_timeout(idle_timeout)?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        loop {
+            let reply = match read_line(&mut reader, MAX_LINE_LEN).and_then(|line| {
+                line.map(|line| Self::process_command(cache, line, &mut reader))
+                    .transpose()
+            }) {
+                Ok(Some(reply)) => reply,
+                Ok(None) => return Ok(()),
+                Err(KvsError::Io(e)) => {
+                    info!("closing memcache connection: {}", e);
+                    return Ok(());
+                }
+                Err(e) => {
+                    // the stream can not be resynchronized after a malformed command
+                    warn!("unable to read memcache command: {}", e);
+                    write!(writer, "CLIENT_ERROR {}\r\n", e)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+            };
+
+            match reply {
+                Reply::Text(text) => writer.write_all(&text)?,
+                Reply::None => {}
+                Reply::Quit => {
+                    writer.flush()?;
+                    return Ok(());
+                }
+            }
+            // pipelined commands already read are answered in one write
+            if reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+        }
+    }
+
+    /// Answer a command line, reading the data block following a storage command.
+    ///
+    /// Fail only if the connection can not go on.
+    fn process_command(
+        cache: &Cache<E>,
+        line: Vec<u8>,
+        reader: &mut impl BufRead,
+    ) -> Result<Reply> {
+        let line = match String::from_utf8(line) {
+            Ok(line) => line,
+            Err(_) => return Ok(Reply::line("CLIENT_ERROR command is not UTF-8")),
+        };
+        let mut args: Vec<&str> = line.split(' ').filter(|arg| !arg.is_empty()).collect();
+        let noreply = args.len() > 2 && args.last() == Some(&"noreply");
+        if noreply {
+            args.pop();
+        }
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(Reply::line("ERROR")),
+        };
+        if let Some(key) = args.iter().find(|key| key.len() > MAX_KEY_LEN) {
+            return Ok(Reply::line(format!("CLIENT_ERROR key {} too long", key)));
+        }
+        debug!("processing memcache command {}", line);
+
+        let reply = match (name, args) {
+            ("get" | "gets", keys) if !keys.is_empty() => {
+                Self::retrieve(cache, keys, name == "gets")
+            }
+            ("set" | "add" | "replace" | "cas", _) => {
+                return Self::process_storage(cache, name, args, noreply, reader)
+            }
+            ("delete", [key]) => cache.delete(key).map(Reply::line),
+            ("incr" | "decr", [key, delta]) => match delta.parse() {
+                Ok(delta) => cache.incr(key, delta, name == "incr").map(Reply::line),
+                Err(_) => Ok(Reply::line("CLIENT_ERROR invalid numeric delta argument")),
+            },
+            ("version", []) => Ok(Reply::line(format!(
+                "VERSION {}",
+                env!("CARGO_PKG_VERSION")
+            ))),
+            ("quit", []) => return Ok(Reply::Quit),
+            ("get" | "gets" | "delete" | "incr" | "decr" | "version" | "quit", _) => {
+                Ok(Reply::line("CLIENT_ERROR bad command line format"))
+            }
+            _ => Ok(Reply::line("ERROR")),
+        };
+        let reply = reply.unwrap_or_else(|e| {
+            error!("memcache command {} failed: {}", line, e);
+            Reply::line(format!("SERVER_ERROR {}", e))
+        });
+        Ok(if noreply { Reply::None } else { reply })
+    }
+
+    fn retrieve(cache: &Cache<E>, keys: &[&str], with_cas: bool) -> Result<Reply> {
+        let mut text = Vec::new();
+        for key in keys {
+            if let Some(item) = cache.get(key)? {
+                write!(text, "VALUE {} {} {}", key, item.flags, item.data.len())?;
+                if with_cas {
+                    write!(text, " {}", item.cas)?;
+                }
+                write!(text, "\r\n{}\r\n", item.data)?;
+            }
+        }
+        text.extend_from_slice(b"END\r\n");
+        Ok(Reply::Text(text))
+    }
+
+    /// Answer `<command> <key> <flags> <exptime> <bytes> [<cas unique>] [noreply]`, followed
+    /// by a data block of `<bytes>` bytes and a line ending.
+    fn process_storage(
+        cache: &Cache<E>,
+        name: &str,
+        args: &[&str],
+        noreply: bool,
+        reader: &mut impl BufRead,
+    ) -> Result<Reply> {
+        let parsed = match (name, args) {
+            ("cas", [key, flags, exptime, bytes, cas]) => {
+                Self::parse_storage(key, flags, exptime, bytes).zip(cas.parse().ok().map(Mode::Cas))
+            }
+            ("set" | "add" | "replace", [key, flags, exptime, bytes]) => {
+                let mode = match name {
+                    "set" => Mode::Set,
+                    "add" => Mode::Add,
+                    _ => Mode::Replace,
+                };
+                Self::parse_storage(key, flags, exptime, bytes).zip(Some(mode))
+            }
+            _ => None,
+        };
+        let ((key, flags, exptime, bytes), mode) = match parsed {
+            Some(parsed) => parsed,
+            None => return Ok(Reply::line("CLIENT_ERROR bad command line format")),
+        };
+
+        if bytes > MAX_ITEM_LEN {
+            io::copy(&mut Read::take(reader, bytes as u64 + 2), &mut io::sink())?;
+            return Ok(Reply::line("SERVER_ERROR object too large for cache"));
+        }
+        let mut data = vec![0u8; bytes + 2];
+        reader.read_exact(&mut data)?;
+        if !data.ends_with(b"\r\n") {
+            return Err(KvsError::Protocol("bad data chunk".to_owned()));
+        }
+        data.truncate(bytes);
+        let data = match String::from_utf8(data) {
+            Ok(data) => data,
+            Err(_) => return Ok(Reply::line("CLIENT_ERROR data is not UTF-8")),
+        };
+
+        let reply = match cache.store(mode, key, flags, exptime, data) {
+            Ok(reply) => Reply::line(reply),
+            Err(e) => {
+                error!("memcache command {} {} failed: {}", name, key, e);
+                Reply::line(format!("SERVER_ERROR {}", e))
+            }
+        };
+        Ok(if noreply { Reply::None } else { reply })
+    }
+
+    fn parse_storage<'a>(
+        key: &'a str,
+        flags: &str,
+        exptime: &str,
+        bytes: &str,
+    ) -> Option<(&'a str, u32, i64, usize)> {
+        Some((
+            key,
+            flags.parse().ok()?,
+            exptime.parse().ok()?,
+            bytes.parse().ok()?,
+        ))
+    }
+}
@@ -1,10 +1,35 @@
+use crate::engine::Stats;
+use crate::err::{KvsError, Result};
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, BufRead, Read};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: String,
+    },
+    Set {
+        key: String,
+        value: String,
+    },
+    Remove {
+        key: String,
+    },
+    Stats,
+    /// Get many keys, answered with a `Batch` of `Value` or `NotFound` in the order of the keys.
+    MGet {
+        keys: Vec<String>,
+    },
+    /// Set many keys, answered with a `Batch` of `Success` in the order of the pairs.
+    MSet {
+        pairs: Vec<(String, String)>,
+    },
+    /// Remove many keys, answered with a `Batch` of `Success` or `NotFound` in the order of
+    /// the keys.
+    MDel {
+        keys: Vec<String>,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -12,7 +37,115 @@ pub enum Response {
     Value(String),
     Success,
     NotFound,
+    Stats(Stats),
+    /// The request failed; nothing can be assumed about whether a write took effect.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    /// The answers to a request on many keys, one per key. Each key may fail on its own with
+    /// an `Error`; the keys are not written atomically.
+    Batch(Vec<Response>),
+}
+
+/// Why a request failed. The codes are part of the protocol, by name in JSON and by number
+/// in frames: they are only ever added to, never renamed, renumbered or removed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// The key does not exist.
+    KeyNotFound = 1,
+    /// The server failed to read or write its data.
+    Io = 2,
+    /// The data of the server is damaged.
+    Corruption = 3,
+    /// The request could not be read.
+    Protocol = 4,
+    /// The server rejects writes.
+    ReadOnly = 5,
+    /// The key or value is larger than the server allows.
+    LimitExceeded = 6,
+    /// Any other failure of the server.
+    Internal = 7,
+}
+
+impl ErrorCode {
+    pub fn to_u16(self) -> u16 {
+        self as u16
+    }
+
+    /// The code of a number, or `Internal` for a code this version does not know.
+    pub fn from_u16(code: u16) -> ErrorCode {
+        match code {
+            1 => ErrorCode::KeyNotFound,
+            2 => ErrorCode::Io,
+            3 => ErrorCode::Corruption,
+            4 => ErrorCode::Protocol,
+            5 => ErrorCode::ReadOnly,
+            6 => ErrorCode::LimitExceeded,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl From<&KvsError> for ErrorCode {
+    fn from(e: &KvsError) -> Self {
+        match e {
+            KvsError::KeyNotFound => ErrorCode::KeyNotFound,
+            KvsError::Io(_) => ErrorCode::Io,
+            KvsError::Corruption { .. } => ErrorCode::Corruption,
+            KvsError::Protocol(_) => ErrorCode::Protocol,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::LimitExceeded => ErrorCode::LimitExceeded,
+            _ => ErrorCode::Internal,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl From<&KvsError> for Response {
+    fn from(e: &KvsError) -> Self {
+        Response::Error {
+            code: e.into(),
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Read a line without its line ending, or return `None` if the stream ends before it starts.
+///
+/// Fail with `Protocol` if the line is longer than `max_len`.
+pub(crate) fn read_line(reader: &mut impl BufRead, max_len: u64) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    reader.by_ref().take(max_len).read_until(b'\n', &mut line)?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if line.len() as u64 + 1 >= max_len {
+            return Err(KvsError::Protocol("line too long".to_owned()));
+        }
+        return Err(cut_short());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+/// The error of a stream ending in the middle of a message.
+pub(crate) fn cut_short() -> KvsError {
+    io::Error::from(io::ErrorKind::UnexpectedEof).into()
 }
 
 pub mod client;
+pub mod http;
+pub mod memcache;
+pub mod protocol;
+pub mod reactor;
+pub mod resp;
 pub mod server;
@@ -0,0 +1,367 @@
+//! The framed binary protocol.
+//!
+//! A connection starts with a handshake: the client sends `MAGIC`, the highest protocol
+//! version it speaks as a `u16` and the features it wants as a `u32` bitmask; the server
+//! answers with `MAGIC`, the version to use and the features both sides support. A version of
+//! 0 means no version is shared, and the server closes the connection.
+//!
+//! Then every message is a frame: its length as a `u32`, not counting the length itself, the
+//! request ID as a `u64`, an opcode and the payload. A response carries the ID of its
+//! request. Strings in payloads are their length as a `u32` followed by their bytes, and all
+//! integers are big-endian.
+
+use super::{ErrorCode, Request, Response};
+use crate::err::{KvsError, Result};
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+
+/// The first bytes a client sends. A legacy JSON client starts with `{`, `"` or whitespace
+/// instead.
+pub const MAGIC: &[u8; 4] = b"KVSB";
+/// The highest protocol version spoken.
+pub const VERSION: u16 = 1;
+/// The lowest protocol version spoken.
+pub const MIN_VERSION: u16 = 1;
+/// The server answers requests sent without waiting for the responses to earlier ones.
+pub const FEATURE_PIPELINING: u32 = 1;
+/// The server takes requests on many keys at once.
+pub const FEATURE_BATCH: u32 = 2;
+/// Features understood, as a bitmask.
+pub const FEATURES: u32 = FEATURE_PIPELINING | FEATURE_BATCH;
+/// Frames longer than this are rejected rather than allocated for.
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// The request ID and opcode, following the length.
+const FRAME_HEADER_LEN: u32 = 9;
+
+mod opcode {
+    pub const GET: u8 = 0x01;
+    pub const SET: u8 = 0x02;
+    pub const REMOVE: u8 = 0x03;
+    pub const STATS: u8 = 0x04;
+    pub const MGET: u8 = 0x05;
+    pub const MSET: u8 = 0x06;
+    pub const MDEL: u8 = 0x07;
+
+    pub const VALUE: u8 = 0x81;
+    pub const SUCCESS: u8 = 0x82;
+    pub const NOT_FOUND: u8 = 0x83;
+    pub const STATS_RESULT: u8 = 0x84;
+    pub const ERROR: u8 = 0x85;
+    pub const BATCH: u8 = 0x86;
+}
+
+/// The version and features a connection uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u16,
+    pub features: u32,
+}
+
+impl Handshake {
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.features.to_be_bytes());
+        writer.write_all(&buf)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Bytes of a handshake.
+    pub const LEN: usize = 10;
+
+    /// Read a handshake, failing with `Protocol` if it does not start with `MAGIC`.
+    pub fn read_from(reader: &mut impl Read) -> Result<Handshake> {
+        let mut buf = [0u8; Handshake::LEN];
+        reader.read_exact(&mut buf)?;
+        if &buf[..4] != MAGIC {
+            return Err(KvsError::Protocol("bad handshake".to_owned()));
+        }
+        Ok(Handshake {
+            version: u16::from_be_bytes([buf[4], buf[5]]),
+            features: u32::from_be_bytes(buf[6..].try_into().unwrap()),
+        })
+    }
+
+    /// The answer of the server to the handshake of a client.
+    pub fn accept(&self) -> Handshake {
+        let version = self.version.min(VERSION);
+        Handshake {
+            version: if version < MIN_VERSION { 0 } else { version },
+            features: self.features & FEATURES,
+        }
+    }
+}
+
+/// A message of the framed protocol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub id: u64,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        let len = FRAME_HEADER_LEN as usize + self.payload.len();
+        let mut buf = Vec::with_capacity(4 + len);
+        buf.extend_from_slice(&(len as u32).to_be_bytes());
+        buf.extend_from_slice(&self.id.to_be_bytes());
+        buf.push(self.opcode);
+        buf.extend_from_slice(&self.payload);
+        writer.write_all(&buf)?;
+        Ok(())
+    }
+
+    /// Read a frame, or return `None` if the stream ends before one starts.
+    pub fn read_from(reader: &mut impl Read) -> Result<Option<Frame>> {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = check_len(u32::from_be_bytes(len))?;
+        let mut buf = vec![0u8; len];
+        reader.read_exact(&mut buf)?;
+        Ok(Some(Frame::from_body(buf)))
+    }
+
+    /// Decode the frame at the start of a buffer, returning it with the number of bytes it
+    /// takes, or `None` if the buffer holds only part of it.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Frame, usize)>> {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = check_len(u32::from_be_bytes(buf[..4].try_into().unwrap()))?;
+        match buf.get(4..4 + len) {
+            Some(body) => Ok(Some((Frame::from_body(body.to_vec()), 4 + len))),
+            None => Ok(None),
+        }
+    }
+
+    /// The frame of the bytes following its length.
+    fn from_body(mut buf: Vec<u8>) -> Frame {
+        Frame {
+            id: u64::from_be_bytes(buf[..8].try_into().unwrap()),
+            opcode: buf[8],
+            payload: buf.split_off(FRAME_HEADER_LEN as usize),
+        }
+    }
+}
+
+fn check_len(len: u32) -> Result<usize> {
+    if !(FRAME_HEADER_LEN..=MAX_FRAME_LEN).contains(&len) {
+        return Err(KvsError::Protocol(format!("bad frame length {}", len)));
+    }
+    Ok(len as usize)
+}
+
+impl Request {
+    pub fn to_frame(&self, id: u64) -> Frame {
+        let mut payload = Vec::new();
+        let opcode = match self {
+            Request::Get { key } => {
+                write_str(&mut payload, key);
+                opcode::GET
+            }
+            Request::Set { key, value } => {
+                write_str(&mut payload, key);
+                write_str(&mut payload, value);
+                opcode::SET
+            }
+            Request::Remove { key } => {
+                write_str(&mut payload, key);
+                opcode::REMOVE
+            }
+            Request::Stats => opcode::STATS,
+            Request::MGet { keys } => {
+                write_strs(&mut payload, keys);
+                opcode::MGET
+            }
+            Request::MSet { pairs } => {
+                payload.extend_from_slice(&(pairs.len() as u32).to_be_bytes());
+                for (key, value) in pairs {
+                    write_str(&mut payload, key);
+                    write_str(&mut payload, value);
+                }
+                opcode::MSET
+            }
+            Request::MDel { keys } => {
+                write_strs(&mut payload, keys);
+                opcode::MDEL
+            }
+        };
+        Frame {
+            id,
+            opcode,
+            payload,
+        }
+    }
+
+    /// Decode a request, failing with `Protocol` if the frame holds none.
+    pub fn from_frame(frame: &Frame) -> Result<Request> {
+        let mut payload = Payload(&frame.payload);
+        let req = match frame.opcode {
+            opcode::GET => Request::Get {
+                key: payload.string()?,
+            },
+            opcode::SET => Request::Set {
+                key: payload.string()?,
+                value: payload.string()?,
+            },
+            opcode::REMOVE => Request::Remove {
+                key: payload.string()?,
+            },
+            opcode::STATS => Request::Stats,
+            opcode::MGET => Request::MGet {
+                keys: payload.strings()?,
+            },
+            opcode::MSET => {
+                let count = payload.count()?;
+                let mut pairs = Vec::new();
+                for _ in 0..count {
+                    pairs.push((payload.string()?, payload.string()?));
+                }
+                Request::MSet { pairs }
+            }
+            opcode::MDEL => Request::MDel {
+                keys: payload.strings()?,
+            },
+            op => return Err(KvsError::Protocol(format!("unknown opcode {:#04x}", op))),
+        };
+        payload.end()?;
+        Ok(req)
+    }
+}
+
+impl Response {
+    pub fn to_frame(&self, id: u64) -> Result<Frame> {
+        let (opcode, payload) = self.encode()?;
+        Ok(Frame {
+            id,
+            opcode,
+            payload,
+        })
+    }
+
+    /// Decode a response, failing with `Protocol` if the frame holds none.
+    pub fn from_frame(frame: &Frame) -> Result<Response> {
+        Response::decode(frame.opcode, &frame.payload)
+    }
+
+    fn encode(&self) -> Result<(u8, Vec<u8>)> {
+        let mut payload = Vec::new();
+        let opcode = match self {
+            Response::Value(value) => {
+                write_str(&mut payload, value);
+                opcode::VALUE
+            }
+            Response::Success => opcode::SUCCESS,
+            Response::NotFound => opcode::NOT_FOUND,
+            Response::Stats(stats) => {
+                payload = serde_json::to_vec(stats)?;
+                opcode::STATS_RESULT
+            }
+            Response::Error { code, message } => {
+                payload.extend_from_slice(&code.to_u16().to_be_bytes());
+                write_str(&mut payload, message);
+                opcode::ERROR
+            }
+            // each response is its opcode, then its payload with the length first
+            Response::Batch(resps) => {
+                payload.extend_from_slice(&(resps.len() as u32).to_be_bytes());
+                for resp in resps {
+                    let (opcode, p) = resp.encode()?;
+                    payload.push(opcode);
+                    payload.extend_from_slice(&(p.len() as u32).to_be_bytes());
+                    payload.extend_from_slice(&p);
+                }
+                opcode::BATCH
+            }
+        };
+        Ok((opcode, payload))
+    }
+
+    fn decode(opcode: u8, buf: &[u8]) -> Result<Response> {
+        let mut payload = Payload(buf);
+        let resp = match opcode {
+            opcode::VALUE => Response::Value(payload.string()?),
+            opcode::SUCCESS => Response::Success,
+            opcode::NOT_FOUND => Response::NotFound,
+            opcode::STATS_RESULT => return Ok(Response::Stats(serde_json::from_slice(buf)?)),
+            opcode::ERROR => Response::Error {
+                code: ErrorCode::from_u16(u16::from_be_bytes(payload.take(2)?.try_into().unwrap())),
+                message: payload.string()?,
+            },
+            opcode::BATCH => {
+                let count = payload.count()?;
+                let mut resps = Vec::new();
+                for _ in 0..count {
+                    let opcode = payload.take(1)?[0];
+                    // batches are never nested, and decoding them recursively could overflow
+                    // the stack
+                    if opcode == opcode::BATCH {
+                        return Err(KvsError::Protocol("nested batch response".to_owned()));
+                    }
+                    let len = payload.count()?;
+                    resps.push(Response::decode(opcode, payload.take(len)?)?);
+                }
+                Response::Batch(resps)
+            }
+            op => return Err(KvsError::Protocol(format!("unknown opcode {:#04x}", op))),
+        };
+        payload.end()?;
+        Ok(resp)
+    }
+}
+
+fn write_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// A list of strings is its length as a `u32` followed by the strings.
+fn write_strs(buf: &mut Vec<u8>, strs: &[String]) {
+    buf.extend_from_slice(&(strs.len() as u32).to_be_bytes());
+    for s in strs {
+        write_str(buf, s);
+    }
+}
+
+/// The rest of a payload being decoded.
+struct Payload<'a>(&'a [u8]);
+
+impl<'a> Payload<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(KvsError::Protocol("payload cut short".to_owned()));
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn count(&mut self) -> Result<usize> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()) as usize)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.count()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| KvsError::Protocol("string is not UTF-8".to_owned()))
+    }
+
+    fn strings(&mut self) -> Result<Vec<String>> {
+        let count = self.count()?;
+        (0..count).map(|_| self.string()).collect()
+    }
+
+    fn end(&self) -> Result<()> {
+        if !self.0.is_empty() {
+            return Err(KvsError::Protocol("trailing bytes in payload".to_owned()));
+        }
+        Ok(())
+    }
+}
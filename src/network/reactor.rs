@@ -0,0 +1,426 @@
+//! An event-driven server for the native protocols.
+//!
+//! One thread waits for all the connections to be ready, reads their requests and writes
+//! their responses without blocking, and hands only the engine calls to the thread pool. A
+//! slow or idle client then ties up no thread.
+
+use super::protocol::{self, Frame, Handshake};
+use super::server::{process_request, DEFAULT_IDLE_TIMEOUT};
+use super::{Request, Response};
+use crate::engine::KvsEngine;
+use crate::err::{KvsError, Result};
+use crate::thread_pool::ThreadPool;
+use mio::net::{TcpListener as MioListener, TcpStream as MioStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use serde_json::Deserializer;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+/// Requests of a connection handed to the pool at once; no more are read from it until
+/// some are answered.
+const MAX_PENDING: u64 = 128;
+/// Bytes read from a connection and not yet decoded, beyond which no more are read.
+const MAX_INPUT: usize = protocol::MAX_FRAME_LEN as usize + 4;
+/// How often idle connections are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The protocol of a connection, told by its first byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Unknown,
+    Handshake,
+    Framed,
+    Json,
+}
+
+/// The responses computed by the pool: the connection, sequence number and bytes of each.
+type Completions = Mutex<Vec<(Token, u64, Vec<u8>)>>;
+
+struct Connection {
+    stream: MioStream,
+    mode: Mode,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// Sequence number of the next request read.
+    next_seq: u64,
+    /// Sequence number of the next response to write. Responses are written in the order of
+    /// their requests, as the legacy JSON protocol matches them by order.
+    next_write: u64,
+    /// Responses ready before those of earlier requests.
+    done: BTreeMap<u64, Vec<u8>>,
+    /// No more requests are read, and the connection is closed once the pending ones are
+    /// answered.
+    closing: bool,
+    /// The client sent all its requests.
+    eof: bool,
+    last_active: Instant,
+    interest: Interest,
+}
+
+impl Connection {
+    fn pending(&self) -> u64 {
+        self.next_seq - self.next_write
+    }
+
+    fn finished(&self) -> bool {
+        (self.closing || self.eof) && self.pending() == 0 && self.output.is_empty()
+    }
+
+    /// Queue the response of a request, writing it once the earlier ones are.
+    fn complete(&mut self, seq: u64, bytes: Vec<u8>) {
+        self.done.insert(seq, bytes);
+        while let Some(bytes) = self.done.remove(&self.next_write) {
+            self.output.extend_from_slice(&bytes);
+            self.next_write += 1;
+        }
+    }
+
+    /// Answer right away, in order, without reading any more requests.
+    fn fail(&mut self, bytes: Vec<u8>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.complete(seq, bytes);
+        self.closing = true;
+        self.input.clear();
+    }
+
+    /// Read what the connection has to read, up to `MAX_INPUT` bytes buffered. Return whether
+    /// anything was read or the end of the stream reached.
+    fn read(&mut self) -> io::Result<bool> {
+        let mut buf = [0u8; 16 * 1024];
+        let mut progress = false;
+        while self.input.len() < MAX_INPUT {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(true);
+                }
+                Ok(n) => {
+                    self.input.extend_from_slice(&buf[..n]);
+                    self.last_active = Instant::now();
+                    progress = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(progress)
+    }
+
+    /// Write what the connection takes of the output.
+    fn write(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                    self.last_active = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the connection to be writable only while there is output left.
+    fn update_interest(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = if self.output.is_empty() {
+            Interest::READABLE
+        } else {
+            Interest::READABLE | Interest::WRITABLE
+        };
+        if interest != self.interest {
+            registry.reregister(&mut self.stream, token, interest)?;
+            self.interest = interest;
+        }
+        Ok(())
+    }
+}
+
+/// Serves the native protocols like `KvsServer`, multiplexing all the connections on the
+/// thread calling `do_loop` and running only the engine calls on the thread pool.
+pub struct ReactorServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    tcp_listener: TcpListener,
+    thread_pool: P,
+    idle_timeout: Option<Duration>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> ReactorServer<E, P> {
+    pub fn new(engine: E, tcp_listener: TcpListener, thread_pool: P) -> Self {
+        Self {
+            engine,
+            tcp_listener,
+            thread_pool,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+
+    /// Set how long a connection may wait for its next request before it is closed. `None`
+    /// keeps idle connections open until the client closes them.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn do_loop(&mut self) -> Result<()> {
+        let mut poll = Poll::new()?;
+        let listener = self.tcp_listener.try_clone()?;
+        listener.set_nonblocking(true)?;
+        let mut listener = MioListener::from_std(listener);
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let completions = Arc::new(Completions::default());
+
+        let mut connections: HashMap<Token, Connection> = HashMap::new();
+        // tokens are not reused, so a late response can not reach another connection
+        let mut next_token = WAKER.0 + 1;
+        let mut events = Events::with_capacity(1024);
+        let mut last_sweep = Instant::now();
+        loop {
+            match poll.poll(&mut events, Some(SWEEP_INTERVAL)) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => result?,
+            }
+
+            let mut ready = Vec::new();
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => loop {
+                        let (mut stream, addr) = match listener.accept() {
+                            Ok(accepted) => accepted,
+                            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => {
+                                warn!("unable to accept connection: {}", e);
+                                break;
+                            }
+                        };
+                        let token = Token(next_token);
+                        next_token += 1;
+                        poll.registry()
+                            .register(&mut stream, token, Interest::READABLE)?;
+                        info!("accepted connection from {}", addr);
+                        connections.insert(token, Self::connection(stream));
+                        ready.push(token);
+                    },
+                    WAKER => {}
+                    token => ready.push(token),
+                }
+            }
+            for (token, seq, bytes) in completions.lock().unwrap().drain(..) {
+                if let Some(conn) = connections.get_mut(&token) {
+                    conn.complete(seq, bytes);
+                    ready.push(token);
+                }
+            }
+
+            for token in ready {
+                let conn = match connections.get_mut(&token) {
+                    Some(conn) => conn,
+                    None => continue,
+                };
+                let result = self
+                    .process(conn, token, &completions, &waker)
+                    .and_then(|_| Ok(conn.write()?))
+                    .and_then(|_| Ok(conn.update_interest(poll.registry(), token)?));
+                match result {
+                    Ok(()) if !conn.finished() => {}
+                    Ok(()) => {
+                        connections.remove(&token);
+                    }
+                    Err(e) => {
+                        info!("closing connection: {}", e);
+                        connections.remove(&token);
+                    }
+                }
+            }
+
+            if last_sweep.elapsed() >= SWEEP_INTERVAL {
+                last_sweep = Instant::now();
+                if let Some(timeout) = self.idle_timeout {
+                    connections.retain(|_, conn| {
+                        let idle = conn.pending() == 0
+                            && conn.output.is_empty()
+                            && conn.last_active.elapsed() >= timeout;
+                        if idle {
+                            info!("closing idle connection");
+                        }
+                        !idle
+                    });
+                }
+            }
+        }
+    }
+
+    fn connection(stream: MioStream) -> Connection {
+        Connection {
+            stream,
+            mode: Mode::Unknown,
+            input: Vec::new(),
+            output: Vec::new(),
+            next_seq: 0,
+            next_write: 0,
+            done: BTreeMap::new(),
+            closing: false,
+            eof: false,
+            last_active: Instant::now(),
+            interest: Interest::READABLE,
+        }
+    }
+
+    /// Read and dispatch the requests of a connection, until it has no more to read or too
+    /// many are pending.
+    fn process(
+        &self,
+        conn: &mut Connection,
+        token: Token,
+        completions: &Arc<Completions>,
+        waker: &Arc<Waker>,
+    ) -> Result<()> {
+        loop {
+            while !conn.closing && conn.pending() < MAX_PENDING {
+                match self.decode(conn)? {
+                    Some((id, req)) => {
+                        let seq = conn.next_seq;
+                        conn.next_seq += 1;
+                        self.dispatch(conn.mode, token, seq, id, req, completions, waker);
+                    }
+                    None => break,
+                }
+            }
+            if conn.closing || conn.eof || conn.pending() >= MAX_PENDING || !conn.read()? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Take the next request out of the input of a connection, with its ID, or return `None`
+    /// if the input holds no whole request.
+    ///
+    /// Answers malformed input with an error, and the handshake with the version to use.
+    fn decode(&self, conn: &mut Connection) -> Result<Option<(u64, Request)>> {
+        loop {
+            match conn.mode {
+                Mode::Unknown => match conn.input.first() {
+                    Some(&b) if b == protocol::MAGIC[0] => conn.mode = Mode::Handshake,
+                    Some(_) => conn.mode = Mode::Json,
+                    None => return Ok(None),
+                },
+                Mode::Handshake => {
+                    if conn.input.len() < Handshake::LEN {
+                        return Ok(None);
+                    }
+                    let handshake = Handshake::read_from(&mut &conn.input[..Handshake::LEN])?;
+                    conn.input.drain(..Handshake::LEN);
+                    let accepted = handshake.accept();
+                    accepted.write_to(&mut conn.output)?;
+                    if accepted.version == 0 {
+                        warn!("no protocol version shared with client: {:?}", handshake);
+                        conn.closing = true;
+                        return Ok(None);
+                    }
+                    conn.mode = Mode::Framed;
+                }
+                Mode::Framed => {
+                    let (frame, len) = match Frame::decode(&conn.input) {
+                        Ok(Some(decoded)) => decoded,
+                        Ok(None) => return Ok(None),
+                        Err(e) => {
+                            // the stream can not be resynchronized after a malformed frame
+                            warn!("unable to read frame: {}", e);
+                            conn.fail(encode(Mode::Framed, 0, &Response::from(&e)));
+                            return Ok(None);
+                        }
+                    };
+                    conn.input.drain(..len);
+                    match Request::from_frame(&frame) {
+                        Ok(req) => return Ok(Some((frame.id, req))),
+                        Err(e) => {
+                            warn!("unable to decode request {}: {}", frame.id, e);
+                            let seq = conn.next_seq;
+                            conn.next_seq += 1;
+                            conn.complete(seq, encode(Mode::Framed, frame.id, &Response::from(&e)));
+                        }
+                    }
+                }
+                Mode::Json => {
+                    let mut requests = Deserializer::from_slice(&conn.input).into_iter::<Request>();
+                    return match requests.next() {
+                        Some(Ok(req)) => {
+                            let len = requests.byte_offset();
+                            conn.input.drain(..len);
+                            Ok(Some((0, req)))
+                        }
+                        Some(Err(e)) if e.is_eof() && conn.input.len() < MAX_INPUT => Ok(None),
+                        Some(Err(e)) => {
+                            // the stream can not be resynchronized after a malformed request
+                            warn!("unable to read request: {}", e);
+                            let resp = Response::from(&KvsError::Protocol(e.to_string()));
+                            conn.fail(encode(Mode::Json, 0, &resp));
+                            Ok(None)
+                        }
+                        None => {
+                            conn.input.clear();
+                            Ok(None)
+                        }
+                    };
+                }
+            }
+        }
+    }
+
+    /// Answer a request on the thread pool, then wake the reactor to write the response.
+    #[allow(clippy::too_many_arguments)]
+    fn dispatch(
+        &self,
+        mode: Mode,
+        token: Token,
+        seq: u64,
+        id: u64,
+        req: Request,
+        completions: &Arc<Completions>,
+        waker: &Arc<Waker>,
+    ) {
+        let engine = self.engine.clone();
+        let completions = completions.clone();
+        let waker = waker.clone();
+        self.thread_pool.spawn(move || {
+            info!("processing request {} {:?}", id, req);
+            let resp = panic::catch_unwind(AssertUnwindSafe(|| process_request(&engine, req)))
+                .unwrap_or_else(|_| Response::from(&KvsError::Panicked));
+            info!("return {} {:?}", id, resp);
+            completions
+                .lock()
+                .unwrap()
+                .push((token, seq, encode(mode, id, &resp)));
+            if let Err(e) = waker.wake() {
+                error!("unable to wake the reactor: {}", e);
+            }
+        });
+    }
+}
+
+/// The bytes of a response in the protocol of a connection.
+fn encode(mode: Mode, id: u64, resp: &Response) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let result = match mode {
+        Mode::Json => serde_json::to_writer(&mut buf, resp).map_err(KvsError::from),
+        _ => resp.to_frame(id).and_then(|frame| frame.write_to(&mut buf)),
+    };
+    if let Err(e) = result {
+        error!("unable to encode response {:?}: {}", resp, e);
+        buf.clear();
+        return encode(mode, id, &Response::from(&e));
+    }
+    buf
+}
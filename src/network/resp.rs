@@ -0,0 +1,382 @@
+//! The RESP2 protocol of Redis, so that Redis clients and tools can use the store.
+//!
+//! A command is an array of bulk strings, or an inline command: words separated by spaces on
+//! one line. Commands are answered in order, and may be pipelined.
+//!
+//! Supported commands are `PING`, `GET`, `SET`, `DEL`, `EXISTS`, `MGET`, `MSET`, `INFO`,
+//! `SCAN` and `QUIT`; any other is answered with an error. Keys and values are strings, so
+//! arguments that are not UTF-8 are rejected.
+
+use super::protocol::MAX_FRAME_LEN;
+use super::server::DEFAULT_IDLE_TIMEOUT;
+use super::{cut_short, read_line};
+use crate::engine::KvsEngine;
+use crate::err::{KvsError, Result};
+use crate::thread_pool::ThreadPool;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// Lines longer than this, inline commands included, are rejected.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+/// Arrays with more elements than this are rejected rather than allocated for.
+const MAX_ARRAY_LEN: usize = 1024 * 1024;
+/// Keys returned by `SCAN` when no `COUNT` is given.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+const COMMANDS: &[&str] = &[
+    "PING", "GET", "SET", "DEL", "EXISTS", "MGET", "MSET", "INFO", "SCAN", "QUIT",
+];
+
+/// A RESP2 reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or the null bulk string for `None`.
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Reply {
+        Reply::Simple("OK".to_owned())
+    }
+
+    fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self {
+            Reply::Simple(s) => write!(writer, "+{}\r\n", s),
+            Reply::Error(s) => write!(writer, "-{}\r\n", s),
+            Reply::Integer(n) => write!(writer, ":{}\r\n", n),
+            Reply::Bulk(None) => write!(writer, "$-1\r\n"),
+            Reply::Bulk(Some(s)) => write!(writer, "${}\r\n{}\r\n", s.len(), s),
+            Reply::Array(replies) => {
+                write!(writer, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write_to(writer))
+            }
+        }
+    }
+}
+
+impl From<&KvsError> for Reply {
+    fn from(e: &KvsError) -> Self {
+        let prefix = match e {
+            KvsError::ReadOnly => "READONLY",
+            _ => "ERR",
+        };
+        Reply::Error(format!("{} {}", prefix, e))
+    }
+}
+
+/// Read a command: its name and arguments. Return `None` if the stream ends before one
+/// starts, and an empty command for an empty line or array.
+fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader, MAX_LINE_LEN)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_len(&line[1..])?.unwrap_or(0);
+    if count > MAX_ARRAY_LEN {
+        return Err(KvsError::Protocol(format!("array of {} elements", count)));
+    }
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader, MAX_LINE_LEN)?.ok_or_else(cut_short)?;
+        if line.first() != Some(&b'$') {
+            return Err(KvsError::Protocol("expected a bulk string".to_owned()));
+        }
+        let len = match parse_len(&line[1..])? {
+            Some(len) if len <= MAX_FRAME_LEN as usize => len,
+            _ => return Err(KvsError::Protocol("bad bulk string length".to_owned())),
+        };
+        let mut arg = vec![0u8; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(KvsError::Protocol(
+                "bulk string not ended by CRLF".to_owned(),
+            ));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Parse the length of an array or bulk string. A negative length is a null one.
+fn parse_len(s: &[u8]) -> Result<Option<usize>> {
+    let len: i64 = std::str::from_utf8(s)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| KvsError::Protocol("invalid length".to_owned()))?;
+    Ok(usize::try_from(len).ok())
+}
+
+/// Serves the commands of Redis clients over TCP, each connection carrying any number of
+/// commands until the client closes it or leaves it idle for longer than the idle timeout.
+pub struct RespServer<E: KvsEngine, P: ThreadPool> {
+    engine: E,
+    tcp_listener: TcpListener,
+    thread_pool: P,
+    idle_timeout: Option<Duration>,
+}
+
+impl<E: KvsEngine, P: ThreadPool> RespServer<E, P> {
+    pub fn new(engine: E, tcp_listener: TcpListener, thread_pool: P) -> Self {
+        Self {
+            engine,
+            tcp_listener,
+            thread_pool,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+
+    /// Set how long a connection may wait for its next command before it is closed. `None`
+    /// keeps idle connections open until the client closes them.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    pub fn do_loop(&mut self) -> Result<()> {
+        for stream in self.tcp_listener.incoming() {
+            let e = self.engine.clone();
+            let stream = stream?;
+            let idle_timeout = self.idle_timeout;
+            self.thread_pool.spawn(move || {
+                if let Err(e) = Self::process_stream(&e, stream, idle_timeout) {
+                    warn!("RESP connection failed: {}", e);
+                }
+            })
+        }
+
+        Ok(())
+    }
+
+    fn process_stream(engine: &E, stream: TcpStream, idle_timeout: Option<Duration>) -> Result<()> {
+        stream.set_read_timeout(idle_timeout)?;
+        let mut reader = BufReader::new(&stream);
+        let mut writer = BufWriter::new(&stream);
+        loop {
+            let args = match read_command(&mut reader) {
+                Ok(Some(args)) => args,
+                Ok(None) => return Ok(()),
+                Err(KvsError::Io(e)) => {
+                    info!("closing RESP connection: {}", e);
+                    return Ok(());
+                }
+                Err(e) => {
+                    // the stream can not be resynchronized after a malformed command
+                    warn!("unable to read RESP command: {}", e);
+                    Reply::from(&e).write_to(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+            };
+            if args.is_empty() {
+                continue;
+            }
+
+            let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+            let reply = if quit {
+                Reply::ok()
+            } else {
+                Self::process_command(engine, &args)
+            };
+            reply.write_to(&mut writer)?;
+            // pipelined commands already read are answered in one write
+            if quit || reader.buffer().is_empty() {
+                writer.flush()?;
+            }
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    fn process_command(engine: &E, args: &[Vec<u8>]) -> Reply {
+        let name = String::from_utf8_lossy(&args[0]).to_ascii_uppercase();
+        if !COMMANDS.contains(&name.as_str()) {
+            return Reply::Error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(&args[0])
+            ));
+        }
+        let args: Vec<String> = match args[1..]
+            .iter()
+            .map(|arg| String::from_utf8(arg.clone()))
+            .collect()
+        {
+            Ok(args) => args,
+            Err(_) => return Reply::Error("ERR arguments must be UTF-8".to_owned()),
+        };
+        info!("processing RESP command {} {:?}", name, args);
+
+        let result = match (name.as_str(), args.as_slice()) {
+            ("PING", []) => Ok(Reply::Simple("PONG".to_owned())),
+            ("PING", [message]) => Ok(Reply::Bulk(Some(message.clone()))),
+            ("GET", [key]) => engine.get(key.clone()).map(Reply::Bulk),
+            ("SET", [key, value]) => engine.set(key.clone(), value.clone()).map(|_| Reply::ok()),
+            ("DEL", keys) if !keys.is_empty() => {
+                Self::count(keys, |key| match engine.remove(key.clone()) {
+                    Err(KvsError::KeyNotFound) => Ok(false),
+                    result => result.map(|_| true),
+                })
+            }
+            ("EXISTS", keys) if !keys.is_empty() => {
+                Self::count(keys, |key| Ok(engine.get(key.clone())?.is_some()))
+            }
+            ("MGET", keys) if !keys.is_empty() => keys
+                .iter()
+                .map(|key| engine.get(key.clone()).map(Reply::Bulk))
+                .collect::<Result<_>>()
+                .map(Reply::Array),
+            ("MSET", pairs) if !pairs.is_empty() && pairs.len() % 2 == 0 => pairs
+                .chunks(2)
+                .try_for_each(|pair| engine.set(pair[0].clone(), pair[1].clone()))
+                .map(|_| Reply::ok()),
+            // every section holds the same few fields, so the section asked for is ignored
+            ("INFO", [] | [_]) => engine.stats().map(|stats| {
+                Reply::Bulk(Some(format!(
+                    "# Server\r\nkvs_version:{}\r\n\r\n# Keyspace\r\nengine:{}\r\nkeys:{}\r\n\
+                     tombstones:{}\r\nlive_bytes:{}\r\ndead_bytes:{}\r\ncompactions:{}\r\n\
+                     file_size:{}\r\n",
+                    env!("CARGO_PKG_VERSION"),
+                    stats.engine,
+                    stats.live_keys,
+                    stats.tombstones,
+                    stats.live_bytes,
+                    stats.dead_bytes,
+                    stats.compactions,
+                    stats.file_size,
+                )))
+            }),
+            ("SCAN", [cursor, options @ ..]) => return Self::scan(engine, cursor, options),
+            _ => {
+                return Reply::Error(format!(
+                    "ERR wrong number of arguments for '{}' command",
+                    name.to_ascii_lowercase()
+                ))
+            }
+        };
+        result.unwrap_or_else(|e| {
+            error!("RESP command {} {:?} failed: {}", name, args, e);
+            Reply::from(&e)
+        })
+    }
+
+    /// Count the keys for which `f` holds.
+    fn count(keys: &[String], f: impl Fn(&String) -> Result<bool>) -> Result<Reply> {
+        let mut count = 0;
+        for key in keys {
+            if f(key)? {
+                count += 1;
+            }
+        }
+        Ok(Reply::Integer(count))
+    }
+
+    /// Answer `SCAN cursor [MATCH pattern] [COUNT count]`.
+    ///
+    /// The cursor is the position in the ordered keys starting with the literal prefix of the
+    /// pattern, so keys written or removed between calls may shift others into or out of the
+    /// pages returned.
+    fn scan(engine: &E, cursor: &str, options: &[String]) -> Reply {
+        let cursor: usize = match cursor.parse() {
+            Ok(cursor) => cursor,
+            Err(_) => return Reply::Error("ERR invalid cursor".to_owned()),
+        };
+        let mut pattern = "*";
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in options.chunks(2) {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case("MATCH") => pattern = value,
+                [name, value] if name.eq_ignore_ascii_case("COUNT") => match value.parse() {
+                    Ok(n) if n > 0 => count = n,
+                    _ => return Reply::Error("ERR value is out of range".to_owned()),
+                },
+                _ => return Reply::Error("ERR syntax error".to_owned()),
+            }
+        }
+
+        let prefix = pattern
+            .find(['*', '?', '[', '\\'])
+            .map_or(pattern, |end| &pattern[..end]);
+        let pairs = match engine.scan(prefix.to_owned()) {
+            Ok(pairs) => pairs,
+            Err(e) => {
+                error!("RESP command SCAN {} failed: {}", pattern, e);
+                return Reply::from(&e);
+            }
+        };
+        let len = pairs.len();
+        let end = cursor.saturating_add(count).min(len);
+        let keys = pairs
+            .into_iter()
+            .take(end)
+            .skip(cursor)
+            .filter(|(key, _)| glob_match(pattern.as_bytes(), key.as_bytes()))
+            .map(|(key, _)| Reply::Bulk(Some(key)))
+            .collect();
+        let next = if end == len { 0 } else { end };
+        Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string())),
+            Reply::Array(keys),
+        ])
+    }
+}
+
+/// Whether a string matches a glob pattern of `*`, `?`, `[...]` sets and `\` escapes, like
+/// the patterns of Redis.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) => (0..=s.len()).any(|i| glob_match(rest, &s[i..])),
+        Some((b'?', rest)) => !s.is_empty() && glob_match(rest, &s[1..]),
+        Some((b'[', rest)) => {
+            let (c, s) = match s.split_first() {
+                Some(split) => split,
+                None => return false,
+            };
+            let (negate, mut set) = match rest.split_first() {
+                Some((b'^', set)) => (true, set),
+                _ => (false, rest),
+            };
+            let mut matched = false;
+            loop {
+                match set {
+                    [] => return false,
+                    [b']', tail @ ..] => {
+                        set = tail;
+                        break;
+                    }
+                    [b'\\', x, tail @ ..] => {
+                        matched |= x == c;
+                        set = tail;
+                    }
+                    [lo, b'-', hi, tail @ ..] if *hi != b']' => {
+                        matched |= (lo.min(hi)..=lo.max(hi)).contains(&c);
+                        set = tail;
+                    }
+                    [x, tail @ ..] => {
+                        matched |= x == c;
+                        set = tail;
+                    }
+                }
+            }
+            matched != negate && glob_match(set, s)
+        }
+        Some((b'\\', [x, rest @ ..])) => s.first() == Some(x) && glob_match(rest, &s[1..]),
+        Some((x, rest)) => s.first() == Some(x) && glob_match(rest, &s[1..]),
+    }
+}
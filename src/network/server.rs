@@ -1,14 +1,24 @@
+use super::protocol::{self, Frame, Handshake};
 use super::Request;
 use super::Response;
 use crate::engine::KvsEngine;
-use crate::err::Result;
+use crate::err::{KvsError, Result};
 use crate::thread_pool::ThreadPool;
+use serde_json::Deserializer;
+use std::io::{BufReader, BufWriter, Write};
 use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
 
+/// How long a connection may be idle by default.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Serves requests over TCP, each connection carrying any number of requests until the
+/// client closes it or leaves it idle for longer than the idle timeout.
 pub struct KvsServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     tcp_listener: TcpListener,
     thread_pool: P,
+    idle_timeout: Option<Duration>,
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
@@ -17,44 +27,174 @@ impl<E: KvsEngine, P: ThreadPool> KvsServer<E, P> {
             engine,
             tcp_listener,
             thread_pool,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
         }
     }
 
+    /// Set how long a connection may wait for its next request before it is closed. `None`
+    /// keeps idle connections open until the client closes them.
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
     pub fn do_loop(&mut self) -> Result<()> {
         for stream in self.tcp_listener.incoming() {
             let e = self.engine.clone();
             let stream = stream?;
+            let idle_timeout = self.idle_timeout;
             self.thread_pool.spawn(move || {
-                let _ = Self::process_stream(&e, stream);
+                if let Err(e) = Self::process_stream(&e, stream, idle_timeout) {
+                    warn!("connection failed: {}", e);
+                }
             })
         }
 
         Ok(())
     }
 
-    fn process_stream(engine: &E, stream: TcpStream) -> Result<()> {
-        let req: Request = serde_json::from_reader(&stream)?;
-        info!("processing request {:?}", req);
-        let resp = match req {
-            Request::Get { key } => match engine.get(key).unwrap_or(None) {
-                None => Response::NotFound,
-                Some(v) => Response::Value(v),
-            },
-            Request::Set { key, value } => {
-                let _ = engine.set(key, value);
-                Response::Success
+    /// Answer the requests of a connection in order, until it is closed or idle.
+    ///
+    /// The first byte tells a framed connection, which starts with the handshake, from a
+    /// legacy JSON one.
+    fn process_stream(engine: &E, stream: TcpStream, idle_timeout: Option<Duration>) -> Result<()> {
+        stream.set_read_timeout(idle_timeout)?;
+        let mut first = [0u8; 1];
+        match stream.peek(&mut first) {
+            Ok(0) => Ok(()),
+            Ok(_) if first[0] == protocol::MAGIC[0] => Self::process_framed(engine, &stream),
+            Ok(_) => Self::process_json(engine, &stream),
+            Err(e) => {
+                info!("closing connection: {}", e);
+                Ok(())
             }
-            Request::Remove { key } => {
-                if engine.remove(key).is_ok() {
-                    Response::Success
-                } else {
-                    Response::NotFound
+        }
+    }
+
+    fn process_framed(engine: &E, stream: &TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream);
+        let mut writer = BufWriter::new(stream);
+        let handshake = Handshake::read_from(&mut reader)?;
+        let accepted = handshake.accept();
+        accepted.write_to(&mut writer)?;
+        if accepted.version == 0 {
+            warn!("no protocol version shared with client: {:?}", handshake);
+            return Ok(());
+        }
+
+        loop {
+            let frame = match Frame::read_from(&mut reader) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()),
+                Err(KvsError::Io(e)) => {
+                    info!("closing connection: {}", e);
+                    return Ok(());
+                }
+                Err(e) => {
+                    // the stream can not be resynchronized after a malformed frame
+                    warn!("unable to read frame: {}", e);
+                    Response::from(&e).to_frame(0)?.write_to(&mut writer)?;
+                    writer.flush()?;
+                    return Ok(());
                 }
+            };
+            let resp = match Request::from_frame(&frame) {
+                Ok(req) => {
+                    info!("processing request {} {:?}", frame.id, req);
+                    process_request(engine, req)
+                }
+                Err(e) => {
+                    warn!("unable to decode request {}: {}", frame.id, e);
+                    Response::from(&e)
+                }
+            };
+
+            resp.to_frame(frame.id)?.write_to(&mut writer)?;
+            // pipelined requests already read are answered in one write
+            if reader.buffer().is_empty() {
+                writer.flush()?;
             }
-        };
+            info!("return {} {:?}", frame.id, resp);
+        }
+    }
+
+    /// Answer the requests of a connection speaking the legacy protocol: JSON values one
+    /// after the other.
+    fn process_json(engine: &E, stream: &TcpStream) -> Result<()> {
+        let requests = Deserializer::from_reader(BufReader::new(stream)).into_iter::<Request>();
+        let mut writer = BufWriter::new(stream);
+        for req in requests {
+            let resp = match req {
+                Ok(req) => {
+                    info!("processing request {:?}", req);
+                    process_request(engine, req)
+                }
+                Err(e) if e.is_io() => {
+                    info!("closing connection: {}", e);
+                    return Ok(());
+                }
+                Err(e) => {
+                    // the stream can not be resynchronized after a malformed request
+                    warn!("unable to read request: {}", e);
+                    let resp = Response::from(&KvsError::Protocol(e.to_string()));
+                    serde_json::to_writer(&mut writer, &resp)?;
+                    writer.flush()?;
+                    return Ok(());
+                }
+            };
 
-        serde_json::to_writer(stream, &resp)?;
-        info!("return {:?}", resp);
+            serde_json::to_writer(&mut writer, &resp)?;
+            writer.flush()?;
+            info!("return {:?}", resp);
+        }
         Ok(())
     }
 }
+
+/// Answer a request, turning a failure into an `Error` response.
+pub(crate) fn process_request<E: KvsEngine>(engine: &E, req: Request) -> Response {
+    let result = match req {
+        Request::Get { ref key } => engine.get(key.clone()).map(|value| match value {
+            None => Response::NotFound,
+            Some(v) => Response::Value(v),
+        }),
+        Request::Set { ref key, ref value } => engine
+            .set(key.clone(), value.clone())
+            .map(|_| Response::Success),
+        Request::Remove { ref key } => match engine.remove(key.clone()) {
+            Err(KvsError::KeyNotFound) => Ok(Response::NotFound),
+            result => result.map(|_| Response::Success),
+        },
+        Request::Stats => engine.stats().map(Response::Stats),
+        Request::MGet { ref keys } => Ok(process_batch(engine, keys, |key| Request::Get {
+            key: key.clone(),
+        })),
+        Request::MSet { ref pairs } => {
+            Ok(process_batch(engine, pairs, |(key, value)| Request::Set {
+                key: key.clone(),
+                value: value.clone(),
+            }))
+        }
+        Request::MDel { ref keys } => Ok(process_batch(engine, keys, |key| Request::Remove {
+            key: key.clone(),
+        })),
+    };
+    result.unwrap_or_else(|e| {
+        error!("request {:?} failed: {}", req, e);
+        Response::from(&e)
+    })
+}
+
+/// Answer a request on many keys with the answers to a request on each.
+fn process_batch<E: KvsEngine, T>(
+    engine: &E,
+    items: &[T],
+    request: impl Fn(&T) -> Request,
+) -> Response {
+    Response::Batch(
+        items
+            .iter()
+            .map(|item| process_request(engine, request(item)))
+            .collect(),
+    )
+}
@@ -1,4 +1,5 @@
 use super::err::Result;
+use std::sync::Arc;
 
 pub trait ThreadPool {
     /// Creates a new thread pool, immediately spawning the specified number of
@@ -19,6 +20,20 @@ pub trait ThreadPool {
         F: FnOnce() + Send + 'static;
 }
 
+/// A pool shared by several servers.
+impl<P: ThreadPool> ThreadPool for Arc<P> {
+    fn new(threads: u32) -> Result<Self> {
+        P::new(threads).map(Arc::new)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        (**self).spawn(job)
+    }
+}
+
 mod naive;
 mod rayon;
 mod shared_queue;
@@ -1,5 +1,6 @@
 use crate::err::Result;
 use rayon;
+use std::io;
 
 pub struct RayonThreadPool(rayon::ThreadPool);
 
@@ -7,7 +8,8 @@ impl super::ThreadPool for RayonThreadPool {
     fn new(threads: u32) -> Result<RayonThreadPool> {
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads as usize)
-            .build()?;
+            .build()
+            .map_err(io::Error::other)?;
         Ok(RayonThreadPool(pool))
     }
 
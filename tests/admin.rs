@@ -0,0 +1,245 @@
+use assert_cmd::prelude::*;
+use kvs::engine::{
+    BTreeStore, KvStore, KvsEngine, LsmStore, SegmentFormat, ShardedKvStore, SledStore,
+};
+use kvs::err::Result;
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tempfile::TempDir;
+
+fn admin(args: &[&str], dir: &Path) -> Command {
+    let mut cmd = Command::cargo_bin("kvs-admin").unwrap();
+    cmd.args(args).arg("--dir").arg(dir);
+    cmd
+}
+
+fn segments(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+fn fill(dir: &Path) -> Result<()> {
+    let store = KvStore::open(dir)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    Ok(())
+}
+
+#[test]
+fn verify_dump_stats() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path())?;
+
+    admin(&["verify"], temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("4 records, checksummed").and(contains("index: 2 keys")));
+    admin(&["dump"], temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("set key1 value1").and(contains("rm key2")));
+    admin(&["stats"], temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("engine: kvs").and(contains("live_keys: 2")));
+    Ok(())
+}
+
+// A flipped byte should fail verification, and repair should keep the records before it
+#[test]
+fn corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path())?;
+
+    let path = &segments(temp_dir.path())[0];
+    let mut bytes = fs::read(path)?;
+    let last = bytes.len() - 3;
+    bytes[last] ^= 0xff;
+    fs::write(path, bytes)?;
+
+    admin(&["verify"], temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("checksum mismatch"));
+    admin(&["repair"], temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("dropped"));
+    admin(&["verify"], temp_dir.path()).assert().success();
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn torn_record() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fill(temp_dir.path())?;
+
+    let path = &segments(temp_dir.path())[0];
+    let len = fs::metadata(path)?.len();
+    OpenOptions::new()
+        .append(true)
+        .open(path)?
+        .write_all(&[0, 0, 0, 0, 0, 0, 1])?;
+
+    admin(&["verify"], temp_dir.path())
+        .assert()
+        .failure()
+        .stdout(contains("record cut short"));
+    admin(&["repair"], temp_dir.path()).assert().success();
+    assert_eq!(fs::metadata(path)?.len(), len);
+    Ok(())
+}
+
+#[test]
+fn compact() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("{}", i))?;
+    }
+    let before = store.stats()?.file_size;
+    drop(store);
+
+    admin(&["compact"], temp_dir.path()).assert().success();
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.stats()?.file_size < before);
+    assert_eq!(store.get("key".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn sled() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = SledStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    admin(&["verify"], temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("1 keys, checksum"));
+    admin(&["dump"], temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("set key1 value1"));
+    admin(&["stats", "--engine", "sled"], temp_dir.path())
+        .assert()
+        .success()
+        .stdout(contains("engine: sled"));
+    Ok(())
+}
+
+// A `log` written by an earlier version, without header or checksums, should still be read,
+// and compaction should rewrite it with checksums
+#[test]
+fn legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let mut log = Vec::new();
+    for record in &[
+        r#"{"Set":{"key":"key1","value":"value1"}}"#,
+        r#"{"Set":{"key":"key2","value":"value2"}}"#,
+    ] {
+        log.extend_from_slice(&(record.len() as u64).to_be_bytes());
+        log.extend_from_slice(record.as_bytes());
+    }
+    fs::write(temp_dir.path().join("log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value3".to_owned())?;
+    // new records go to a segment of their own, with checksums
+    let reports = KvStore::inspect(temp_dir.path(), |_| ())?;
+    assert_eq!(reports.len(), 2);
+    assert_eq!(reports[0].format, Some(SegmentFormat::Legacy));
+    assert_eq!(reports[0].records, 2);
+    assert_eq!(reports[1].format, Some(SegmentFormat::Checksummed));
+    assert_eq!(reports[1].records, 1);
+
+    store.compact()?;
+    for report in KvStore::inspect(temp_dir.path(), |_| ())? {
+        assert!(report.is_ok());
+        assert_eq!(report.format, Some(SegmentFormat::Checksummed));
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Data of other engines or of a sharded store should be refused, and left untouched
+#[test]
+fn other_engines() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join(".engine"), [3u8])?;
+    fs::write(temp_dir.path().join("MANIFEST.tmp"), "")?;
+    for command in &["verify", "dump", "stats", "compact", "repair"] {
+        admin(&[command], temp_dir.path())
+            .assert()
+            .failure()
+            .stderr(contains("EngineMismatch"));
+        admin(&[command, "--engine", "kvs"], temp_dir.path())
+            .assert()
+            .failure();
+    }
+    assert!(segments(temp_dir.path()).is_empty());
+    assert!(temp_dir.path().join("MANIFEST.tmp").exists());
+
+    let temp_dir = TempDir::new().unwrap();
+    ShardedKvStore::open(temp_dir.path(), 2)?.set("key1".to_owned(), "value1".to_owned())?;
+    admin(&["verify"], temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("EngineMismatch"));
+    Ok(())
+}
+
+// An lsm store should be refused even without the tag of `kvs-server`
+#[test]
+fn lsm_refused() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    LsmStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    let files = fs::read_dir(temp_dir.path())?.count();
+    for command in &["verify", "stats", "compact", "repair"] {
+        admin(&[command], temp_dir.path())
+            .assert()
+            .failure()
+            .stderr(contains("EngineMismatch"));
+    }
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), files);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A btree store should be refused even without the tag of `kvs-server`
+#[test]
+fn btree_refused() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    BTreeStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    let files = fs::read_dir(temp_dir.path())?.count();
+    for command in &["verify", "stats", "compact", "repair"] {
+        admin(&[command], temp_dir.path())
+            .assert()
+            .failure()
+            .stderr(contains("EngineMismatch"));
+    }
+    assert_eq!(fs::read_dir(temp_dir.path())?.count(), files);
+    let store = BTreeStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use futures::executor::block_on;
+use futures::future::join_all;
+use kvs::engine::{AsyncKvsEngine, KvStore, MemStore, Offload, SledStore};
+use kvs::err::{KvsError, Result};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use tempfile::TempDir;
+
+async fn exercise<E: AsyncKvsEngine>(engine: E) -> Result<()> {
+    let sets = (0..100).map(|i| engine.set(format!("key{}", i), format!("value{}", i)));
+    for result in join_all(sets).await {
+        result?;
+    }
+
+    let gets = (0..100).map(|i| engine.get(format!("key{}", i)));
+    for (i, value) in join_all(gets).await.into_iter().enumerate() {
+        assert_eq!(value?, Some(format!("value{}", i)));
+    }
+    assert_eq!(engine.get("missing".to_owned()).await?, None);
+
+    engine.remove("key0".to_owned()).await?;
+    assert_eq!(engine.get("key0".to_owned()).await?, None);
+    let err = engine.remove("key0".to_owned()).await.unwrap_err();
+    assert!(matches!(err, KvsError::KeyNotFound));
+
+    let pairs = engine.scan("key1".to_owned()).await?;
+    assert_eq!(pairs.len(), 11);
+    assert_eq!(pairs[0], ("key1".to_owned(), "value1".to_owned()));
+    assert_eq!(engine.stats().await?.live_keys, 99);
+    Ok(())
+}
+
+#[test]
+fn kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    block_on(exercise(store))
+}
+
+#[test]
+fn sled_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledStore::open(temp_dir.path())?;
+    block_on(exercise(store))
+}
+
+#[test]
+fn offload() -> Result<()> {
+    let engine = Offload::new(MemStore::new(), SharedQueueThreadPool::new(4)?);
+    block_on(exercise(engine))
+}
+
+// Values written asynchronously are found by the blocking interface after reopening.
+#[test]
+fn persist() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    block_on(AsyncKvsEngine::set(
+        &store,
+        "key1".to_owned(),
+        "value1".to_owned(),
+    ))?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        kvs::engine::KvsEngine::get(&store, "key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use kvs::engine::{BTreeStore, BTreeStoreOptions, KvsEngine, SyncPolicy};
+use kvs::err::{KvsError, Result};
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use tempfile::TempDir;
+
+// Should split and merge pages as keys come and go, keeping them in order
+#[test]
+fn many_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeStore::open(temp_dir.path())?;
+    // an order unrelated to the keys
+    let ids: Vec<u32> = (0..5000).map(|i| (i * 7919) % 5000).collect();
+    for &i in &ids {
+        store.set(format!("key{:05}", i), format!("value{}", i))?;
+    }
+    for &i in &ids {
+        if i % 5 != 0 {
+            store.remove(format!("key{:05}", i))?;
+        }
+    }
+
+    let check = |store: &BTreeStore| -> Result<()> {
+        let all = store.scan("".to_owned())?;
+        assert_eq!(all.len(), 1000);
+        assert!(all.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(store.scan("key012".to_owned())?.len(), 20);
+        assert_eq!(
+            store.get("key04995".to_owned())?,
+            Some("value4995".to_owned())
+        );
+        assert_eq!(store.get("key04996".to_owned())?, None);
+        assert_eq!(store.stats()?.live_keys, 1000);
+        Ok(())
+    };
+    check(&store)?;
+    drop(store);
+    let store = BTreeStore::open(temp_dir.path())?;
+    check(&store)?;
+
+    for i in (0..5000).step_by(5) {
+        store.remove(format!("key{:05}", i))?;
+    }
+    assert_eq!(store.scan("".to_owned())?, vec![]);
+    Ok(())
+}
+
+// Should store keys and values larger than a page
+#[test]
+fn large_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeStore::open(temp_dir.path())?;
+    let long = |c: char, n: usize| std::iter::repeat_n(c, n).collect::<String>();
+
+    for i in 0..50 {
+        store.set(format!("{}{}", long('k', 5000), i), long('v', 10000 + i))?;
+    }
+    store.set("small".to_owned(), long('s', 20000))?;
+    store.set("small".to_owned(), "value".to_owned())?;
+    for i in (0..50).step_by(2) {
+        store.remove(format!("{}{}", long('k', 5000), i))?;
+    }
+
+    drop(store);
+    let store = BTreeStore::open(temp_dir.path())?;
+    assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+    let long_keys = store.scan(long('k', 5000))?;
+    assert_eq!(long_keys.len(), 25);
+    for (key, value) in long_keys {
+        let i: usize = key[5000..].parse().unwrap();
+        assert_eq!(i % 2, 1);
+        assert_eq!(value, long('v', 10000 + i));
+    }
+
+    // freed pages are reused rather than growing the file
+    let size = store.stats()?.file_size;
+    for _ in 0..10 {
+        store.set("small".to_owned(), long('s', 20000))?;
+    }
+    assert!(store.stats()?.file_size <= size + 2 * 20000);
+    Ok(())
+}
+
+// Should count live keys and bytes, and the free pages left behind by copies
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "btree");
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.live_bytes, 10);
+    assert!(stats.dead_bytes > 0);
+    assert!(stats.file_size > 0);
+
+    drop(store);
+    let store = BTreeStore::open(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.live_keys, stats.live_keys);
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+
+    Ok(())
+}
+
+// A torn meta page should leave the store as of the write before
+#[test]
+fn torn_meta() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BTreeStoreOptions::new()
+        .sync(SyncPolicy::Always)
+        .cache_size(0);
+    let store = options.open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("last".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // the meta pages are the first two pages, with the transaction id at byte 16
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(temp_dir.path().join("data.btree"))?;
+    let mut txids = [0u64; 2];
+    for (page, txid) in txids.iter_mut().enumerate() {
+        let mut buf = [0u8; 8];
+        file.seek(SeekFrom::Start(page as u64 * 4096 + 16))?;
+        file.read_exact(&mut buf)?;
+        *txid = u64::from_be_bytes(buf);
+    }
+    let latest = if txids[0] > txids[1] { 0 } else { 1 };
+    file.seek(SeekFrom::Start(latest * 4096 + 20))?;
+    file.write_all(&[0xff; 8])?;
+    drop(file);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("last".to_owned())?, None);
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.set("last".to_owned(), "value".to_owned())?;
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// A damaged page should fail with its offset rather than be read, when no older tree is left
+#[test]
+fn corrupt_page() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = BTreeStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    // the root is the last page written, and its checksum is in its last 4 bytes; the meta
+    // of the empty tree before it is in page 0
+    let path = temp_dir.path().join("data.btree");
+    let len = fs::metadata(&path)?.len();
+    let mut file = OpenOptions::new().write(true).open(&path)?;
+    file.seek(SeekFrom::Start(len - 100))?;
+    file.write_all(&[0xff])?;
+    file.seek(SeekFrom::Start(20))?;
+    file.write_all(&[0xff; 8])?;
+    drop(file);
+
+    match BTreeStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset }) => assert_eq!(offset, len - 4),
+        other => panic!("expected a corruption, got {:?}", other.err()),
+    }
+    Ok(())
+}
+
+// Pages released by a write should be reused once the next one is written, whatever the policy
+#[test]
+fn page_reuse() -> Result<()> {
+    for policy in [SyncPolicy::Always, SyncPolicy::Never] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = BTreeStoreOptions::new()
+            .sync(policy)
+            .open(temp_dir.path())?;
+        for i in 0..200 {
+            store.set("key".to_owned(), format!("value{}", i))?;
+        }
+        assert!(store.stats()?.file_size <= 8 * 4096);
+    }
+    Ok(())
+}
+
+// A meta page written over pages that never reached the disk should leave the store as of the
+// write before
+#[test]
+fn meta_over_garbage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = BTreeStoreOptions::new().cache_size(0);
+    let store = options.open(temp_dir.path())?;
+    for i in 0..500 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("last".to_owned(), "value".to_owned())?;
+    drop(store);
+
+    // the meta pages are the first two pages, with the transaction id at byte 16 and the root
+    // at byte 24; a write always writes a new root
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(temp_dir.path().join("data.btree"))?;
+    let mut newest = (0, 0);
+    for page in 0..2 {
+        let mut buf = [0u8; 16];
+        file.seek(SeekFrom::Start(page * 4096 + 16))?;
+        file.read_exact(&mut buf)?;
+        let txid = u64::from_be_bytes(buf[..8].try_into().unwrap());
+        let root = u64::from_be_bytes(buf[8..].try_into().unwrap());
+        newest = newest.max((txid, root));
+    }
+    file.seek(SeekFrom::Start(newest.1 * 4096))?;
+    file.write_all(&[0xab; 4096])?;
+    drop(file);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("last".to_owned())?, None);
+    for i in 0..500 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    store.set("last".to_owned(), "value".to_owned())?;
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("last".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use kvs::engine::{KvStore, KvStoreOptions, KvsEngine};
+use kvs::err::Result;
+use std::env;
+use std::fs;
+use std::process::{Command, Stdio};
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+const CRASH_POINTS: &[&str] = &[
+    "compact-write",
+    "compact-sync",
+    "compact-rename",
+    "compact-sync-dir",
+    "compact-remove",
+    "compact-done",
+];
+
+// Fill a store, then trigger a compaction that is killed at `KVS_CRASH_POINT`.
+// Run in a child process by `crash_during_compaction`.
+#[test]
+#[ignore]
+fn crash_child() -> Result<()> {
+    let dir = match env::var_os("KVS_CRASH_DIR") {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+
+    let options = KvStoreOptions::new()
+        .compaction_threshold(u64::MAX)
+        .segment_size(4 * 1024);
+    let store = options.open(&dir)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "a".to_owned())?;
+    }
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "b".to_owned())?;
+    }
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    drop(store);
+
+    let store = options
+        .compaction_threshold(0)
+        .compaction_ratio(0.0)
+        .open(&dir)?;
+    store.set("trigger".to_owned(), "c".to_owned())?;
+    Ok(())
+}
+
+fn check_content(store: &KvStore) -> Result<()> {
+    for key_id in 0..10 {
+        assert_eq!(store.get(format!("key{}", key_id))?, None);
+    }
+    for key_id in 10..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("b".to_owned()));
+    }
+    assert_eq!(store.get("trigger".to_owned())?, Some("c".to_owned()));
+    Ok(())
+}
+
+// Kill a store at every step of a compaction, then check nothing is lost on reopen.
+#[test]
+fn crash_during_compaction() -> Result<()> {
+    for point in CRASH_POINTS {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let status = Command::new(env::current_exe()?)
+            .args(["crash_child", "--exact", "--ignored"])
+            .env("KVS_CRASH_POINT", point)
+            .env("KVS_CRASH_DIR", temp_dir.path())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()?;
+        assert!(!status.success(), "no crash at {}", point);
+
+        let store = KvStore::open(temp_dir.path())?;
+        check_content(&store)?;
+        let leftovers = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "tmp"))
+            .count();
+        assert_eq!(leftovers, 0, "temporary file left after crash at {}", point);
+
+        // Still writable, and consistent after another reopen
+        store.set("key0".to_owned(), "d".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, Some("d".to_owned()));
+        assert_eq!(store.get("key99".to_owned())?, Some("b".to_owned()));
+    }
+
+    Ok(())
+}
+
+// A crash of an earlier version between removing `log` and renaming `new_log` over it
+// leaves the whole store in `new_log`.
+#[test]
+fn recover_legacy_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        fs::rename(path, temp_dir.path().join("new_log"))?;
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(!temp_dir.path().join("new_log").exists());
+
+    Ok(())
+}
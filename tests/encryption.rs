@@ -0,0 +1,173 @@
+use kvs::engine::{EncryptionKey, KvStore, KvStoreOptions, KvsEngine, SegmentFormat};
+use kvs::err::{KvsError, Result};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+const KEY_A: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const KEY_B: &str = "ffeeddccbbaa99887766554433221100ffeeddccbbaa99887766554433221100";
+
+fn key(hex: &str) -> EncryptionKey {
+    EncryptionKey::from_hex(hex).unwrap()
+}
+
+fn contains_plain(dir: &Path, needle: &str) -> bool {
+    fs::read_dir(dir).unwrap().any(|entry| {
+        let bytes = fs::read(entry.unwrap().path()).unwrap();
+        bytes.windows(needle.len()).any(|w| w == needle.as_bytes())
+    })
+}
+
+fn assert_wrong_key(result: Result<KvStore>) {
+    match result {
+        Err(e) => assert!(matches!(e, KvsError::WrongKey), "unexpected error: {}", e),
+        Ok(_) => panic!("opened with a wrong key"),
+    }
+}
+
+#[test]
+fn parse_key() -> Result<()> {
+    assert!(EncryptionKey::from_hex("00").is_err());
+    assert!(EncryptionKey::from_hex(&KEY_A.replace('0', "g")).is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("key");
+    fs::write(&path, format!("{}\n", KEY_A))?;
+    assert_eq!(
+        format!("{:?}", EncryptionKey::from_file(&path)?),
+        format!("{:?}", key(KEY_A))
+    );
+    Ok(())
+}
+
+// Records should not be readable on disk, nor without the key
+#[test]
+fn encrypted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key(key(KEY_A));
+    let store = options.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "secret1".to_owned())?;
+    store.set("key2".to_owned(), "secret2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+    assert!(!contains_plain(temp_dir.path(), "secret1"));
+    assert!(!contains_plain(temp_dir.path(), "key1"));
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("secret1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    assert_wrong_key(KvStore::open(temp_dir.path()));
+    assert_wrong_key(
+        KvStoreOptions::new()
+            .encryption_key(key(KEY_B))
+            .open(temp_dir.path()),
+    );
+    Ok(())
+}
+
+// Records swapped within a segment or copied to another one should fail to decrypt
+#[test]
+fn moved_records() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().encryption_key(key(KEY_A));
+    let store = options.open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut records = Vec::new();
+    let reports = KvStore::inspect_with(temp_dir.path(), &options, |r| records.push(r))?;
+    let (first, second) = (&records[0], &records[1]);
+    assert_eq!(first.len, second.len);
+    let path = &reports[0].path;
+    let bytes = fs::read(path)?;
+    let (start, middle, len) = (
+        first.offset as usize,
+        second.offset as usize,
+        first.len as usize,
+    );
+
+    let mut swapped = bytes.clone();
+    swapped[start..start + len].copy_from_slice(&bytes[middle..middle + len]);
+    swapped[middle..middle + len].copy_from_slice(&bytes[start..start + len]);
+    fs::write(path, &swapped)?;
+    let reports = KvStore::inspect_with(temp_dir.path(), &options, |_| ())?;
+    assert!(reports[0]
+        .error
+        .as_ref()
+        .is_some_and(|e| e.contains("offset")));
+
+    fs::write(path, &bytes)?;
+    fs::copy(
+        path,
+        temp_dir.path().join(format!("{}.log", reports[0].gen + 1)),
+    )?;
+    let reports = KvStore::inspect_with(temp_dir.path(), &options, |_| ())?;
+    assert!(reports[0].is_ok());
+    assert!(reports[1].error.is_some());
+    Ok(())
+}
+
+// Compaction should re-encrypt the records of a previous key with the current one
+#[test]
+fn rotate_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .encryption_key(key(KEY_A))
+        .open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let rotating = KvStoreOptions::new()
+        .encryption_key(key(KEY_B))
+        .decryption_key(key(KEY_A));
+    let store = rotating.open(temp_dir.path())?;
+    store.set("key0".to_owned(), "new".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.compact()?;
+    drop(store);
+
+    let options = KvStoreOptions::new().encryption_key(key(KEY_B));
+    for report in KvStore::inspect_with(temp_dir.path(), &options, |_| ())? {
+        assert!(report.is_ok());
+    }
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key9".to_owned())?, Some("value9".to_owned()));
+    drop(store);
+
+    assert_wrong_key(
+        KvStoreOptions::new()
+            .encryption_key(key(KEY_A))
+            .open(temp_dir.path()),
+    );
+    Ok(())
+}
+
+// Giving a key to a plain store should encrypt new records, and all of them once compacted
+#[test]
+fn encrypt_plain_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "plain1".to_owned())?;
+    drop(store);
+
+    let options = KvStoreOptions::new().encryption_key(key(KEY_A));
+    let store = options.open(temp_dir.path())?;
+    store.set("key2".to_owned(), "secret2".to_owned())?;
+    assert!(contains_plain(temp_dir.path(), "plain1"));
+    assert!(!contains_plain(temp_dir.path(), "secret2"));
+
+    store.compact()?;
+    assert!(!contains_plain(temp_dir.path(), "plain1"));
+    let reports = KvStore::inspect_with(temp_dir.path(), &options, |_| ())?;
+    assert!(reports
+        .iter()
+        .all(|r| r.format == Some(SegmentFormat::Encrypted)));
+    assert_eq!(store.get("key1".to_owned())?, Some("plain1".to_owned()));
+    Ok(())
+}
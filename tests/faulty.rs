@@ -0,0 +1,102 @@
+use kvs::engine::{FaultRule, FaultyEngine, KvsEngine, MemStore, Operation};
+use kvs::err::{KvsError, Result};
+use std::time::{Duration, Instant};
+
+fn is_injected<T>(res: Result<T>) -> bool {
+    match res {
+        Err(e) => matches!(e, KvsError::InjectedFault),
+        Ok(_) => false,
+    }
+}
+
+// Should fail only the operations and keys a rule matches
+#[test]
+fn error_by_operation_and_key() -> Result<()> {
+    let rule = FaultRule::new()
+        .ops(&[Operation::Set, Operation::Remove])
+        .keys("user:*")
+        .error_rate(1.0);
+    let store = FaultyEngine::new(MemStore::new(), 1).with_rule(rule);
+
+    assert!(is_injected(
+        store.set("user:1".to_owned(), "value1".to_owned())
+    ));
+    assert_eq!(store.get("user:1".to_owned())?, None);
+    store.set("group:1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("group:1".to_owned())?, Some("value1".to_owned()));
+
+    store
+        .inner()
+        .set("user:1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("user:1".to_owned())?, Some("value1".to_owned()));
+    assert!(is_injected(store.remove("user:1".to_owned())));
+    store.remove("group:1".to_owned())?;
+
+    Ok(())
+}
+
+// Should draw the same faults from the same seed
+#[test]
+fn reproducible() -> Result<()> {
+    let outcomes = |seed| {
+        let store =
+            FaultyEngine::new(MemStore::new(), seed).with_rule(FaultRule::new().error_rate(0.5));
+        (0..100)
+            .map(|i| store.set(format!("key{}", i), "value".to_owned()).is_ok())
+            .collect::<Vec<bool>>()
+    };
+
+    let first = outcomes(42);
+    assert_eq!(first, outcomes(42));
+    assert_ne!(first, outcomes(43));
+    assert!(first.contains(&true) && first.contains(&false));
+    Ok(())
+}
+
+// Should store half of the value and report a failure on a partial write
+#[test]
+fn partial_write() -> Result<()> {
+    let rule = FaultRule::new().partial_write_rate(1.0);
+    let store = FaultyEngine::new(MemStore::new(), 1).with_rule(rule);
+
+    assert!(is_injected(
+        store.set("key1".to_owned(), "value1".to_owned())
+    ));
+    assert_eq!(store.get("key1".to_owned())?, Some("val".to_owned()));
+    Ok(())
+}
+
+// Should delay matching operations
+#[test]
+fn latency() -> Result<()> {
+    let rule = FaultRule::new()
+        .ops(&[Operation::Get])
+        .latency(Duration::from_millis(50));
+    let store = FaultyEngine::new(MemStore::new(), 1).with_rule(rule);
+
+    let start = Instant::now();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(start.elapsed() < Duration::from_millis(50));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    Ok(())
+}
+
+// Should parse the rules given to `kvs-server --inject-fault`
+#[test]
+fn parse_rule() {
+    let rule: FaultRule = "ops=set|rm,keys=user*,error=0.1,partial=0.2,latency=20ms"
+        .parse()
+        .unwrap();
+    let expected = FaultRule::new()
+        .ops(&[Operation::Set, Operation::Remove])
+        .keys("user*")
+        .error_rate(0.1)
+        .partial_write_rate(0.2)
+        .latency(Duration::from_millis(20));
+    assert_eq!(rule, expected);
+
+    assert!("error".parse::<FaultRule>().is_err());
+    assert!("ops=put".parse::<FaultRule>().is_err());
+    assert!("latency=20".parse::<FaultRule>().is_err());
+}
@@ -0,0 +1,238 @@
+use kvs::engine::{FaultLayer, FaultRule, KvStore, KvsEngine, LimitsLayer};
+use kvs::err::Result;
+use kvs::network::http::HttpServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+/// Start an HTTP server on a free port, serving until the test process exits. Keys starting
+/// with `broken` always fail.
+fn start_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let faults = FaultLayer::new(vec![FaultRule::new().keys("broken*").error_rate(1.0)], 0);
+    let store = KvStore::open(temp_dir.path())?
+        .layer(faults)
+        .layer(LimitsLayer::new().max_value_size(16));
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || HttpServer::new(store, listener, pool).do_loop().unwrap());
+    Ok(addr)
+}
+
+/// A minimal HTTP/1.1 client, keeping its connection open between requests.
+struct HttpClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl HttpClient {
+    fn connect(addr: SocketAddr) -> Result<HttpClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(HttpClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn send(&mut self, method: &str, path: &str, body: Option<Value>) -> Result<()> {
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        write!(
+            self.writer,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )?;
+        Ok(())
+    }
+
+    /// Read a response: its status and its JSON body, or `Null` without one.
+    fn recv(&mut self) -> Result<(u16, Value)> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut len = 0;
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line)?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                len = value.parse().unwrap();
+            }
+        }
+        let mut body = vec![0u8; len];
+        self.reader.read_exact(&mut body)?;
+        if body.is_empty() {
+            return Ok((status, Value::Null));
+        }
+        Ok((status, serde_json::from_slice(&body)?))
+    }
+
+    fn call(&mut self, method: &str, path: &str, body: Option<Value>) -> Result<(u16, Value)> {
+        self.send(method, path, body)?;
+        self.recv()
+    }
+}
+
+// Keys should be set, read and removed through their resource
+#[test]
+fn http_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = HttpClient::connect(start_server(&temp_dir)?)?;
+
+    assert_eq!(
+        client.call("PUT", "/keys/key1", Some(json!({"value": "value1"})))?,
+        (204, Value::Null)
+    );
+    assert_eq!(
+        client.call("GET", "/keys/key1", None)?,
+        (200, json!({"key": "key1", "value": "value1"}))
+    );
+    assert_eq!(
+        client.call("PUT", "/keys/a%2Fb%20c", Some(json!({"value": "v"})))?,
+        (204, Value::Null)
+    );
+    assert_eq!(
+        client.call("GET", "/keys/a%2Fb%20c", None)?,
+        (200, json!({"key": "a/b c", "value": "v"}))
+    );
+    assert_eq!(
+        client.call("DELETE", "/keys/key1", None)?,
+        (204, Value::Null)
+    );
+    assert_eq!(
+        client.call("GET", "/keys/key1", None)?,
+        (
+            404,
+            json!({"code": "KeyNotFound", "message": "Key not found"})
+        )
+    );
+    assert_eq!(client.call("DELETE", "/keys/key1", None)?.0, 404);
+    Ok(())
+}
+
+// Keys should be listed by prefix, in order
+#[test]
+fn http_list() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = HttpClient::connect(start_server(&temp_dir)?)?;
+    for key in &["user2", "user1", "other"] {
+        let path = format!("/keys/{}", key);
+        assert_eq!(
+            client.call("PUT", &path, Some(json!({"value": key})))?.0,
+            204
+        );
+    }
+
+    assert_eq!(
+        client.call("GET", "/keys?prefix=user", None)?,
+        (
+            200,
+            json!([
+                {"key": "user1", "value": "user1"},
+                {"key": "user2", "value": "user2"},
+            ])
+        )
+    );
+    let (status, all) = client.call("GET", "/keys", None)?;
+    assert_eq!(status, 200);
+    assert_eq!(all.as_array().unwrap().len(), 3);
+    Ok(())
+}
+
+// Batch requests should answer the outcome for each key in order
+#[test]
+fn http_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = HttpClient::connect(start_server(&temp_dir)?)?;
+
+    let body = json!([
+        {"key": "key1", "value": "value1"},
+        {"key": "broken1", "value": "value"},
+        {"key": "key2", "value": "value2"},
+    ]);
+    let (status, results) = client.call("POST", "/batch/put", Some(body))?;
+    assert_eq!(status, 200);
+    assert_eq!(results[0], json!({"key": "key1", "status": 204}));
+    assert_eq!(results[1]["status"], 500);
+    assert_eq!(results[1]["error"]["code"], "Internal");
+    assert_eq!(results[2], json!({"key": "key2", "status": 204}));
+
+    assert_eq!(
+        client.call("POST", "/batch/get", Some(json!(["key2", "key3", "key1"])))?,
+        (
+            200,
+            json!([
+                {"key": "key2", "status": 200, "value": "value2"},
+                {
+                    "key": "key3",
+                    "status": 404,
+                    "error": {"code": "KeyNotFound", "message": "Key not found"}
+                },
+                {"key": "key1", "status": 200, "value": "value1"},
+            ])
+        )
+    );
+    let (_, results) = client.call("POST", "/batch/delete", Some(json!(["key1", "key3"])))?;
+    assert_eq!(results[0]["status"], 204);
+    assert_eq!(results[1]["status"], 404);
+    Ok(())
+}
+
+// Failures should be answered with their status code, keeping the connection open
+#[test]
+fn http_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = HttpClient::connect(start_server(&temp_dir)?)?;
+
+    let (status, body) = client.call("GET", "/keys/broken", None)?;
+    assert_eq!(status, 500);
+    assert_eq!(body["code"], "Internal");
+    assert_eq!(
+        client
+            .call(
+                "PUT",
+                "/keys/key",
+                Some(json!({"value": "a value longer than allowed"}))
+            )?
+            .0,
+        413
+    );
+    assert_eq!(
+        client.call("PUT", "/keys/key", Some(json!("value")))?.0,
+        400
+    );
+    assert_eq!(client.call("POST", "/keys/key", None)?.0, 405);
+    assert_eq!(client.call("GET", "/nothing", None)?.0, 404);
+
+    // pipelined requests are answered in order
+    client.send("PUT", "/keys/key", Some(json!({"value": "value"})))?;
+    client.send("GET", "/keys/key", None)?;
+    assert_eq!(client.recv()?.0, 204);
+    assert_eq!(
+        client.recv()?,
+        (200, json!({"key": "key", "value": "value"}))
+    );
+    Ok(())
+}
+
+// A malformed request should be answered with 400 before the connection is closed
+#[test]
+fn http_bad_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = HttpClient::connect(start_server(&temp_dir)?)?;
+
+    client.writer.write_all(b"GARBAGE\r\n\r\n")?;
+    assert_eq!(client.recv()?.0, 400);
+    let mut rest = Vec::new();
+    client.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
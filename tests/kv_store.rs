@@ -1,15 +1,93 @@
-use kvs::engine::{KvStore, KvsEngine};
-use kvs::err::Result;
+use kvs::engine::{BTreeStore, KvStore, KvStoreOptions, KvsEngine, LsmStoreOptions, SyncPolicy};
+use kvs::err::{KvsError, Result};
+use std::fs;
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+/// Run scenarios against an engine, opened at a path by the given function, as tests of a
+/// module named after the engine.
+macro_rules! engine_tests {
+    ($engine:ident, $open:expr, [$($scenario:ident),* $(,)?]) => {
+        mod $engine {
+            use super::*;
+
+            $(
+                #[test]
+                fn $scenario() -> Result<()> {
+                    super::$scenario($open)
+                }
+            )*
+        }
+    };
+}
+
+engine_tests!(
+    kv_store,
+    |path: &Path| KvStore::open(path),
+    [
+        get_stored_value,
+        overwrite_value,
+        get_non_existent_value,
+        remove_non_existent_key,
+        remove_key,
+        remove_key_twice,
+        tombstones,
+        scan,
+        compaction,
+        concurrent_set,
+        concurrent_get,
+    ]
+);
+
+// small enough for the scenarios to flush and compact
+engine_tests!(
+    lsm_store,
+    |path: &Path| {
+        LsmStoreOptions::new()
+            .memtable_size(512)
+            .table_size(1024)
+            .level0_tables(2)
+            .open(path)
+    },
+    [
+        get_stored_value,
+        overwrite_value,
+        get_non_existent_value,
+        remove_non_existent_key,
+        remove_key,
+        remove_key_twice,
+        tombstones,
+        scan,
+        compaction,
+        concurrent_set,
+        concurrent_get,
+    ]
+);
+
+// removed keys leave nothing behind, so there are no tombstones to count
+engine_tests!(
+    btree_store,
+    |path: &Path| BTreeStore::open(path),
+    [
+        get_stored_value,
+        overwrite_value,
+        get_non_existent_value,
+        remove_non_existent_key,
+        remove_key,
+        remove_key_twice,
+        scan,
+        concurrent_set,
+        concurrent_get,
+    ]
+);
+
 // Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
+fn get_stored_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -19,7 +97,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,10 +105,9 @@ fn get_stored_value() -> Result<()> {
 }
 
 // Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
+fn overwrite_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -39,7 +116,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,46 +125,99 @@ fn overwrite_value() -> Result<()> {
 }
 
 // Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
+fn get_non_existent_value<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
 }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
+fn remove_non_existent_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
 
-#[test]
-fn remove_key() -> Result<()> {
+fn remove_key<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
     Ok(())
 }
 
+// Should fail to remove a key twice, also after reopening
+fn remove_key_twice<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::KeyNotFound));
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::KeyNotFound));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should keep a removal across reopening, and count it until the key is set again
+fn tombstones<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.stats()?.tombstones, 1);
+
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.stats()?.tombstones, 0);
+    store.remove("key1".to_owned())?;
+    Ok(())
+}
+
+// Should list keys with a prefix in order, also after reopening
+fn scan<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    for key in &["b2", "a1", "b1", "c1", "b3"] {
+        store.set((*key).to_owned(), format!("value-{}", key))?;
+    }
+    store.remove("b3".to_owned())?;
+
+    let expected = vec![
+        ("b1".to_owned(), "value-b1".to_owned()),
+        ("b2".to_owned(), "value-b2".to_owned()),
+    ];
+    assert_eq!(store.scan("b".to_owned())?, expected);
+    assert_eq!(store.scan("".to_owned())?.len(), 4);
+
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.scan("b".to_owned())?, expected);
+    assert_eq!(store.scan("d".to_owned())?, vec![]);
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
-#[test]
-fn compaction() -> Result<()> {
+fn compaction<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -117,7 +247,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let store = KvStore::open(temp_dir.path())?;
+        let store = open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -128,10 +258,9 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
-#[test]
-fn concurrent_set() -> Result<()> {
+fn concurrent_set<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
     for i in 0..1000 {
         let store = store.clone();
@@ -151,7 +280,7 @@ fn concurrent_set() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
@@ -159,10 +288,9 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
-#[test]
-fn concurrent_get() -> Result<()> {
+fn concurrent_get<E: KvsEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -189,7 +317,7 @@ fn concurrent_get() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     let mut handles = Vec::new();
     for thread_id in 0..100 {
         let store = store.clone();
@@ -210,3 +338,264 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Should count live keys and bytes left behind by overwrites and removals
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 0);
+    assert_eq!(stats.dead_bytes, 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "kvs");
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.dead_bytes, 0);
+    // beyond the records, files only hold a header per segment
+    let overhead = stats.file_size - stats.live_bytes;
+    assert!(overhead < 64);
+
+    // An overwrite makes exactly the old record stale
+    let record_len = stats.live_bytes / 2;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.dead_bytes, record_len);
+
+    // A removal makes both the old record and its own record stale
+    let before = stats.file_size;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.tombstones, 1);
+    assert_eq!(stats.live_bytes, record_len);
+    assert_eq!(stats.dead_bytes, stats.file_size - overhead - record_len);
+    assert!(stats.file_size > before);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction, None);
+
+    // Open from disk again and check the index is rebuilt with the same numbers
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.live_keys, stats.live_keys);
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+    assert_eq!(reopened.dead_bytes, stats.dead_bytes);
+
+    Ok(())
+}
+
+// Should split the log into segments and compact according to the options
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction_threshold(16 * 1024)
+        .compaction_ratio(0.9)
+        .sync(SyncPolicy::Always)
+        .cache_size(1024);
+    let store = options.open(temp_dir.path())?;
+
+    for iter in 0..5 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some(format!("{}", iter)));
+    }
+    let segments = WalkDir::new(temp_dir.path())
+        .min_depth(1)
+        .into_iter()
+        .count();
+    assert!(segments > 1);
+
+    let stats = store.stats()?;
+    assert_eq!(stats.compactions, 0);
+    assert!(stats.threshold >= 16 * 1024);
+    assert!(stats.dead_bytes < stats.threshold);
+
+    for _ in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), "value".to_owned())?;
+        }
+    }
+    assert!(store.stats()?.compactions > 0);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some("value".to_owned())
+        );
+    }
+
+    Ok(())
+}
+
+// A record failing its checksum before valid ones should fail the open, not truncate them
+#[test]
+fn corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut records = Vec::new();
+    let reports = KvStore::inspect(temp_dir.path(), |r| records.push(r))?;
+    let path = &reports[0].path;
+    let mut bytes = fs::read(path)?;
+    let first = &records[0];
+    bytes[(first.offset + first.len - 2) as usize] ^= 0xff;
+    fs::write(path, &bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset }) => assert_eq!(offset, first.offset),
+        other => panic!("expected a corruption, got {:?}", other.err()),
+    }
+    assert_eq!(fs::read(path)?, bytes);
+    Ok(())
+}
+
+// A last record failing its checksum was torn by a crash, and should be dropped on open
+#[test]
+fn corrupt_last_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let mut records = Vec::new();
+    let reports = KvStore::inspect(temp_dir.path(), |r| records.push(r))?;
+    let path = &reports[0].path;
+    let mut bytes = fs::read(path)?;
+    let last = &records[1];
+    bytes[(last.offset + last.len - 2) as usize] ^= 0xff;
+    fs::write(path, &bytes)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should reject writes and leave the files untouched in read-only mode
+#[test]
+fn read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().read_only(true);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.set("key1".to_owned(), "value1".to_owned()).is_err());
+    assert_eq!(
+        WalkDir::new(temp_dir.path())
+            .min_depth(1)
+            .into_iter()
+            .count(),
+        0
+    );
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.remove("key1".to_owned()).is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// A read-only store should follow the appends, rotations and compactions of a writer
+#[test]
+fn read_only_follows_writer() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let writer = KvStoreOptions::new()
+        .segment_size(1024)
+        .compaction_threshold(4 * 1024)
+        .open(temp_dir.path())?;
+    writer.set("key1".to_owned(), "value1".to_owned())?;
+    let reader = KvStoreOptions::new()
+        .read_only(true)
+        .cache_size(1024)
+        .open(temp_dir.path())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    writer.set("key1".to_owned(), "value2".to_owned())?;
+    writer.set("key2".to_owned(), "value3".to_owned())?;
+    assert_eq!(reader.get("key1".to_owned())?, Some("value2".to_owned()));
+    writer.remove("key2".to_owned())?;
+    assert_eq!(reader.get("key2".to_owned())?, None);
+
+    for iter in 0..20 {
+        for key_id in 0..50 {
+            let key = format!("key{}", key_id);
+            writer.set(key.clone(), format!("{}", iter))?;
+            assert_eq!(reader.get(key)?, Some(format!("{}", iter)));
+        }
+    }
+    assert!(writer.stats()?.compactions > 0);
+    assert_eq!(reader.scan("key4".to_owned())?.len(), 11);
+    assert_eq!(reader.stats()?.live_keys, 50);
+
+    // the reader lets go of compacted segments
+    let log_size: u64 = WalkDir::new(temp_dir.path())
+        .min_depth(1)
+        .into_iter()
+        .map(|entry| entry.unwrap().metadata().unwrap().len())
+        .sum();
+    assert_eq!(reader.stats()?.file_size, log_size);
+
+    Ok(())
+}
+
+// Reads through memory maps should see the same values across rotations, compactions and
+// reopening
+#[test]
+fn mmap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .mmap(true)
+        .segment_size(1024)
+        .compaction_threshold(8 * 1024);
+    let store = options.open(temp_dir.path())?;
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+        for key_id in 0..100 {
+            let value = store.get(format!("key{}", key_id))?;
+            assert_eq!(value, Some(format!("value{}-{}", key_id, iter)));
+        }
+    }
+    assert!(store.stats()?.compactions > 0);
+    drop(store);
+
+    let store = options.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let value = store.get(format!("key{}", key_id))?;
+        assert_eq!(value, Some(format!("value{}-9", key_id)));
+    }
+    assert_eq!(store.scan("key9".to_owned())?.len(), 11);
+
+    Ok(())
+}
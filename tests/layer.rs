@@ -0,0 +1,104 @@
+use kvs::engine::{
+    FaultLayer, FaultRule, KvStore, KvsEngine, LimitsLayer, MemStore, MetricsLayer, Operation,
+    SlowLogLayer,
+};
+use kvs::err::{KvsError, Result};
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn limits_on<E: KvsEngine>(engine: E) -> Result<()> {
+    let store = engine.layer(LimitsLayer::new().max_key_size(4).max_value_size(6));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let err = store
+        .set("key12".to_owned(), "value1".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvsError::LimitExceeded));
+    let err = store
+        .set("key1".to_owned(), "value12".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvsError::LimitExceeded));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should reject oversized keys and values around any engine
+#[test]
+fn limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    limits_on(KvStore::open(temp_dir.path())?)?;
+    limits_on(MemStore::new())
+}
+
+// Should reject writes in read-only mode
+#[test]
+fn read_only() -> Result<()> {
+    let inner = MemStore::new();
+    inner.set("key1".to_owned(), "value1".to_owned())?;
+    let store = inner.layer(LimitsLayer::new().read_only(true));
+
+    let err = store
+        .set("key1".to_owned(), "value2".to_owned())
+        .unwrap_err();
+    assert!(matches!(err, KvsError::ReadOnly));
+    let err = store.remove("key1".to_owned()).unwrap_err();
+    assert!(matches!(err, KvsError::ReadOnly));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Should count operations and errors, also through stats
+#[test]
+fn metrics() -> Result<()> {
+    let store = MemStore::new().layer(MetricsLayer::new());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.get("key1".to_owned())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    let metrics = store.metrics();
+    assert_eq!(metrics[&Operation::Set].count, 2);
+    assert_eq!(metrics[&Operation::Get].count, 1);
+    assert_eq!(metrics[&Operation::Remove].count, 2);
+    assert_eq!(metrics[&Operation::Remove].errors, 1);
+    assert!(metrics[&Operation::Set].max <= metrics[&Operation::Set].total);
+
+    let stats = store.stats()?;
+    assert_eq!(stats.operations[&Operation::Set].count, 2);
+    assert_eq!(stats.operations[&Operation::Stats].count, 1);
+    Ok(())
+}
+
+// Should stack layers, the last one applied being the outermost
+#[test]
+fn stack() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let faults = FaultLayer::new(
+        vec![FaultRule::new()
+            .ops(&[Operation::Get])
+            .keys("bad*")
+            .error_rate(1.0)],
+        7,
+    );
+    let store = KvStore::open(temp_dir.path())?
+        .layer(faults)
+        .layer(LimitsLayer::new().max_value_size(10))
+        .layer(SlowLogLayer::new(Duration::from_secs(1)))
+        .layer(MetricsLayer::new());
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("bad1".to_owned(), "value1".to_owned())?;
+    assert!(store
+        .set("key2".to_owned(), "a long value".to_owned())
+        .is_err());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(store.get("bad1".to_owned()).is_err());
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "kvs");
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.operations[&Operation::Set].errors, 1);
+    assert_eq!(stats.operations[&Operation::Get].errors, 1);
+    Ok(())
+}
@@ -0,0 +1,253 @@
+use kvs::engine::{KvsEngine, LsmStore, LsmStoreOptions, SyncPolicy};
+use kvs::err::{KvsError, Result};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use tempfile::TempDir;
+use walkdir::WalkDir;
+
+/// Options small enough for a few hundred writes to flush and compact several times.
+fn small_options() -> LsmStoreOptions {
+    LsmStoreOptions::new()
+        .memtable_size(512)
+        .block_size(128)
+        .table_size(1024)
+        .level0_tables(2)
+        .level_size(2048)
+}
+
+fn count_files(dir: &TempDir, ext: &str) -> usize {
+    WalkDir::new(dir.path())
+        .min_depth(1)
+        .into_iter()
+        .filter(|e| {
+            e.as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|e| e == ext)
+        })
+        .count()
+}
+
+// Should overwrite existent value, in the memtable and in tables
+#[test]
+fn overwrite_in_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.flush()?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+#[test]
+fn get_non_existent_in_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key0".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should hide a value held by a table behind a removal
+#[test]
+fn remove_from_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    store.flush()?;
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+// Should list keys with a prefix in order, merging the memtable and tables
+#[test]
+fn scan_merges_tables() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+    for key in &["b2", "a1", "b3"] {
+        store.set((*key).to_owned(), format!("value-{}", key))?;
+    }
+    store.flush()?;
+    for key in &["b1", "c1"] {
+        store.set((*key).to_owned(), format!("value-{}", key))?;
+    }
+    store.remove("b3".to_owned())?;
+
+    let expected = vec![
+        ("b1".to_owned(), "value-b1".to_owned()),
+        ("b2".to_owned(), "value-b2".to_owned()),
+    ];
+    assert_eq!(store.scan("b".to_owned())?, expected);
+    assert_eq!(store.scan("".to_owned())?.len(), 4);
+
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.scan("b".to_owned())?, expected);
+    assert_eq!(store.scan("d".to_owned())?, vec![]);
+    Ok(())
+}
+
+// Should count live keys and the bytes of overwritten and removed entries
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "lsm");
+    assert_eq!(stats.live_keys, 0);
+    assert_eq!(stats.dead_bytes, 0);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.flush()?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 2);
+    assert_eq!(stats.live_bytes, 20);
+    assert_eq!(stats.dead_bytes, 0);
+
+    // The overwritten value stays in its table until compaction
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.live_keys, 1);
+    assert_eq!(stats.tombstones, 1);
+    assert_eq!(stats.live_bytes, 10);
+    assert_eq!(stats.dead_bytes, 24);
+    assert!(stats.file_size > 0);
+
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    let reopened = store.stats()?;
+    assert_eq!(reopened.live_keys, stats.live_keys);
+    assert_eq!(reopened.live_bytes, stats.live_bytes);
+    assert_eq!(reopened.dead_bytes, stats.dead_bytes);
+
+    Ok(())
+}
+
+// Should flush to tables and merge them down the levels according to the options
+#[test]
+fn open_with_options() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = small_options().sync(SyncPolicy::Always);
+    let store = options.open(temp_dir.path())?;
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+        if iter % 3 == 0 {
+            for key_id in (0..100).step_by(7) {
+                store.remove(format!("key{}", key_id))?;
+            }
+        }
+        assert_eq!(store.get("key1".to_owned())?, Some(format!("{}", iter)));
+    }
+    let stats = store.stats()?;
+    assert!(stats.compactions > 0);
+    assert_eq!(stats.live_keys, 100);
+    assert!(count_files(&temp_dir, "sst") > 1);
+    assert_eq!(count_files(&temp_dir, "wal"), 1);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = options.open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("19".to_owned()));
+    }
+    assert_eq!(store.scan("key9".to_owned())?.len(), 11);
+
+    Ok(())
+}
+
+// Should drop a write cut short at the end of the log, and tables no manifest lists
+#[test]
+fn recover() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.flush()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let wal = WalkDir::new(temp_dir.path())
+        .min_depth(1)
+        .into_iter()
+        .map(|e| e.unwrap().into_path())
+        .find(|p| p.extension().is_some_and(|e| e == "wal"))
+        .expect("no log written");
+    OpenOptions::new()
+        .append(true)
+        .open(&wal)?
+        .write_all(&[0, 0, 0, 0, 0, 0, 0, 40, 1, 2])?;
+    fs::write(temp_dir.path().join("999.sst"), b"left over")?;
+
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!temp_dir.path().join("999.sst").exists());
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = LsmStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should fail to open a log corrupt before its last record, leaving it untouched
+#[test]
+fn corrupt_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = LsmStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let wal = WalkDir::new(temp_dir.path())
+        .min_depth(1)
+        .into_iter()
+        .map(|e| e.unwrap().into_path())
+        .find(|p| p.extension().is_some_and(|e| e == "wal"))
+        .expect("no log written");
+    let mut bytes = fs::read(&wal)?;
+    // a byte of the value of the first record
+    bytes[30] ^= 0xff;
+    fs::write(&wal, &bytes)?;
+
+    assert!(matches!(
+        LsmStore::open(temp_dir.path()),
+        Err(KvsError::Corruption { offset: 0 })
+    ));
+    assert_eq!(fs::read(&wal)?, bytes);
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use kvs::engine::{BatchOp, KvsEngine, MemStore};
+use kvs::err::Result;
+use std::thread;
+use tempfile::TempDir;
+
+// Should get, overwrite and remove values
+#[test]
+fn get_set_remove() -> Result<()> {
+    let store = MemStore::new();
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.remove("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.remove("key1".to_owned()).is_err());
+
+    Ok(())
+}
+
+// Should list keys with a prefix in order
+#[test]
+fn scan() -> Result<()> {
+    let store = MemStore::new();
+    for key in &["b2", "a1", "b1", "c1", "b3"] {
+        store.set((*key).to_owned(), format!("value-{}", key))?;
+    }
+    store.remove("b3".to_owned())?;
+
+    let keys: Vec<String> = store
+        .scan("b".to_owned())?
+        .into_iter()
+        .map(|(k, _)| k)
+        .collect();
+    assert_eq!(keys, vec!["b1", "b2"]);
+    assert_eq!(store.scan("".to_owned())?.len(), 4);
+    assert_eq!(
+        store.scan("c".to_owned())?,
+        vec![("c1".to_owned(), "value-c1".to_owned())]
+    );
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "memory");
+    assert_eq!(stats.live_keys, 4);
+    Ok(())
+}
+
+// Should apply the writes of a batch in order
+#[test]
+fn write_batch() -> Result<()> {
+    let store = MemStore::new();
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    store.write_batch(vec![
+        BatchOp::Set("key2".to_owned(), "value2".to_owned()),
+        BatchOp::Remove("key1".to_owned()),
+        BatchOp::Remove("missing".to_owned()),
+        BatchOp::Set("key3".to_owned(), "value3".to_owned()),
+        BatchOp::Set("key3".to_owned(), "value4".to_owned()),
+    ])?;
+    assert_eq!(
+        store.scan("".to_owned())?,
+        vec![
+            ("key2".to_owned(), "value2".to_owned()),
+            ("key3".to_owned(), "value4".to_owned()),
+        ]
+    );
+    Ok(())
+}
+
+// Should share data between clones
+#[test]
+fn concurrent_set() -> Result<()> {
+    let store = MemStore::new();
+    let handles: Vec<_> = (0..100)
+        .map(|i| {
+            let store = store.clone();
+            thread::spawn(move || {
+                store
+                    .set(format!("key{}", i), format!("value{}", i))
+                    .unwrap();
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    for i in 0..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    Ok(())
+}
+
+// Should write a snapshot when the last clone is dropped and load it again
+#[test]
+fn snapshot_on_drop() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("snapshot");
+
+    let store = MemStore::with_snapshot(&path)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let clone = store.clone();
+    drop(store);
+    assert!(!path.exists());
+    drop(clone);
+    assert!(path.exists());
+
+    let store = MemStore::with_snapshot(&path)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use kvs::engine::{KvStore, KvsEngine};
+use kvs::err::Result;
+use kvs::network::memcache::{ItemLayer, MemcacheServer};
+use kvs::network::resp::RespServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+/// Start a memcache server on a free port over a store, serving until the test process exits.
+fn start_server(store: KvStore) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        MemcacheServer::new(store, listener, pool)
+            .do_loop()
+            .unwrap()
+    });
+    Ok(addr)
+}
+
+/// Send a RESP command and read the given number of lines answering it.
+fn resp_call(
+    stream: &mut BufReader<TcpStream>,
+    args: &[&str],
+    lines: usize,
+) -> Result<Vec<String>> {
+    let mut command = format!("*{}\r\n", args.len());
+    for arg in args {
+        command += &format!("${}\r\n{}\r\n", arg.len(), arg);
+    }
+    stream.get_mut().write_all(command.as_bytes())?;
+    let mut reply = Vec::new();
+    for _ in 0..lines {
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        reply.push(line.trim_end_matches("\r\n").to_owned());
+    }
+    Ok(reply)
+}
+
+/// A minimal memcached text protocol client.
+struct MemcacheClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl MemcacheClient {
+    fn connect(addr: SocketAddr) -> Result<MemcacheClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(MemcacheClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn send(&mut self, command: &str) -> Result<()> {
+        write!(self.writer, "{}\r\n", command)?;
+        Ok(())
+    }
+
+    fn line(&mut self) -> Result<String> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        Ok(line.trim_end_matches("\r\n").to_owned())
+    }
+
+    /// Send a command and read the line answering it.
+    fn call(&mut self, command: &str) -> Result<String> {
+        self.send(command)?;
+        self.line()
+    }
+
+    /// Send a storage command with its data block and read the line answering it.
+    fn store(&mut self, command: &str, data: &str) -> Result<String> {
+        self.send(&format!("{} {}", command, data.len()))?;
+        self.call(data)
+    }
+
+    /// Send a retrieval command and read the lines answering it, up to `END`.
+    fn retrieve(&mut self, command: &str) -> Result<Vec<String>> {
+        self.send(command)?;
+        let mut lines = Vec::new();
+        loop {
+            let line = self.line()?;
+            if line == "END" {
+                return Ok(lines);
+            }
+            lines.push(line);
+        }
+    }
+
+    /// The CAS token of a key.
+    fn cas(&mut self, key: &str) -> Result<String> {
+        let lines = self.retrieve(&format!("gets {}", key))?;
+        Ok(lines[0].rsplit(' ').next().unwrap().to_owned())
+    }
+}
+
+// Items should be stored and retrieved with their flags
+#[test]
+fn memcache_storage() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("native".to_owned(), "value".to_owned())?;
+    let mut client = MemcacheClient::connect(start_server(store.clone())?)?;
+
+    assert_eq!(client.store("set key1 42 0", "value1")?, "STORED");
+    // other protocols see the data alone
+    let items = store.clone().layer(ItemLayer::new());
+    assert_eq!(items.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(client.store("set key2 0 0", "a b\r\nc")?, "STORED");
+    assert_eq!(
+        client.retrieve("get key1 missing key2")?,
+        ["VALUE key1 42 6", "value1", "VALUE key2 0 6", "a b", "c"]
+    );
+    // values set through another protocol have no flags
+    assert_eq!(
+        client.retrieve("get native")?,
+        ["VALUE native 0 5", "value"]
+    );
+    // overwriting an item through another protocol drops its flags
+    store.set("key1".to_owned(), "other".to_owned())?;
+    assert_eq!(client.retrieve("get key1")?, ["VALUE key1 0 5", "other"]);
+
+    assert_eq!(client.store("add key1 0 0", "other")?, "NOT_STORED");
+    assert_eq!(client.store("add key3 0 0", "value3")?, "STORED");
+    assert_eq!(client.store("replace missing 0 0", "value")?, "NOT_STORED");
+    assert_eq!(client.store("replace key3 7 0", "value4")?, "STORED");
+    assert_eq!(client.retrieve("get key3")?, ["VALUE key3 7 6", "value4"]);
+
+    assert_eq!(client.call("delete key3")?, "DELETED");
+    assert_eq!(client.call("delete key3")?, "NOT_FOUND");
+    assert!(client.retrieve("get key3")?.is_empty());
+    Ok(())
+}
+
+// A CAS write should only succeed if the item is unchanged since its token was read
+#[test]
+fn memcache_cas() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = MemcacheClient::connect(start_server(KvStore::open(temp_dir.path())?)?)?;
+
+    assert_eq!(client.store("set key 1 0", "value1")?, "STORED");
+    let cas = client.cas("key")?;
+    assert_eq!(
+        client.retrieve("gets key")?,
+        [format!("VALUE key 1 6 {}", cas), "value1".to_owned()]
+    );
+    assert_eq!(client.store("set key 1 0", "value2")?, "STORED");
+    client.send(&format!("cas key 2 0 6 {}", cas))?;
+    assert_eq!(client.call("value3")?, "EXISTS");
+    let cas = client.cas("key")?;
+    client.send(&format!("cas key 2 0 6 {}", cas))?;
+    assert_eq!(client.call("value3")?, "STORED");
+    assert_eq!(client.retrieve("get key")?, ["VALUE key 2 6", "value3"]);
+    client.send("cas missing 0 0 5 1")?;
+    assert_eq!(client.call("value")?, "NOT_FOUND");
+    Ok(())
+}
+
+// incr and decr should change numbers in place, keeping their flags
+#[test]
+fn memcache_incr_decr() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = MemcacheClient::connect(start_server(KvStore::open(temp_dir.path())?)?)?;
+
+    assert_eq!(client.store("set counter 5 0", "10")?, "STORED");
+    assert_eq!(client.call("incr counter 5")?, "15");
+    assert_eq!(client.call("decr counter 20")?, "0");
+    assert_eq!(
+        client.store("set counter 5 0", "18446744073709551615")?,
+        "STORED"
+    );
+    assert_eq!(client.call("incr counter 2")?, "1");
+    assert_eq!(client.retrieve("get counter")?, ["VALUE counter 5 1", "1"]);
+    assert_eq!(client.call("incr missing 1")?, "NOT_FOUND");
+    assert_eq!(client.store("set text 0 0", "abc")?, "STORED");
+    assert_eq!(
+        client.call("incr text 1")?,
+        "CLIENT_ERROR cannot increment or decrement non-numeric value"
+    );
+    Ok(())
+}
+
+// Other protocols should see the data of items, not expired ones, and remove items whole
+#[test]
+fn memcache_other_protocols() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut client = MemcacheClient::connect(start_server(store.clone())?)?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let resp_addr = listener.local_addr()?;
+    let items = store.layer(ItemLayer::new());
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || RespServer::new(items, listener, pool).do_loop().unwrap());
+    let mut resp = BufReader::new(TcpStream::connect(resp_addr)?);
+
+    assert_eq!(client.store("set key1 42 0", "value1")?, "STORED");
+    assert_eq!(client.store("set key2 0 0", "value2")?, "STORED");
+    assert_eq!(client.store("set gone 0 -1", "value")?, "STORED");
+    assert_eq!(resp_call(&mut resp, &["GET", "key1"], 2)?, ["$6", "value1"]);
+    assert_eq!(resp_call(&mut resp, &["GET", "gone"], 1)?, ["$-1"]);
+    assert_eq!(
+        resp_call(&mut resp, &["SCAN", "0"], 8)?,
+        ["*2", "$1", "0", "*2", "$4", "key1", "$4", "key2"]
+    );
+
+    assert_eq!(resp_call(&mut resp, &["DEL", "key1"], 1)?, [":1"]);
+    assert!(client.retrieve("get key1")?.is_empty());
+    assert_eq!(client.store("add key1 0 0", "value3")?, "STORED");
+    Ok(())
+}
+
+// Items should expire after their expiry time
+#[test]
+fn memcache_exptime() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut client = MemcacheClient::connect(start_server(store.clone())?)?;
+
+    assert_eq!(client.store("set gone 0 -1", "value")?, "STORED");
+    assert!(client.retrieve("get gone")?.is_empty());
+    // the expired item is removed when read
+    assert_eq!(store.get("gone".to_owned())?, None);
+    assert_eq!(client.store("add gone 0 0", "value")?, "STORED");
+
+    assert_eq!(client.store("set later 0 1000", "value")?, "STORED");
+    assert_eq!(client.retrieve("get later")?.len(), 2);
+    Ok(())
+}
+
+// noreply, unknown commands and malformed data should be answered as memcached does
+#[test]
+fn memcache_protocol() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = MemcacheClient::connect(start_server(KvStore::open(temp_dir.path())?)?)?;
+
+    client.send("set quiet 0 0 5 noreply\r\nvalue")?;
+    client.send("delete missing noreply")?;
+    assert_eq!(client.retrieve("get quiet")?, ["VALUE quiet 0 5", "value"]);
+
+    assert_eq!(client.call("flush_all")?, "ERROR");
+    assert_eq!(
+        client.call("delete")?,
+        "CLIENT_ERROR bad command line format"
+    );
+    assert!(client.call("version")?.starts_with("VERSION "));
+
+    // a data block longer than announced can not be recovered from
+    client.send("set key 0 0 2\r\nvalue")?;
+    assert_eq!(
+        client.line()?,
+        "CLIENT_ERROR Protocol error: bad data chunk"
+    );
+    // the unread rest of the data may reset the connection rather than end it
+    let mut rest = Vec::new();
+    assert!(!matches!(client.reader.read_to_end(&mut rest), Ok(n) if n > 0));
+    Ok(())
+}
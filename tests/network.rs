@@ -0,0 +1,263 @@
+use kvs::engine::KvStore;
+use kvs::err::Result;
+use kvs::network::client::KvsClient;
+use kvs::network::protocol::{self, Frame, Handshake};
+use kvs::network::server::KvsServer;
+use kvs::network::{ErrorCode, Request, Response};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Start a server on a free port, serving until the test process exits.
+fn start_server(temp_dir: &TempDir, idle_timeout: Option<Duration>) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || {
+        KvsServer::new(store, listener, pool)
+            .idle_timeout(idle_timeout)
+            .do_loop()
+            .unwrap()
+    });
+    Ok(addr)
+}
+
+fn set(key: &str, value: &str) -> Request {
+    Request::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn get(key: &str) -> Request {
+    Request::Get {
+        key: key.to_owned(),
+    }
+}
+
+// A connection should carry many requests, each answered in turn
+#[test]
+fn persistent_connection() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+    for i in 0..100 {
+        let key = format!("key{}", i);
+        assert!(matches!(
+            client.do_request(&set(&key, &format!("value{}", i)))?,
+            Response::Success
+        ));
+        match other.do_request(&get(&key))? {
+            Response::Value(value) => assert_eq!(value, format!("value{}", i)),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+    let remove = Request::Remove {
+        key: "key0".to_owned(),
+    };
+    assert!(matches!(client.do_request(&remove)?, Response::Success));
+    assert!(matches!(client.do_request(&remove)?, Response::NotFound));
+    assert!(matches!(
+        client.do_request(&Request::Stats)?,
+        Response::Stats(_)
+    ));
+    Ok(())
+}
+
+// The server should close a connection left idle, and keep serving new ones
+#[test]
+fn idle_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, Some(Duration::from_millis(100)))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.do_request(&set("key1", "value1"))?,
+        Response::Success
+    ));
+    thread::sleep(Duration::from_millis(500));
+    assert!(client.do_request(&get("key1")).is_err());
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.do_request(&get("key1"))?,
+        Response::Value(_)
+    ));
+    Ok(())
+}
+
+// Clients of the framed and of the legacy JSON protocol should be served side by side
+#[test]
+fn legacy_json() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    let mut framed = KvsClient::connect(addr)?;
+    assert_eq!(
+        framed.handshake().map(|h| h.version),
+        Some(protocol::VERSION)
+    );
+    let mut json = KvsClient::connect_json(addr)?;
+    assert_eq!(json.handshake(), None);
+
+    assert!(matches!(
+        framed.do_request(&set("key1", "value1"))?,
+        Response::Success
+    ));
+    for _ in 0..3 {
+        match json.do_request(&get("key1"))? {
+            Response::Value(value) => assert_eq!(value, "value1"),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+    assert!(matches!(
+        json.do_request(&Request::Stats)?,
+        Response::Stats(_)
+    ));
+    Ok(())
+}
+
+// A frame holding no request should be answered with an error, keeping the connection
+#[test]
+fn bad_frame() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = Handshake {
+        version: protocol::VERSION,
+        features: 0,
+    };
+    hello.write_to(&mut stream)?;
+    assert_eq!(Handshake::read_from(&mut stream)?, hello);
+
+    let frame = Frame {
+        id: 7,
+        opcode: 0x7f,
+        payload: vec![1, 2, 3],
+    };
+    frame.write_to(&mut stream)?;
+    let resp = Frame::read_from(&mut stream)?.expect("connection closed");
+    assert_eq!(resp.id, 7);
+    match Response::from_frame(&resp)? {
+        Response::Error { code, .. } => assert_eq!(code, ErrorCode::Protocol),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+
+    get("key1").to_frame(8).write_to(&mut stream)?;
+    let resp = Frame::read_from(&mut stream)?.expect("connection closed");
+    assert_eq!(resp.id, 8);
+    assert!(matches!(Response::from_frame(&resp)?, Response::NotFound));
+    Ok(())
+}
+
+// A client speaking no version the server knows should be told so and disconnected
+#[test]
+fn version_mismatch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    Handshake {
+        version: 0,
+        features: 0,
+    }
+    .write_to(&mut stream)?;
+    assert_eq!(Handshake::read_from(&mut stream)?.version, 0);
+    stream.flush()?;
+    assert!(Frame::read_from(&mut stream)?.is_none());
+    Ok(())
+}
+
+// Pipelined requests should be answered in full, each response matched to its request
+#[test]
+fn pipelining() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    for mut client in [KvsClient::connect(addr)?, KvsClient::connect_json(addr)?] {
+        let sets: Vec<Request> = (0..2000)
+            .map(|i| set(&format!("key{}", i), &format!("value{}", i)))
+            .collect();
+        let resps = client.pipeline(&sets)?;
+        assert_eq!(resps.len(), 2000);
+        assert!(resps.iter().all(|resp| matches!(resp, Response::Success)));
+
+        let gets: Vec<Request> = (0..2000).map(|i| get(&format!("key{}", i))).collect();
+        for (i, resp) in client.pipeline(&gets)?.into_iter().enumerate() {
+            match resp {
+                Response::Value(value) => assert_eq!(value, format!("value{}", i)),
+                resp => panic!("unexpected response {:?}", resp),
+            }
+        }
+    }
+
+    // requests sent by hand are answered in order, by ID
+    let mut client = KvsClient::connect(addr)?;
+    assert_ne!(
+        client.handshake().map_or(0, |h| h.features) & protocol::FEATURE_PIPELINING,
+        0
+    );
+    let ids: Vec<u64> = (0..10)
+        .map(|i| client.send(&get(&format!("key{}", i))))
+        .collect::<Result<_>>()?;
+    for id in ids {
+        assert_eq!(client.recv()?.0, id);
+    }
+    Ok(())
+}
+
+// Requests on many keys should answer for each key in order, also through the fallback of
+// one request per key
+#[test]
+fn batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    for mut client in [KvsClient::connect(addr)?, KvsClient::connect_json(addr)?] {
+        let pairs: Vec<(String, String)> = (0..500)
+            .map(|i| (format!("key{}", i), format!("value{}", i)))
+            .collect();
+        let resps = client.mset(pairs)?;
+        assert_eq!(resps.len(), 500);
+        assert!(resps.iter().all(|resp| matches!(resp, Response::Success)));
+
+        let keys: Vec<String> = (0..501).map(|i| format!("key{}", i)).collect();
+        let resps = client.mget(keys.clone())?;
+        for (i, resp) in resps[..500].iter().enumerate() {
+            match resp {
+                Response::Value(value) => assert_eq!(*value, format!("value{}", i)),
+                resp => panic!("unexpected response {:?}", resp),
+            }
+        }
+        assert!(matches!(resps[500], Response::NotFound));
+
+        let resps = client.mdel(keys)?;
+        assert!(resps[..500]
+            .iter()
+            .all(|resp| matches!(resp, Response::Success)));
+        assert!(matches!(resps[500], Response::NotFound));
+        assert!(matches!(
+            client.do_request(&get("key0"))?,
+            Response::NotFound
+        ));
+    }
+    Ok(())
+}
+
+// A batch response nested in another should be rejected rather than decoded recursively
+#[test]
+fn nested_batch() -> Result<()> {
+    let flat = Response::Batch(vec![Response::Success, Response::NotFound]);
+    assert!(Response::from_frame(&flat.to_frame(1)?).is_ok());
+
+    let nested = Response::Batch(vec![Response::Batch(vec![Response::Success])]);
+    assert!(Response::from_frame(&nested.to_frame(1)?).is_err());
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use kvs::engine::KvStore;
+use kvs::err::Result;
+use kvs::network::client::KvsClient;
+use kvs::network::protocol::{self, Frame, Handshake};
+use kvs::network::reactor::ReactorServer;
+use kvs::network::{ErrorCode, Request, Response};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+/// Start an event-driven server on a free port, with only two threads for the engine calls,
+/// serving until the test process exits.
+fn start_server(temp_dir: &TempDir, idle_timeout: Option<Duration>) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?;
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(2)?;
+    thread::spawn(move || {
+        ReactorServer::new(store, listener, pool)
+            .idle_timeout(idle_timeout)
+            .do_loop()
+            .unwrap()
+    });
+    Ok(addr)
+}
+
+fn set(key: &str, value: &str) -> Request {
+    Request::Set {
+        key: key.to_owned(),
+        value: value.to_owned(),
+    }
+}
+
+fn get(key: &str) -> Request {
+    Request::Get {
+        key: key.to_owned(),
+    }
+}
+
+// Many more connections than pool threads should be open and served at once
+#[test]
+fn reactor_many_connections() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    let mut clients = (0..64)
+        .map(|i| {
+            if i % 2 == 0 {
+                KvsClient::connect(addr)
+            } else {
+                KvsClient::connect_json(addr)
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    // a client sending half a request holds up no one
+    let mut slow = TcpStream::connect(addr)?;
+    slow.write_all(b"{\"Get\":")?;
+
+    for (i, client) in clients.iter_mut().enumerate() {
+        let key = format!("key{}", i);
+        assert!(matches!(
+            client.do_request(&set(&key, &format!("value{}", i)))?,
+            Response::Success
+        ));
+    }
+    for (i, client) in clients.iter_mut().rev().enumerate() {
+        let i = 63 - i;
+        match client.do_request(&get(&format!("key{}", i)))? {
+            Response::Value(value) => assert_eq!(value, format!("value{}", i)),
+            resp => panic!("unexpected response {:?}", resp),
+        }
+    }
+
+    slow.write_all(b"{\"key\":\"key5\"}}")?;
+    let mut resp = [0u8; 18];
+    slow.read_exact(&mut resp)?;
+    assert_eq!(&resp, b"{\"Value\":\"value5\"}");
+    Ok(())
+}
+
+// Pipelined requests should be answered in order, over both protocols
+#[test]
+fn reactor_pipelining() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    for mut client in [KvsClient::connect(addr)?, KvsClient::connect_json(addr)?] {
+        let sets: Vec<Request> = (0..2000)
+            .map(|i| set(&format!("key{}", i), &format!("value{}", i)))
+            .collect();
+        let resps = client.pipeline(&sets)?;
+        assert_eq!(resps.len(), 2000);
+        assert!(resps.iter().all(|resp| matches!(resp, Response::Success)));
+
+        let gets: Vec<Request> = (0..2000).map(|i| get(&format!("key{}", i))).collect();
+        for (i, resp) in client.pipeline(&gets)?.into_iter().enumerate() {
+            match resp {
+                Response::Value(value) => assert_eq!(value, format!("value{}", i)),
+                resp => panic!("unexpected response {:?}", resp),
+            }
+        }
+    }
+    Ok(())
+}
+
+// The server should close a connection left idle, and keep serving new ones
+#[test]
+fn reactor_idle_timeout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, Some(Duration::from_millis(100)))?;
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.do_request(&set("key1", "value1"))?,
+        Response::Success
+    ));
+    thread::sleep(Duration::from_millis(500));
+    assert!(client.do_request(&get("key1")).is_err());
+
+    let mut client = KvsClient::connect(addr)?;
+    assert!(matches!(
+        client.do_request(&get("key1"))?,
+        Response::Value(_)
+    ));
+    Ok(())
+}
+
+// Malformed frames and unknown versions should be answered as by the threaded server
+#[test]
+fn reactor_bad_input() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = start_server(&temp_dir, None)?;
+
+    let mut stream = TcpStream::connect(addr)?;
+    let hello = Handshake {
+        version: protocol::VERSION,
+        features: 0,
+    };
+    hello.write_to(&mut stream)?;
+    assert_eq!(Handshake::read_from(&mut stream)?, hello);
+    Frame {
+        id: 7,
+        opcode: 0x7f,
+        payload: vec![1, 2, 3],
+    }
+    .write_to(&mut stream)?;
+    get("key1").to_frame(8).write_to(&mut stream)?;
+    let resp = Frame::read_from(&mut stream)?.expect("connection closed");
+    assert_eq!(resp.id, 7);
+    match Response::from_frame(&resp)? {
+        Response::Error { code, .. } => assert_eq!(code, ErrorCode::Protocol),
+        resp => panic!("unexpected response {:?}", resp),
+    }
+    let resp = Frame::read_from(&mut stream)?.expect("connection closed");
+    assert_eq!(resp.id, 8);
+    assert!(matches!(Response::from_frame(&resp)?, Response::NotFound));
+
+    let mut stream = TcpStream::connect(addr)?;
+    Handshake {
+        version: 0,
+        features: 0,
+    }
+    .write_to(&mut stream)?;
+    assert_eq!(Handshake::read_from(&mut stream)?.version, 0);
+    assert!(Frame::read_from(&mut stream)?.is_none());
+    Ok(())
+}
@@ -0,0 +1,232 @@
+use kvs::engine::{KvStore, KvsEngine, LimitsLayer};
+use kvs::err::Result;
+use kvs::network::resp::RespServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+/// Start a RESP server on a free port, serving until the test process exits.
+fn start_server(temp_dir: &TempDir) -> Result<SocketAddr> {
+    let store = KvStore::open(temp_dir.path())?.layer(LimitsLayer::new().max_value_size(16));
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let pool = SharedQueueThreadPool::new(4)?;
+    thread::spawn(move || RespServer::new(store, listener, pool).do_loop().unwrap());
+    Ok(addr)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>),
+}
+
+fn bulk(s: &str) -> Reply {
+    Reply::Bulk(Some(s.to_owned()))
+}
+
+fn ok() -> Reply {
+    Reply::Simple("OK".to_owned())
+}
+
+/// A minimal RESP2 client.
+struct RespClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl RespClient {
+    fn connect(addr: SocketAddr) -> Result<RespClient> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(RespClient {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        })
+    }
+
+    fn send(&mut self, args: &[&str]) -> Result<()> {
+        let mut buf = format!("*{}\r\n", args.len());
+        for arg in args {
+            buf += &format!("${}\r\n{}\r\n", arg.len(), arg);
+        }
+        self.writer.write_all(buf.as_bytes())?;
+        Ok(())
+    }
+
+    fn recv(&mut self) -> Result<Reply> {
+        let mut line = String::new();
+        self.reader.read_line(&mut line)?;
+        let line = line.trim_end_matches("\r\n");
+        let (kind, rest) = line.split_at(1);
+        Ok(match kind {
+            "+" => Reply::Simple(rest.to_owned()),
+            "-" => Reply::Error(rest.to_owned()),
+            ":" => Reply::Integer(rest.parse().unwrap()),
+            "$" => match rest.parse::<i64>().unwrap() {
+                -1 => Reply::Bulk(None),
+                len => {
+                    let mut buf = vec![0u8; len as usize + 2];
+                    self.reader.read_exact(&mut buf)?;
+                    buf.truncate(len as usize);
+                    Reply::Bulk(Some(String::from_utf8(buf).unwrap()))
+                }
+            },
+            "*" => {
+                let count: usize = rest.parse().unwrap();
+                let mut replies = Vec::new();
+                for _ in 0..count {
+                    replies.push(self.recv()?);
+                }
+                Reply::Array(replies)
+            }
+            _ => panic!("unexpected reply {:?}", line),
+        })
+    }
+
+    fn call(&mut self, args: &[&str]) -> Result<Reply> {
+        self.send(args)?;
+        self.recv()
+    }
+}
+
+// The supported commands should map onto the engine
+#[test]
+fn resp_commands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = RespClient::connect(start_server(&temp_dir)?)?;
+
+    assert_eq!(client.call(&["PING"])?, Reply::Simple("PONG".to_owned()));
+    assert_eq!(client.call(&["ping", "hello"])?, bulk("hello"));
+    assert_eq!(client.call(&["SET", "key1", "value1"])?, ok());
+    assert_eq!(client.call(&["GET", "key1"])?, bulk("value1"));
+    assert_eq!(client.call(&["GET", "key2"])?, Reply::Bulk(None));
+    assert_eq!(
+        client.call(&["MSET", "key2", "value2", "key3", "value3"])?,
+        ok()
+    );
+    assert_eq!(
+        client.call(&["MGET", "key1", "key4", "key3"])?,
+        Reply::Array(vec![bulk("value1"), Reply::Bulk(None), bulk("value3")])
+    );
+    assert_eq!(
+        client.call(&["EXISTS", "key1", "key4", "key2"])?,
+        Reply::Integer(2)
+    );
+    assert_eq!(client.call(&["DEL", "key1", "key4"])?, Reply::Integer(1));
+    assert_eq!(client.call(&["EXISTS", "key1"])?, Reply::Integer(0));
+
+    match client.call(&["INFO"])? {
+        Reply::Bulk(Some(info)) => {
+            assert!(info.contains("engine:kvs\r\n"), "{}", info);
+            assert!(info.contains("keys:2\r\n"), "{}", info);
+        }
+        reply => panic!("unexpected reply {:?}", reply),
+    }
+    Ok(())
+}
+
+// SCAN should page through the keys matching a pattern
+#[test]
+fn resp_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = RespClient::connect(start_server(&temp_dir)?)?;
+    for i in 0..25 {
+        assert_eq!(client.call(&["SET", &format!("user{:02}", i), "v"])?, ok());
+    }
+    assert_eq!(client.call(&["SET", "other", "v"])?, ok());
+
+    let mut cursor = "0".to_owned();
+    let mut keys = Vec::new();
+    let mut pages = 0;
+    loop {
+        match client.call(&["SCAN", &cursor, "MATCH", "user*", "COUNT", "10"])? {
+            Reply::Array(mut reply) => {
+                if let Reply::Array(page) = reply.pop().unwrap() {
+                    keys.extend(page);
+                }
+                match reply.pop().unwrap() {
+                    Reply::Bulk(Some(next)) => cursor = next,
+                    reply => panic!("unexpected cursor {:?}", reply),
+                }
+            }
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+        pages += 1;
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(pages, 3);
+    let expected: Vec<Reply> = (0..25).map(|i| bulk(&format!("user{:02}", i))).collect();
+    assert_eq!(keys, expected);
+
+    assert_eq!(
+        client.call(&["SCAN", "0", "MATCH", "user?[3-4]", "COUNT", "100"])?,
+        Reply::Array(vec![
+            bulk("0"),
+            Reply::Array(vec![
+                bulk("user03"),
+                bulk("user04"),
+                bulk("user13"),
+                bulk("user14"),
+                bulk("user23"),
+                bulk("user24")
+            ])
+        ])
+    );
+    Ok(())
+}
+
+// Unsupported commands and engine failures should be answered with RESP errors
+#[test]
+fn resp_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = RespClient::connect(start_server(&temp_dir)?)?;
+
+    assert_eq!(
+        client.call(&["FLUSHALL"])?,
+        Reply::Error("ERR unknown command 'FLUSHALL'".to_owned())
+    );
+    assert_eq!(
+        client.call(&["GET"])?,
+        Reply::Error("ERR wrong number of arguments for 'get' command".to_owned())
+    );
+    assert_eq!(
+        client.call(&["SET", "key", "a value longer than allowed"])?,
+        Reply::Error("ERR Key or value too large".to_owned())
+    );
+    // the connection is still usable
+    assert_eq!(client.call(&["PING"])?, Reply::Simple("PONG".to_owned()));
+    Ok(())
+}
+
+// Inline commands should be understood and pipelined commands answered in order
+#[test]
+fn resp_inline_and_pipelining() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut client = RespClient::connect(start_server(&temp_dir)?)?;
+
+    client.writer.write_all(b"SET key value\r\nGET key\r\n")?;
+    assert_eq!(client.recv()?, ok());
+    assert_eq!(client.recv()?, bulk("value"));
+
+    for i in 0..100 {
+        client.send(&["SET", &format!("key{}", i), &i.to_string()])?;
+        client.send(&["GET", &format!("key{}", i)])?;
+    }
+    for i in 0..100 {
+        assert_eq!(client.recv()?, ok());
+        assert_eq!(client.recv()?, bulk(&i.to_string()));
+    }
+
+    assert_eq!(client.call(&["QUIT"])?, ok());
+    let mut rest = Vec::new();
+    client.reader.read_to_end(&mut rest)?;
+    assert!(rest.is_empty());
+    Ok(())
+}
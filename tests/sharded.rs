@@ -0,0 +1,96 @@
+use crossbeam_utils::sync::WaitGroup;
+use kvs::engine::{KvStore, KvsEngine, ShardedKvStore};
+use kvs::err::{KvsError, Result};
+use std::thread;
+use tempfile::TempDir;
+
+#[test]
+fn get_set_remove() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key0".to_owned())?;
+    assert!(store.remove("key0".to_owned()).is_err());
+    drop(store);
+
+    let store = ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    for i in 1..100 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(store.stats()?.live_keys, 99);
+
+    // every shard got some of the keys
+    for shard in 0..4 {
+        let dir = temp_dir.path().join(format!("shard-{}", shard));
+        assert!(KvStore::open(dir)?.stats()?.live_keys > 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn scan_across_shards() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 3)?;
+    for i in 0..20 {
+        store.set(format!("key{:02}", i), format!("value{}", i))?;
+    }
+    store.set("other".to_owned(), "value".to_owned())?;
+
+    let pairs = store.scan("key1".to_owned())?;
+    let keys: Vec<String> = pairs.into_iter().map(|(k, _)| k).collect();
+    let expected: Vec<String> = (10..20).map(|i| format!("key{}", i)).collect();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+// The shard count is fixed at creation, and an unsharded store is not taken for a sharded one
+#[test]
+fn shard_count_checked() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ShardedKvStore::open(temp_dir.path(), 4)?;
+    assert_eq!(ShardedKvStore::shard_count(temp_dir.path())?, Some(4));
+    let err = ShardedKvStore::open(temp_dir.path(), 8).err().unwrap();
+    assert!(matches!(err, KvsError::ShardMismatch));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(ShardedKvStore::shard_count(temp_dir.path())?, None);
+    let err = ShardedKvStore::open(temp_dir.path(), 4).err().unwrap();
+    assert!(matches!(err, KvsError::ShardMismatch));
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open(temp_dir.path(), 8)?;
+    let wg = WaitGroup::new();
+    for t in 0..8 {
+        let store = store.clone();
+        let wg = wg.clone();
+        thread::spawn(move || {
+            for i in 0..100 {
+                store
+                    .set(format!("key{}-{}", t, i), format!("value{}", i))
+                    .unwrap();
+            }
+            drop(wg);
+        });
+    }
+    wg.wait();
+    drop(store);
+
+    let store = ShardedKvStore::open(temp_dir.path(), 8)?;
+    assert_eq!(store.stats()?.live_keys, 800);
+    for t in 0..8 {
+        assert_eq!(
+            store.get(format!("key{}-99", t))?,
+            Some("value99".to_owned())
+        );
+    }
+    Ok(())
+}